@@ -1,11 +1,25 @@
+mod check;
+mod config;
 mod diff;
+mod env;
 mod import;
 mod init;
+mod lock;
+mod outdated;
+mod status;
 mod sync;
+mod unlock;
 mod view;
 
+pub use check::*;
+pub use config::*;
 pub use diff::*;
+pub use env::*;
 pub use import::*;
 pub use init::*;
+pub use lock::*;
+pub use outdated::*;
+pub use status::*;
 pub use sync::*;
+pub use unlock::*;
 pub use view::*;
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+
+use crate::compose::VariableCheck;
+use crate::config::{Config, EnvVar, resolve_stacks};
+use crate::reporter::Reporter;
+use crate::schema::SchemaViolation;
+use crate::secrets;
+use crate::validate;
+
+/// Check every stack's compose files and variables and fail if any stack
+/// has an invalid compose file, or variables that would be deployed blank
+/// or that break its env schema.
+pub fn check_command(config_path: &str, stacks: &[String]) -> Result<()> {
+    let (_, configs) = resolve_stacks(config_path, stacks)?;
+    let mut failed = 0;
+    for config in &configs {
+        if !config.enabled {
+            Reporter::disabled(&config.name);
+            continue;
+        }
+        let errors = compose_errors(config)?;
+        if !errors.is_empty() {
+            Reporter::invalid_compose(&config.name, &errors);
+            failed += 1;
+            continue;
+        }
+        let check = check_stack(config, &config.compose_content()?, &config.env_vars()?)?;
+        Reporter::variable_check(&config.name, &check);
+        if !check.passed() {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} {} failed the check",
+            failed,
+            if failed == 1 { "stack" } else { "stacks" }
+        );
+    }
+    Ok(())
+}
+
+/// Validate a stack's compose files before anything is sent to the host,
+/// so a typo fails with its file and line instead of a server error.
+pub fn ensure_compose_valid(config: &Config) -> Result<()> {
+    let errors = compose_errors(config)?;
+    if !errors.is_empty() {
+        bail!(
+            "Stack '{}' has an invalid compose file:\n  {}\nFix the compose file, or pass \
+             --skip-check to deploy anyway.",
+            config.name,
+            errors.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Problems in a stack's compose files, as `file:line: message`. With
+/// `docker_validate`, the merged file is also checked by
+/// `docker compose config` once it passes the built-in checks, with the
+/// stack's env set so `${VAR:?}` references resolve.
+pub fn compose_errors(config: &Config) -> Result<Vec<String>> {
+    let files: Vec<(String, String)> = config
+        .compose_paths()
+        .iter()
+        .map(|path| path.display().to_string())
+        .zip(config.read_compose_files()?)
+        .collect();
+    let mut errors: Vec<String> = validate::validate(&files)
+        .iter()
+        .map(|issue| issue.to_string())
+        .collect();
+    if errors.is_empty() && config.docker_validate {
+        let contents: Vec<String> = files.into_iter().map(|(_, content)| content).collect();
+        let merged = config.merge_compose(&contents)?;
+        let project_dir = config.compose_paths()[0]
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| config.base_dir.clone());
+        let env: Vec<EnvVar> = config
+            .merged_env()?
+            .into_iter()
+            .filter(|v| !secrets::is_unresolved(&v.value))
+            .collect();
+        if let Some(docker_errors) = validate::docker_compose_config(&merged, &project_dir, &env) {
+            errors.extend(
+                docker_errors
+                    .into_iter()
+                    .map(|e| format!("docker compose config: {}", e)),
+            );
+        }
+    }
+    Ok(errors)
+}
+
+/// Run the check before a deploy, failing when a variable would be
+/// deployed blank or breaks the env schema.
+pub fn ensure_variables(config: &Config, compose_content: &str, env: &[EnvVar]) -> Result<()> {
+    let check = check_stack(config, compose_content, env)?;
+    if !check.missing.is_empty() {
+        bail!(
+            "Stack '{}' references variables that are not set and have no default: {}. \
+             Define them in the env file, or pass --skip-check to deploy anyway.",
+            config.name,
+            check.missing.join(", ")
+        );
+    }
+    if !check.invalid.is_empty() {
+        bail!(
+            "Stack '{}' has an env file that breaks its env schema: {}. \
+             Fix the env file, or pass --skip-check to deploy anyway.",
+            config.name,
+            check.invalid.join("; ")
+        );
+    }
+    Ok(())
+}
+
+/// Validate the env against the stack's env schema, if it has one.
+pub fn schema_violations(config: &Config, env: &[EnvVar]) -> Result<Vec<SchemaViolation>> {
+    match config.env_schema()? {
+        Some(schema) => schema.validate(env),
+        None => Ok(vec![]),
+    }
+}
+
+fn check_stack(config: &Config, compose_content: &str, env: &[EnvVar]) -> Result<VariableCheck> {
+    let mut check = VariableCheck::new(compose_content, env).context(format!(
+        "Failed to check compose file: {}",
+        config.compose_source()
+    ))?;
+    // `common_env` is shared by every stack, so not all of them read it.
+    check
+        .unused
+        .retain(|name| !config.common_env.contains_key(name));
+    // Missing keys the compose file references are already reported.
+    check.invalid = schema_violations(config, env)?
+        .into_iter()
+        .filter(|v| !(v.missing && check.missing.contains(&v.name)))
+        .map(|v| v.message)
+        .collect();
+    Ok(check)
+}
@@ -0,0 +1,690 @@
+use anyhow::{Result, bail};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    self, Config, DeployMode, EnvStrategy, PartialConfigFile, ResolvedGlobalConfig,
+};
+use crate::reporter::{ConfigSetting, Reporter};
+
+/// Config keys that only apply in one mode.
+const PORTAINER_KEYS: &[&str] = &["portainer_api_key", "endpoint_id"];
+const SSH_KEYS: &[&str] = &["ssh_user", "ssh_key", "host_dir"];
+const PORTAINER_STACK_KEYS: &[&str] = &["endpoint_id"];
+const SSH_STACK_KEYS: &[&str] = &["files"];
+
+/// Problems found by `config check`: errors fail the check, warnings don't.
+#[derive(Debug, Default)]
+struct Findings {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+/// Check the config chain for unknown keys, missing files, invalid stack
+/// names, stacks defined in more than one file, and keys that don't apply
+/// to the deploy mode.
+pub fn config_check_command(config_path: &str) -> Result<()> {
+    let path = Path::new(config_path);
+    let files = config::config_chain_files(path)?;
+    let tables = files
+        .iter()
+        .map(|file| Ok((file.clone(), config::read_config_value(file)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let Some((local_path, _)) = tables.first() else {
+        bail!("No config file found. Create a .stack-sync.toml file with stack definitions.");
+    };
+
+    let mut findings = Findings::default();
+    for (file, table) in &tables {
+        check_unknown_keys(file, table, &mut findings);
+    }
+    check_duplicate_stacks(&tables, &mut findings);
+
+    match config::resolve_config_chain_lenient(path) {
+        Ok((global, local_config, _)) => {
+            let mode = match global {
+                ResolvedGlobalConfig::Portainer(_) => DeployMode::Portainer,
+                ResolvedGlobalConfig::Ssh(_) => DeployMode::Ssh,
+            };
+            for (file, table) in &tables {
+                check_mode_keys(file, table, &mode, &mut findings);
+            }
+            let base_dir = local_path.parent().unwrap_or(Path::new("."));
+            check_stacks(&local_config, &global, base_dir, &mut findings);
+        }
+        Err(e) => findings.errors.push(format!("{:#}", e)),
+    }
+
+    Reporter::config_check(
+        &local_path.display().to_string(),
+        &findings.errors,
+        &findings.warnings,
+    );
+    if !findings.errors.is_empty() {
+        bail!(
+            "Config check failed with {} {}",
+            findings.errors.len(),
+            if findings.errors.len() == 1 {
+                "error"
+            } else {
+                "errors"
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Print the config as resolved from the whole chain, with the file (or
+/// env var) each value came from when `origin` is set. The API key and
+/// values whose names look secret are masked.
+pub fn config_show_command(config_path: &str, origin: bool, show_values: bool) -> Result<()> {
+    let path = Path::new(config_path);
+    let (global, local_config, local_path) = config::resolve_config_chain(path)?;
+    let tables = config::config_chain_files(path)?
+        .into_iter()
+        .map(|file| {
+            Ok((
+                file.display().to_string(),
+                config::read_config_value(&file)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let origins = Origins { tables: &tables };
+    let local = local_path.display().to_string();
+
+    let mut globals = Vec::new();
+    let mut push = |key: &str, value: String, from: Option<String>| {
+        globals.push(ConfigSetting {
+            key: key.to_string(),
+            value,
+            origin: from,
+        });
+    };
+    let mode = match global {
+        ResolvedGlobalConfig::Portainer(_) => "portainer",
+        ResolvedGlobalConfig::Ssh(_) => "ssh",
+    };
+    push("mode", quote(mode), origins.key("mode"));
+    match &global {
+        ResolvedGlobalConfig::Portainer(p) => {
+            push("host", quote(&p.host), origins.key("host"));
+            push(
+                "endpoint_id",
+                p.endpoint_id.to_string(),
+                origins.key("endpoint_id"),
+            );
+            let api_key_origin = if std::env::var("PORTAINER_API_KEY").is_ok() {
+                Some("PORTAINER_API_KEY".to_string())
+            } else {
+                origins.key("portainer_api_key")
+            };
+            push(
+                "portainer_api_key",
+                quote(&mask(&p.api_key)),
+                api_key_origin,
+            );
+        }
+        ResolvedGlobalConfig::Ssh(s) => {
+            push("host", quote(&s.host), origins.key("host"));
+            if let Some(user) = &s.ssh_user {
+                push("ssh_user", quote(user), origins.key("ssh_user"));
+            }
+            if let Some(key) = &s.ssh_key {
+                push("ssh_key", quote(key), origins.key("ssh_key"));
+            }
+            push("host_dir", quote(&s.host_dir), origins.key("host_dir"));
+        }
+    }
+    if let Some(identity) = &local_config.age_identity {
+        push("age_identity", quote(identity), origins.key("age_identity"));
+    }
+    if let Some(recipients) = &local_config.age_recipients {
+        push(
+            "age_recipients",
+            format!("{:?}", recipients),
+            origins.key("age_recipients"),
+        );
+    }
+    if let Some(docker_validate) = local_config.docker_validate {
+        push(
+            "docker_validate",
+            docker_validate.to_string(),
+            origins.key("docker_validate"),
+        );
+    }
+    if let Some(portainer_stamp) = local_config.portainer_stamp {
+        push(
+            "portainer_stamp",
+            portainer_stamp.to_string(),
+            origins.key("portainer_stamp"),
+        );
+    }
+    if let Some(strict) = local_config.strict {
+        push("strict", strict.to_string(), origins.key("strict"));
+    }
+
+    let base_dir = local_path.parent().unwrap_or(Path::new("."));
+    let mut names = local_config.stack_names();
+    names.sort();
+    let stacks = names
+        .into_iter()
+        .map(|name| Ok((name, local_config.resolve(name, &global, base_dir)?)))
+        .collect::<Result<Vec<_>>>()?;
+    // Keys any stack's env schema marks secret, for `common_env`
+    let common_secrets: Vec<String> = stacks
+        .iter()
+        .flat_map(|(_, config)| schema_secrets(config))
+        .collect();
+    let hide_env = |secrets: &[String], key: &str| {
+        !show_values || looks_secret(key) || secrets.iter().any(|s| s == key)
+    };
+
+    let mut sections = vec![(String::new(), globals)];
+    for (name, table) in [
+        ("vars", &local_config.vars),
+        ("secret_providers", &local_config.secret_providers),
+    ] {
+        let settings = table_settings(table, |key| origins.entry(name, key), looks_secret);
+        if !settings.is_empty() {
+            sections.push((name.to_string(), settings));
+        }
+    }
+    let settings = table_settings(
+        &local_config.common_env,
+        |key| origins.entry("common_env", key),
+        |key| hide_env(&common_secrets, key),
+    );
+    if !settings.is_empty() {
+        sections.push(("common_env".to_string(), settings));
+    }
+
+    for (name, config) in stacks {
+        let entry = &local_config.stacks[name];
+        let from = Some(local.clone());
+        sections.push((
+            format!("stacks.{}", name),
+            stack_settings(&config, entry.endpoint_id.is_some(), &from, &origins),
+        ));
+        let settings = table_settings(&entry.vars, |_| from.clone(), looks_secret);
+        if !settings.is_empty() {
+            sections.push((format!("stacks.{}.vars", name), settings));
+        }
+        let secrets = schema_secrets(&config);
+        let settings = table_settings(&entry.env, |_| from.clone(), |key| hide_env(&secrets, key));
+        if !settings.is_empty() {
+            sections.push((format!("stacks.{}.env", name), settings));
+        }
+    }
+
+    if !origin {
+        for (_, settings) in &mut sections {
+            for setting in settings {
+                setting.origin = None;
+            }
+        }
+    }
+    Reporter::effective_config(&local, &sections);
+    Ok(())
+}
+
+/// Where values came from: the nearest config file of the chain that sets
+/// them, like the chain itself resolves them.
+struct Origins<'a> {
+    tables: &'a [(String, toml::Table)],
+}
+
+impl Origins<'_> {
+    /// The file setting a top-level key, or `default`.
+    fn key(&self, key: &str) -> Option<String> {
+        let file = self
+            .tables
+            .iter()
+            .find(|(_, table)| table.contains_key(key));
+        Some(file.map_or_else(|| "default".to_string(), |(file, _)| file.clone()))
+    }
+
+    /// The file setting an entry of a top-level table.
+    fn entry(&self, table: &str, key: &str) -> Option<String> {
+        self.tables
+            .iter()
+            .find(|(_, t)| {
+                t.get(table)
+                    .and_then(|v| v.as_table())
+                    .is_some_and(|v| v.contains_key(key))
+            })
+            .map(|(file, _)| file.clone())
+    }
+}
+
+fn stack_settings(
+    config: &Config,
+    own_endpoint: bool,
+    from: &Option<String>,
+    origins: &Origins,
+) -> Vec<ConfigSetting> {
+    let setting = |key: &str, value: String, origin: Option<String>| ConfigSetting {
+        key: key.to_string(),
+        value,
+        origin,
+    };
+    let paths = |paths: Vec<PathBuf>| -> Vec<String> {
+        paths.iter().map(|p| p.display().to_string()).collect()
+    };
+    let mut settings = vec![setting(
+        "compose_files",
+        format!("{:?}", paths(config.compose_paths())),
+        from.clone(),
+    )];
+    if config.compose_template {
+        settings.push(setting(
+            "compose_template",
+            quote(&config.compose_paths()[0].display().to_string()),
+            from.clone(),
+        ));
+    }
+    if !config.env_files.is_empty() {
+        settings.push(setting(
+            "env_files",
+            format!("{:?}", paths(config.env_paths())),
+            from.clone(),
+        ));
+    }
+    if let Some(schema) = &config.env_schema {
+        settings.push(setting(
+            "env_schema",
+            quote(&config.base_dir.join(schema).display().to_string()),
+            from.clone(),
+        ));
+    }
+    let strategy = match config.env_strategy {
+        EnvStrategy::Replace => "replace",
+        EnvStrategy::Merge => "merge",
+        EnvStrategy::PreserveRemote => "preserve-remote",
+    };
+    settings.push(setting("env_strategy", quote(strategy), from.clone()));
+    if !config.ignore_env.is_empty() {
+        settings.push(setting(
+            "ignore_env",
+            format!("{:?}", config.ignore_env),
+            from.clone(),
+        ));
+    }
+    if !config.files.is_empty() {
+        settings.push(setting(
+            "files",
+            format!("{:?}", config.files),
+            from.clone(),
+        ));
+    }
+    if config.endpoint_id != 0 {
+        let origin = if own_endpoint {
+            from.clone()
+        } else {
+            origins.key("endpoint_id")
+        };
+        settings.push(setting(
+            "endpoint_id",
+            config.endpoint_id.to_string(),
+            origin,
+        ));
+    }
+    settings.push(setting("enabled", config.enabled.to_string(), from.clone()));
+    settings
+}
+
+/// Entries of a config table, sorted by key, with the values of `hidden`
+/// keys masked entirely.
+fn table_settings(
+    table: &std::collections::HashMap<String, String>,
+    origin: impl Fn(&str) -> Option<String>,
+    hidden: impl Fn(&str) -> bool,
+) -> Vec<ConfigSetting> {
+    let mut keys: Vec<&String> = table.keys().collect();
+    keys.sort();
+    keys.into_iter()
+        .map(|key| {
+            let value = &table[key];
+            let value = if hidden(key) {
+                "****".to_string()
+            } else {
+                value.clone()
+            };
+            ConfigSetting {
+                key: key.clone(),
+                value: quote(&value),
+                origin: origin(key),
+            }
+        })
+        .collect()
+}
+
+/// Keys the stack's env schema marks `secret = true`. A schema that can't
+/// be read marks nothing; `config check` reports it.
+fn schema_secrets(config: &Config) -> Vec<String> {
+    let schema = config.env_schema().ok().flatten().unwrap_or_default();
+    schema
+        .keys
+        .into_iter()
+        .filter(|(_, key)| key.secret)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Whether a key's name suggests its value is a secret.
+fn looks_secret(key: &str) -> bool {
+    let key = key.to_uppercase();
+    ["KEY", "SECRET", "TOKEN", "PASSWORD", "PASSWD"]
+        .iter()
+        .any(|word| key.contains(word))
+}
+
+/// Keep only enough of the API key to tell which one it is.
+fn mask(value: &str) -> String {
+    if value.chars().count() <= 8 {
+        return "****".to_string();
+    }
+    let prefix: String = value.chars().take(4).collect();
+    format!("{}****", prefix)
+}
+
+fn quote(value: &str) -> String {
+    format!("{:?}", value)
+}
+
+fn check_unknown_keys(file: &Path, table: &toml::Table, findings: &mut Findings) {
+    for key in config::unknown_keys(table) {
+        let (known, name) = match key.rsplit_once('.') {
+            Some((_, name)) => (config::stack_keys(), name),
+            None => (config::config_keys(), key.as_str()),
+        };
+        let hint = closest_key(name, known)
+            .map(|k| format!(" (did you mean '{}'?)", k))
+            .unwrap_or_default();
+        findings
+            .errors
+            .push(format!("{}: unknown key '{}'{}", file.display(), key, hint));
+    }
+}
+
+/// Stacks are only read from the nearest config, so stacks in a parent
+/// config are ignored, whether or not the local config has one by the same
+/// name. Both are warnings: a parent config can be the local config of its
+/// own directory.
+fn check_duplicate_stacks(tables: &[(PathBuf, toml::Table)], findings: &mut Findings) {
+    let Some(((local_path, local), parents)) = tables.split_first() else {
+        return;
+    };
+    let stack_names = |table: &toml::Table| -> Vec<String> {
+        let stacks = table.get("stacks").and_then(|s| s.as_table());
+        stacks.into_iter().flat_map(|s| s.keys().cloned()).collect()
+    };
+    let local_names = stack_names(local);
+    for (parent_path, parent) in parents {
+        for name in stack_names(parent) {
+            if local_names.contains(&name) {
+                findings.warnings.push(format!(
+                    "stack '{}' is defined in both {} and {}; only the first is used",
+                    name,
+                    local_path.display(),
+                    parent_path.display()
+                ));
+            } else {
+                findings.warnings.push(format!(
+                    "{}: stack '{}' is ignored, stacks are only read from {}",
+                    parent_path.display(),
+                    name,
+                    local_path.display()
+                ));
+            }
+        }
+    }
+}
+
+fn check_mode_keys(file: &Path, table: &toml::Table, mode: &DeployMode, findings: &mut Findings) {
+    let (other, keys, stack_keys) = match mode {
+        DeployMode::Portainer => ("SSH", SSH_KEYS, SSH_STACK_KEYS),
+        DeployMode::Ssh => ("Portainer", PORTAINER_KEYS, PORTAINER_STACK_KEYS),
+    };
+    for key in table.keys().filter(|k| keys.contains(&k.as_str())) {
+        findings.warnings.push(format!(
+            "{}: '{}' is only used in {} mode",
+            file.display(),
+            key,
+            other
+        ));
+    }
+    let stacks = table.get("stacks").and_then(|s| s.as_table());
+    for (name, stack) in stacks.into_iter().flatten() {
+        let keys = stack.as_table().into_iter().flat_map(|t| t.keys());
+        for key in keys.filter(|k| stack_keys.contains(&k.as_str())) {
+            findings.warnings.push(format!(
+                "{}: '{}' of stack '{}' is only used in {} mode",
+                file.display(),
+                key,
+                name,
+                other
+            ));
+        }
+    }
+}
+
+fn check_stacks(
+    local_config: &PartialConfigFile,
+    global: &ResolvedGlobalConfig,
+    base_dir: &Path,
+    findings: &mut Findings,
+) {
+    let mut names = local_config.stack_names();
+    names.sort();
+    for name in names {
+        if !is_valid_stack_name(name) {
+            findings.errors.push(format!(
+                "stack name '{}' is not a valid compose project name: use lowercase letters, \
+                 digits, '-' and '_', starting with a letter or digit",
+                name
+            ));
+        }
+        let config = match local_config.resolve(name, global, base_dir) {
+            Ok(config) => config,
+            Err(e) => {
+                findings.errors.push(format!("{:#}", e));
+                continue;
+            }
+        };
+        let mut paths: Vec<(&str, PathBuf)> = config
+            .compose_paths()
+            .into_iter()
+            .map(|p| ("compose file", p))
+            .collect();
+        paths.extend(config.env_paths().into_iter().map(|p| ("env file", p)));
+        if let Some(schema) = &config.env_schema {
+            paths.push(("env schema", base_dir.join(schema)));
+        }
+        let compose_dir = config.compose_paths()[0]
+            .parent()
+            .unwrap_or(base_dir)
+            .to_path_buf();
+        paths.extend(config.files.iter().map(|f| ("file", compose_dir.join(f))));
+        for (kind, path) in paths {
+            if !path.is_file() {
+                findings.errors.push(format!(
+                    "stack '{}': {} not found: {}",
+                    name,
+                    kind,
+                    path.display()
+                ));
+            }
+        }
+    }
+}
+
+/// Stack names become Portainer stack names and compose project names
+/// (and directory names in SSH mode), which docker compose restricts to
+/// lowercase letters, digits, dashes and underscores.
+fn is_valid_stack_name(name: &str) -> bool {
+    Regex::new(r"^[a-z0-9][a-z0-9_-]*$").unwrap().is_match(name)
+}
+
+/// The known key closest to a misspelled one, if any is close enough to be
+/// a likely typo.
+fn closest_key<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|k| (edit_distance(key, k), *k))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, k)| k)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask("ptr_abcdefghijkl"), "ptr_****");
+        assert_eq!(mask("short"), "****");
+        assert!(looks_secret("portainer_api_key"));
+        assert!(looks_secret("DB_PASSWORD"));
+        assert!(!looks_secret("DOMAIN"));
+    }
+
+    #[test]
+    fn test_table_settings_masks_hidden_keys() {
+        let table = std::collections::HashMap::from([
+            (
+                "DATABASE_URL".to_string(),
+                "postgres://app:pw@db/app".to_string(),
+            ),
+            ("DOMAIN".to_string(), "example.com".to_string()),
+        ]);
+        let settings = table_settings(&table, |_| None, |key| key == "DATABASE_URL");
+        let values: Vec<(&str, &str)> = settings
+            .iter()
+            .map(|s| (s.key.as_str(), s.value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            [("DATABASE_URL", "\"****\""), ("DOMAIN", "\"example.com\"")]
+        );
+    }
+
+    #[test]
+    fn test_origins() {
+        let table = |s: &str| -> toml::Table { toml::from_str(s).unwrap() };
+        let tables = [
+            (
+                "local.toml".to_string(),
+                table("[vars]\nA = \"1\"\n[stacks.web]\ncompose_file = \"a.yaml\"\n"),
+            ),
+            (
+                "parent.toml".to_string(),
+                table("host = \"x\"\n[vars]\nA = \"2\"\nB = \"3\"\n"),
+            ),
+        ];
+        let origins = Origins { tables: &tables };
+        assert_eq!(origins.key("host").as_deref(), Some("parent.toml"));
+        assert_eq!(origins.key("mode").as_deref(), Some("default"));
+        assert_eq!(origins.entry("vars", "A").as_deref(), Some("local.toml"));
+        assert_eq!(origins.entry("vars", "B").as_deref(), Some("parent.toml"));
+    }
+
+    #[test]
+    fn test_closest_key() {
+        assert_eq!(
+            closest_key("env_flie", config::stack_keys()),
+            Some("env_file")
+        );
+        assert_eq!(closest_key("compose", config::stack_keys()), None);
+        assert_eq!(closest_key("hots", config::config_keys()), Some("host"));
+    }
+
+    #[test]
+    fn test_is_valid_stack_name() {
+        assert!(is_valid_stack_name("my-app_2"));
+        assert!(!is_valid_stack_name("My-App"));
+        assert!(!is_valid_stack_name("-app"));
+        assert!(!is_valid_stack_name("my app"));
+        assert!(!is_valid_stack_name("../app"));
+    }
+
+    #[test]
+    fn test_check_unknown_keys_and_mode_keys() {
+        let table: toml::Table = toml::from_str(
+            r#"
+host_dir = "/srv"
+hots = "x"
+
+[stacks.web]
+compose_file = "compose.yaml"
+env_flie = ".env"
+endpoint_id = 3
+files = ["a.conf"]
+
+[stacks.web.env]
+anything = "goes"
+"#,
+        )
+        .unwrap();
+        let file = Path::new("stack-sync.toml");
+        let mut findings = Findings::default();
+        check_unknown_keys(file, &table, &mut findings);
+        check_mode_keys(file, &table, &DeployMode::Portainer, &mut findings);
+        assert_eq!(
+            findings.errors,
+            [
+                "stack-sync.toml: unknown key 'hots' (did you mean 'host'?)",
+                "stack-sync.toml: unknown key 'stacks.web.env_flie' (did you mean 'env_file'?)",
+            ]
+        );
+        assert_eq!(
+            findings.warnings,
+            [
+                "stack-sync.toml: 'host_dir' is only used in SSH mode",
+                "stack-sync.toml: 'files' of stack 'web' is only used in SSH mode",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_duplicate_stacks() {
+        let table = |s: &str| -> toml::Table { toml::from_str(s).unwrap() };
+        let tables = [
+            (
+                PathBuf::from("local.toml"),
+                table("[stacks.web]\ncompose_file = \"a.yaml\"\n"),
+            ),
+            (
+                PathBuf::from("parent.toml"),
+                table(
+                    "[stacks.web]\ncompose_file = \"b.yaml\"\n\
+                     [stacks.db]\ncompose_file = \"c.yaml\"\n",
+                ),
+            ),
+        ];
+        let mut findings = Findings::default();
+        check_duplicate_stacks(&tables, &mut findings);
+        assert!(findings.errors.is_empty());
+        assert_eq!(
+            findings.warnings,
+            [
+                "parent.toml: stack 'db' is ignored, stacks are only read from local.toml",
+                "stack 'web' is defined in both local.toml and parent.toml; only the first is used",
+            ]
+        );
+    }
+}
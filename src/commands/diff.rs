@@ -1,108 +1,231 @@
 use anyhow::{Context, Result};
+use std::collections::BTreeMap;
 
-use crate::config::{self, Config, EnvVar, ResolvedGlobalConfig, resolve_stacks};
+use crate::commands::check;
+use crate::compose::{self, ComposeChange};
+use crate::config::{self, Config, EnvVar, ResolvedGlobalConfig, StackFiles, resolve_stacks};
+use crate::lockfile::{self, LockFile};
 use crate::portainer::{self, PortainerClient};
 use crate::reporter::{EnvChange, Reporter};
 use crate::ssh::SshClient;
-
-pub fn diff_command(config_path: &str, stacks: &[String]) -> Result<()> {
+use crate::stamp::sha256_hex;
+
+pub fn diff_command(
+    config_path: &str,
+    stacks: &[String],
+    semantic: bool,
+    pinned: bool,
+) -> Result<()> {
     let (global_config, configs) = resolve_stacks(config_path, stacks)?;
+    let lock_file = lockfile::load_for(pinned, &configs)?;
+    let lock_file = lock_file.as_ref();
     match &global_config {
         ResolvedGlobalConfig::Portainer(p) => {
             let client = portainer::PortainerClient::new(&p.host, &p.api_key);
             for config in &configs {
-                diff_portainer(config, &client)?;
+                diff_portainer(config, &client, semantic, lock_file)?;
             }
         }
         ResolvedGlobalConfig::Ssh(s) => {
             let client = SshClient::new(s);
             for config in &configs {
-                diff_ssh(config, &client)?;
+                diff_ssh(config, &client, semantic, lock_file)?;
             }
         }
     }
     Ok(())
 }
 
-fn diff_portainer(config: &Config, client: &PortainerClient) -> Result<()> {
-    let compose_path = config.compose_path();
-    let local_compose = std::fs::read_to_string(&compose_path).context(format!(
-        "Failed to read compose file: {}",
-        compose_path.display()
-    ))?;
-    let local_env = match config.env_path() {
-        Some(path) => config::parse_env_file(&path)?,
-        None => vec![],
-    };
-
-    match client.find_stack_by_name(&config.name)? {
+fn diff_portainer(
+    config: &Config,
+    client: &PortainerClient,
+    semantic: bool,
+    lock: Option<&LockFile>,
+) -> Result<()> {
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    let local_compose = config.merge_compose(&compose_files)?;
+    let local_env = config.env_vars()?;
+
+    let existing = client.find_stack_by_name(&config.name)?;
+    let remote_env = existing.as_ref().map(|e| e.env.clone()).unwrap_or_default();
+    let deployed_env = config.deployed_env(&remote_env, &local_env);
+    let violations = schema_messages(config, &deployed_env)?;
+
+    match existing {
         Some(existing) => {
             let remote_compose = client.get_stack_file(existing.id)?;
             report_diff(
                 &config.name,
                 &remote_compose,
                 &local_compose,
-                &existing.env,
-                &local_env,
+                &remote_env,
+                &deployed_env,
+                &[],
+                semantic,
             );
         }
         None => {
             Reporter::would_create(&config.name);
         }
     }
+    Reporter::schema_violations(&violations);
 
     Ok(())
 }
 
-fn diff_ssh(config: &Config, client: &SshClient) -> Result<()> {
-    let compose_path = config.compose_path();
-    let local_compose = std::fs::read_to_string(&compose_path).context(format!(
-        "Failed to read compose file: {}",
-        compose_path.display()
-    ))?;
-    let local_env = match config.env_path() {
-        Some(path) => config::parse_env_file(&path)?,
+fn diff_ssh(
+    config: &Config,
+    client: &SshClient,
+    semantic: bool,
+    lock: Option<&LockFile>,
+) -> Result<()> {
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    let local_compose = config.merge_compose(&compose_files)?;
+    let local_env = config.env_vars()?;
+
+    let remote = client.get_stack(&config.name)?;
+    let remote_env = match remote.as_ref().and_then(|r| r.env_for(config.has_env())) {
+        Some(content) => {
+            config::parse_env_str(content).context("Failed to parse remote env file")?
+        }
         None => vec![],
     };
-
-    if !client.stack_exists(&config.name)? {
-        Reporter::would_create(&config.name);
-        return Ok(());
+    let deployed_env = config.deployed_env(&remote_env, &local_env);
+    let violations = schema_messages(config, &deployed_env)?;
+
+    match remote {
+        Some(remote) => {
+            let files = config.stack_files(&local_compose)?;
+            if !files.skipped.is_empty() {
+                Reporter::files_not_uploaded(&config.name, &files.skipped);
+            }
+            report_diff(
+                &config.name,
+                &remote.compose,
+                &local_compose,
+                &remote_env,
+                &deployed_env,
+                &diff_files(&remote.files, &files),
+                semantic,
+            );
+        }
+        None => {
+            Reporter::would_create(&config.name);
+        }
     }
-
-    let remote_compose = client.get_compose_content(&config.name)?;
-    let remote_env = client
-        .get_env_content(&config.name)?
-        .map(|content| config::parse_env_str(&content))
-        .unwrap_or_default();
-    report_diff(
-        &config.name,
-        &remote_compose,
-        &local_compose,
-        &remote_env,
-        &local_env,
-    );
+    Reporter::schema_violations(&violations);
 
     Ok(())
 }
 
+/// Env schema violations, shown as warnings: `diff` only reports them, it's
+/// `sync` and `check` that fail on them.
+fn schema_messages(config: &Config, env: &[EnvVar]) -> Result<Vec<String>> {
+    Ok(check::schema_violations(config, env)?
+        .into_iter()
+        .map(|v| v.message)
+        .collect())
+}
+
 fn report_diff(
     name: &str,
     remote_compose: &str,
     local_compose: &str,
     remote_env: &[EnvVar],
     local_env: &[EnvVar],
+    file_changes: &[EnvChange],
+    semantic: bool,
 ) {
-    let compose_diff = unified_diff(remote_compose.trim_end(), local_compose.trim_end(), 3);
+    let compose_diff = ComposeDiff::new(remote_compose, local_compose, semantic);
     let env_changes = diff_env(remote_env, local_env);
+    if let ComposeDiff::Lines(_, Some(reason)) = &compose_diff {
+        Reporter::semantic_fallback(name, reason);
+    }
 
-    if compose_diff.is_empty() && env_changes.is_empty() {
+    if compose_diff.is_empty() && env_changes.is_empty() && file_changes.is_empty() {
         Reporter::up_to_date(name);
         return;
     }
 
     Reporter::changed(name);
-    Reporter::diff_details(&compose_diff, &env_changes);
+    report_image_changes(remote_compose, local_compose);
+    compose_diff.report(&env_changes);
+    Reporter::file_changes(file_changes);
+}
+
+/// Summarize which service images a sync would change, ahead of the full
+/// diff. Nothing is printed when no image changes or either file isn't
+/// valid YAML.
+pub fn report_image_changes(remote_compose: &str, local_compose: &str) {
+    if let Ok(changes) = compose::image_changes(remote_compose, local_compose)
+        && !changes.is_empty()
+    {
+        Reporter::image_changes(&changes);
+    }
+}
+
+/// Attach the compose and env diff to a stack's structured output, so
+/// `sync --dry-run` in JSON or Markdown carries the same detail as `diff`.
+/// Text output is left alone.
+pub fn report_structured_diff(
+    remote_compose: &str,
+    local_compose: &str,
+    remote_env: &[EnvVar],
+    local_env: &[EnvVar],
+    file_changes: &[EnvChange],
+    semantic: bool,
+) {
+    if !Reporter::is_structured() {
+        return;
+    }
+    let compose_diff = ComposeDiff::new(remote_compose, local_compose, semantic);
+    let env_changes = diff_env(remote_env, local_env);
+    if !compose_diff.is_empty() || !env_changes.is_empty() {
+        compose_diff.report(&env_changes);
+    }
+    Reporter::file_changes(file_changes);
+}
+
+/// The compose part of a diff: changes by YAML path in semantic mode, or a
+/// line diff otherwise. Semantic mode falls back to the line diff when
+/// either file isn't valid YAML, keeping the parse error to report.
+enum ComposeDiff {
+    Lines(Vec<String>, Option<anyhow::Error>),
+    Semantic(Vec<ComposeChange>),
+}
+
+impl ComposeDiff {
+    fn new(remote_compose: &str, local_compose: &str, semantic: bool) -> Self {
+        let fallback = if semantic {
+            match compose::semantic_diff(remote_compose, local_compose) {
+                Ok(changes) => return ComposeDiff::Semantic(changes),
+                Err(e) => Some(e),
+            }
+        } else {
+            None
+        };
+        ComposeDiff::Lines(
+            unified_diff(remote_compose.trim_end(), local_compose.trim_end(), 3),
+            fallback,
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            ComposeDiff::Lines(lines, _) => lines.is_empty(),
+            ComposeDiff::Semantic(changes) => changes.is_empty(),
+        }
+    }
+
+    fn report(&self, env_changes: &[EnvChange]) {
+        match self {
+            ComposeDiff::Lines(lines, _) => Reporter::diff_details(lines, env_changes),
+            ComposeDiff::Semantic(changes) => {
+                Reporter::compose_changes(changes);
+                Reporter::diff_details(&[], env_changes);
+            }
+        }
+    }
 }
 
 /// Compare env vars by name; values are never included in the output.
@@ -126,6 +249,28 @@ fn diff_env(remote: &[EnvVar], local: &[EnvVar]) -> Vec<EnvChange> {
     changes
 }
 
+/// Compare the files uploaded with an SSH stack, by path and SHA-256 as the
+/// host reports them; contents aren't shown, as they may hold secrets.
+/// Env files with unresolved secrets count as unchanged when they exist.
+pub fn diff_files(remote: &BTreeMap<String, String>, local: &StackFiles) -> Vec<EnvChange> {
+    let mut changes = Vec::new();
+    for (path, content) in &local.files {
+        match remote.get(path) {
+            None => changes.push(EnvChange::Added(path.clone())),
+            Some(sum) if *sum != sha256_hex(content) && !local.unresolved.contains(path) => {
+                changes.push(EnvChange::Changed(path.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for path in remote.keys() {
+        if !local.files.contains_key(path) {
+            changes.push(EnvChange::Removed(path.clone()));
+        }
+    }
+    changes
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum DiffOp {
     Equal,
@@ -363,4 +508,32 @@ mod tests {
         let changes = diff_env(&remote, &local);
         assert_eq!(changes, vec![EnvChange::Changed("SECRET".to_string())]);
     }
+
+    #[test]
+    fn test_diff_files() {
+        let file = |path: &str, content: &str| (path.to_string(), content.as_bytes().to_vec());
+        let sum = |path: &str, content: &str| (path.to_string(), sha256_hex(content));
+        let remote = BTreeMap::from([sum("a.conf", "1"), sum("b.conf", "2"), sum("old", "")]);
+        let mut local = StackFiles {
+            files: BTreeMap::from([file("a.conf", "1"), file("b.conf", "3"), file("new", "")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            diff_files(&remote, &local),
+            vec![
+                EnvChange::Changed("b.conf".to_string()),
+                EnvChange::Added("new".to_string()),
+                EnvChange::Removed("old".to_string()),
+            ]
+        );
+        // An env file with unresolved secrets can't be compared
+        local.unresolved.push("b.conf".to_string());
+        assert_eq!(
+            diff_files(&remote, &local),
+            vec![
+                EnvChange::Added("new".to_string()),
+                EnvChange::Removed("old".to_string()),
+            ]
+        );
+    }
 }
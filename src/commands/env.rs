@@ -0,0 +1,64 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, PartialConfigFile};
+use crate::encryption::{self, Encryption, Keys};
+use crate::reporter::Reporter;
+
+/// Encrypt a plaintext env file with age (or SOPS), writing `<file>.age`
+/// (or `<file>.sops`) unless an output path is given.
+pub fn env_encrypt_command(
+    config_path: &str,
+    file: &str,
+    sops: bool,
+    output: Option<&str>,
+) -> Result<()> {
+    let config = load_config(config_path)?;
+    let encryption = if sops {
+        Encryption::Sops
+    } else {
+        Encryption::Age
+    };
+    let input = Path::new(file);
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => PathBuf::from(format!("{}.{}", file, encryption.name().to_lowercase())),
+    };
+
+    encryption::encrypt_file(input, &output, encryption, &keys(&config))?;
+    Reporter::encrypted_env_file(input.display(), output.display(), encryption.name());
+    Ok(())
+}
+
+/// Decrypt an encrypted env file to stdout, or to `output`.
+pub fn env_decrypt_command(config_path: &str, file: &str, output: Option<&str>) -> Result<()> {
+    let config = load_config(config_path)?;
+    let plaintext = encryption::decrypt_file(Path::new(file), &keys(&config))?;
+    match output {
+        Some(output) => {
+            encryption::write_plaintext(Path::new(output), &plaintext)?;
+            Reporter::decrypted_env_file(file, output);
+        }
+        None => print!("{}", plaintext),
+    }
+    Ok(())
+}
+
+/// Edit an encrypted env file in place.
+pub fn env_edit_command(config_path: &str, file: &str) -> Result<()> {
+    let config = load_config(config_path)?;
+    let changed = encryption::edit_file(Path::new(file), &keys(&config))?;
+    Reporter::edited_env_file(file, changed);
+    Ok(())
+}
+
+fn load_config(config_path: &str) -> Result<PartialConfigFile> {
+    config::age_config(Path::new(config_path))
+}
+
+fn keys(config: &PartialConfigFile) -> Keys<'_> {
+    Keys {
+        identity: config.age_identity.as_deref(),
+        recipients: config.age_recipients.as_deref(),
+    }
+}
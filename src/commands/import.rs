@@ -3,6 +3,7 @@ use std::path::Path;
 
 use crate::config::{self, ResolvedGlobalConfig};
 use crate::portainer::PortainerClient;
+use crate::reporter::Reporter;
 use crate::ssh::SshClient;
 
 pub fn import_command(config_path: &str, stack: &str, force: bool) -> Result<()> {
@@ -76,12 +77,12 @@ fn import_portainer(
         "Failed to write compose file: {}",
         compose_path.display()
     ))?;
-    println!("Wrote compose file to {}", compose_path.display());
+    Reporter::wrote_file(stack_name, "compose", compose_path.display());
 
     // Write env file if stack has env vars
     let env_file_ref = if !stack.env.is_empty() {
         config::write_env_file(&env_path, &stack.env)?;
-        println!("Wrote env file to {}", env_path.display());
+        Reporter::wrote_file(stack_name, "env", env_path.display());
         Some(env_filename.as_str())
     } else {
         None
@@ -89,7 +90,7 @@ fn import_portainer(
 
     // Add stack to config
     config::append_stack_to_config(config_path, stack_name, &compose_filename, env_file_ref)?;
-    println!("Added stack '{}' to config", stack_name);
+    Reporter::imported(stack_name);
 
     Ok(())
 }
@@ -106,13 +107,11 @@ fn import_ssh(config_path: &Path, stack_name: &str, client: &SshClient, force: b
     }
 
     // Check if stack exists on remote
-    if !client.stack_exists(stack_name)? {
-        anyhow::bail!(
-            "Stack '{}' not found on remote host {}",
-            stack_name,
-            client.host()
-        );
-    }
+    let remote = client.get_stack(stack_name)?.context(format!(
+        "Stack '{}' not found on remote host {}",
+        stack_name,
+        client.host()
+    ))?;
 
     // Define file paths
     let compose_filename = format!("{}.compose.yaml", stack_name);
@@ -129,16 +128,14 @@ fn import_ssh(config_path: &Path, stack_name: &str, client: &SshClient, force: b
     }
 
     // Fetch and write compose file
-    let compose_content = client.get_compose_content(stack_name)?;
-    std::fs::write(&compose_path, &compose_content).context(format!(
+    std::fs::write(&compose_path, &remote.compose).context(format!(
         "Failed to write compose file: {}",
         compose_path.display()
     ))?;
-    println!("Wrote compose file to {}", compose_path.display());
+    Reporter::wrote_file(stack_name, "compose", compose_path.display());
 
     // Fetch and write env file if it exists on remote
-    let env_content = client.get_env_content(stack_name)?;
-    let env_file_ref = if let Some(env) = env_content {
+    let env_file_ref = if let Some(env) = remote.env {
         if !force && env_path.exists() {
             anyhow::bail!(
                 "Env file '{}' already exists. Use --force to overwrite.",
@@ -147,7 +144,7 @@ fn import_ssh(config_path: &Path, stack_name: &str, client: &SshClient, force: b
         }
         std::fs::write(&env_path, &env)
             .context(format!("Failed to write env file: {}", env_path.display()))?;
-        println!("Wrote env file to {}", env_path.display());
+        Reporter::wrote_file(stack_name, "env", env_path.display());
         Some(env_filename.as_str())
     } else {
         None
@@ -155,7 +152,7 @@ fn import_ssh(config_path: &Path, stack_name: &str, client: &SshClient, force: b
 
     // Add stack to config
     config::append_stack_to_config(config_path, stack_name, &compose_filename, env_file_ref)?;
-    println!("Added stack '{}' to config", stack_name);
+    Reporter::imported(stack_name);
 
     Ok(())
 }
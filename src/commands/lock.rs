@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+use crate::compose::{self, ImageRef};
+use crate::config::resolve_stacks;
+use crate::lockfile::{self, LockFile};
+use crate::registry::{RegistryClient, RegistryImage};
+use crate::reporter::Reporter;
+
+/// Resolve the image tags in each stack's compose file to digests and write
+/// them to the lock file next to the config.
+pub fn lock_command(config_path: &str, stacks: &[String]) -> Result<()> {
+    let (_, configs) = resolve_stacks(config_path, stacks)?;
+    let Some(base_dir) = configs.first().map(|c| c.base_dir.clone()) else {
+        return Ok(());
+    };
+    let registry = RegistryClient::new();
+    let mut lock = LockFile::load(&base_dir)?;
+    // Locking everything also drops stacks removed from the config
+    if stacks.is_empty() {
+        lock.stacks
+            .retain(|name, _| configs.iter().any(|c| &c.name == name));
+    }
+
+    for config in &configs {
+        let compose = config.compose_content()?;
+        let (digests, skipped) = resolve_digests(&registry, &compose)
+            .context(format!("Failed to lock stack '{}'", config.name))?;
+        Reporter::locked(&config.name, &digests, &skipped);
+        lock.stacks.insert(config.name.clone(), digests);
+    }
+
+    lock.save(&base_dir)?;
+    Reporter::wrote_lock_file(LockFile::path(&base_dir).display());
+    Ok(())
+}
+
+/// Look up the digest of every image in a compose file. Returns the digests
+/// by image reference, and the images that can't be locked.
+fn resolve_digests(
+    registry: &RegistryClient,
+    compose: &str,
+) -> Result<(BTreeMap<String, String>, Vec<String>)> {
+    let mut digests = BTreeMap::new();
+    let mut skipped = Vec::new();
+    for (_, image) in compose::service_images(&compose::parse(compose)?) {
+        let Some(image) = image else { continue };
+        if digests.contains_key(&image) {
+            continue;
+        }
+        if !lockfile::is_lockable(&image) {
+            if image.contains('$') {
+                skipped.push(image);
+            }
+            continue;
+        }
+        let image_ref = ImageRef::parse(&image);
+        let digest = registry
+            .digest(&RegistryImage::from_ref(&image_ref), &image_ref.version())
+            .context(format!("Failed to resolve digest for {}", image))?;
+        digests.insert(image, digest);
+    }
+    Ok((digests, skipped))
+}
@@ -0,0 +1,146 @@
+use anyhow::Result;
+
+use crate::compose::{self, ImageRef};
+use crate::config::{Config, ResolvedGlobalConfig, resolve_stacks};
+use crate::portainer::PortainerClient;
+use crate::registry::{self, ImageStatus, RegistryClient, RegistryImage};
+use crate::reporter::Reporter;
+use crate::ssh::SshClient;
+
+/// Check each stack's images against their registries and return whether
+/// any image is outdated.
+pub fn outdated_command(config_path: &str, stacks: &[String]) -> Result<bool> {
+    let (global_config, configs) = resolve_stacks(config_path, stacks)?;
+    let registry = RegistryClient::new();
+    let mut outdated = false;
+    match &global_config {
+        ResolvedGlobalConfig::Portainer(p) => {
+            let client = PortainerClient::new(&p.host, &p.api_key);
+            for config in configs.iter().filter(|c| report_enabled(c)) {
+                let statuses = match client.find_stack_by_name(&config.name)? {
+                    Some(existing) => {
+                        let compose = client.get_stack_file(existing.id)?;
+                        check_images(&registry, &compose, |image| {
+                            client.image_repo_digests(config.endpoint_id, image)
+                        })?
+                    }
+                    None => check_images(&registry, &config.compose_content()?, |_| Ok(None))?,
+                };
+                outdated |= report(&config.name, &statuses);
+            }
+        }
+        ResolvedGlobalConfig::Ssh(s) => {
+            let client = SshClient::new(s);
+            for config in configs.iter().filter(|c| report_enabled(c)) {
+                let statuses = match client.get_stack(&config.name)? {
+                    Some(remote) => check_images(&registry, &remote.compose, |image| {
+                        client.image_repo_digests(image)
+                    })?,
+                    None => check_images(&registry, &config.compose_content()?, |_| Ok(None))?,
+                };
+                outdated |= report(&config.name, &statuses);
+            }
+        }
+    }
+    Ok(outdated)
+}
+
+fn report_enabled(config: &Config) -> bool {
+    if !config.enabled {
+        Reporter::disabled(&config.name);
+    }
+    config.enabled
+}
+
+fn report(name: &str, statuses: &[ImageStatus]) -> bool {
+    let outdated = statuses.iter().any(|s| s.is_outdated());
+    if outdated {
+        Reporter::outdated(name);
+    } else {
+        Reporter::up_to_date(name);
+    }
+    Reporter::image_statuses(statuses);
+    outdated
+}
+
+/// Check every service image in a compose file. Images are checked against
+/// the deployed compose file when the stack exists, so results describe
+/// what is running; `deployed_digests` looks up the digests the host pulled.
+fn check_images(
+    registry: &RegistryClient,
+    compose: &str,
+    deployed_digests: impl Fn(&str) -> Result<Option<Vec<String>>>,
+) -> Result<Vec<ImageStatus>> {
+    let compose = compose::parse(compose)?;
+    Ok(compose::service_images(&compose)
+        .into_iter()
+        .filter_map(|(service, image)| Some((service, image?)))
+        .map(|(service, image)| {
+            let mut status = ImageStatus {
+                service,
+                image,
+                newer_tag: None,
+                new_digest: None,
+                error: None,
+            };
+            if let Err(err) = check_image(registry, &mut status, &deployed_digests) {
+                status.error = Some(format!("{:#}", err));
+            }
+            status
+        })
+        .collect())
+}
+
+fn check_image(
+    registry: &RegistryClient,
+    status: &mut ImageStatus,
+    deployed_digests: impl Fn(&str) -> Result<Option<Vec<String>>>,
+) -> Result<()> {
+    if status.image.contains('$') {
+        anyhow::bail!("image reference uses variables");
+    }
+    let image = ImageRef::parse(&status.image);
+    let location = RegistryImage::from_ref(&image);
+
+    if let Some(tag) = &image.tag
+        && registry::is_version_tag(tag)
+    {
+        status.newer_tag = registry::newer_tag(tag, &registry.tags(&location)?);
+    }
+
+    // Images pinned to a digest can't move; for tags, compare what the
+    // registry serves now with what the host pulled
+    if image.digest.is_none()
+        && let Some(deployed) = deployed_digests(&status.image)?
+    {
+        let latest = registry.digest(&location, &image.version())?;
+        if !digest_matches(&deployed, &latest) {
+            status.new_digest = Some(latest);
+        }
+    }
+    Ok(())
+}
+
+/// Whether any `repo@digest` entry from `RepoDigests` has the given digest.
+fn digest_matches(repo_digests: &[String], digest: &str) -> bool {
+    repo_digests
+        .iter()
+        .any(|d| d.rsplit_once('@').is_some_and(|(_, d)| d == digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_matches() {
+        let deployed = vec![
+            "nginx@sha256:aaa".to_string(),
+            "mirror.example.com/nginx@sha256:bbb".to_string(),
+        ];
+        assert!(digest_matches(&deployed, "sha256:aaa"));
+        assert!(digest_matches(&deployed, "sha256:bbb"));
+        assert!(!digest_matches(&deployed, "sha256:ccc"));
+        assert!(!digest_matches(&[], "sha256:aaa"));
+    }
+}
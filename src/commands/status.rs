@@ -0,0 +1,264 @@
+use anyhow::Result;
+
+use super::{diff_files, format_timestamp};
+use crate::config::{Config, EnvVar, ResolvedGlobalConfig, resolve_stacks};
+use crate::lockfile::{self, LockFile};
+use crate::portainer::PortainerClient;
+use crate::reporter::{Reporter, StackStatus};
+use crate::ssh::{RemoteStack, SshClient};
+use crate::stamp::env_vars_content;
+
+/// Print one row per stack and return whether any stack has drifted from
+/// the local config. With `pinned`, the local config is compared with its
+/// images pinned to the lock file, as `sync --pinned` deploys it.
+pub fn status_command(config_path: &str, stacks: &[String], pinned: bool) -> Result<bool> {
+    let (global_config, configs) = resolve_stacks(config_path, stacks)?;
+    let lock_file = lockfile::load_for(pinned, &configs)?;
+    let lock_file = lock_file.as_ref();
+    let name_width = configs
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or_default()
+        .max("STACK".len());
+
+    Reporter::status_header(name_width);
+    let mut drift = false;
+    match &global_config {
+        ResolvedGlobalConfig::Portainer(p) => {
+            let client = PortainerClient::new(&p.host, &p.api_key);
+            for config in &configs {
+                let (status, updated) = status_portainer(config, &client, lock_file)?;
+                drift |= status.is_drift();
+                Reporter::status_row(&config.name, name_width, status, &format_updated(updated));
+            }
+        }
+        ResolvedGlobalConfig::Ssh(s) => {
+            let client = SshClient::new(s);
+            for config in &configs {
+                let (status, updated) = status_ssh(config, &client, lock_file)?;
+                drift |= status.is_drift();
+                Reporter::status_row(&config.name, name_width, status, &format_updated(updated));
+            }
+        }
+    }
+    Ok(drift)
+}
+
+fn format_updated(updated: Option<u64>) -> String {
+    format_timestamp(updated.unwrap_or_default())
+}
+
+fn status_portainer(
+    config: &Config,
+    client: &PortainerClient,
+    lock: Option<&LockFile>,
+) -> Result<(StackStatus, Option<u64>)> {
+    let existing = client.find_stack_by_name(&config.name)?;
+    let stamp = existing.as_ref().and_then(|e| e.stamp.clone());
+    let updated = stamp.as_ref().map(|s| s.deployed_at).or_else(|| {
+        existing
+            .as_ref()
+            .map(|e| e.update_date.max(e.creation_date))
+            .filter(|ts| *ts > 0)
+    });
+
+    let Some(existing) = existing else {
+        let status = if config.enabled {
+            StackStatus::Missing
+        } else {
+            StackStatus::Disabled
+        };
+        return Ok((status, None));
+    };
+    let running = existing.status == 1;
+    if !config.enabled {
+        return Ok((disabled_status(running), updated));
+    }
+
+    let local_compose = local_compose(config, lock)?;
+    let local_env = config.deployed_env(&existing.env, &config.env_vars()?);
+
+    let remote_compose = client.get_stack_file(existing.id)?;
+    let local_matches =
+        remote_compose.trim_end() == local_compose.trim_end() && existing.env == local_env;
+    let remote_env = env_vars_content(&existing.env);
+    let remote_matches_stamp = stamp.map(|s| s.matches(&remote_compose, remote_env.as_deref()));
+
+    Ok((
+        classify(local_matches, remote_matches_stamp, running),
+        updated,
+    ))
+}
+
+fn status_ssh(
+    config: &Config,
+    client: &SshClient,
+    lock: Option<&LockFile>,
+) -> Result<(StackStatus, Option<u64>)> {
+    let Some(remote) = client.get_stack(&config.name)? else {
+        let status = if config.enabled {
+            StackStatus::Missing
+        } else {
+            StackStatus::Disabled
+        };
+        return Ok((status, None));
+    };
+    let updated = remote.stamp.as_ref().map(|s| s.deployed_at);
+    if !config.enabled {
+        return Ok((disabled_status(remote.running), updated));
+    }
+
+    let local_matches = ssh_matches(
+        config,
+        &remote,
+        &local_compose(config, lock)?,
+        &config.env_vars()?,
+    )?;
+    let remote_matches_stamp = remote
+        .stamp
+        .as_ref()
+        .map(|s| s.matches(&remote.compose, remote.env_for(s.env_hmac.is_some())));
+
+    Ok((
+        classify(local_matches, remote_matches_stamp, remote.running),
+        updated,
+    ))
+}
+
+/// The compose file syncing would deploy, pinned when a lock file is given.
+fn local_compose(config: &Config, lock: Option<&LockFile>) -> Result<String> {
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    config.merge_compose(&compose_files)
+}
+
+/// Whether the stack on the host is what syncing the local config would
+/// deploy: the same compose file, `.env` and uploaded files.
+fn ssh_matches(
+    config: &Config,
+    remote: &RemoteStack,
+    local_compose: &str,
+    local_env: &[EnvVar],
+) -> Result<bool> {
+    let env_content = config.deployed_env_content(remote.env.as_deref(), local_env)?;
+    Ok(remote.compose.trim_end() == local_compose.trim_end()
+        && remote.env_for(env_content.is_some()).map(|s| s.trim_end())
+            == env_content.as_deref().map(|s| s.trim_end())
+        && diff_files(&remote.files, &config.stack_files(local_compose)?).is_empty())
+}
+
+/// A disabled stack that is still running has a pending local change: the
+/// next sync will stop it.
+fn disabled_status(running: bool) -> StackStatus {
+    if running {
+        StackStatus::LocalChanged
+    } else {
+        StackStatus::Disabled
+    }
+}
+
+/// Decide the status of an enabled stack that exists on the remote. The
+/// deployment stamp tells the two kinds of difference apart: if the remote
+/// no longer matches what stack-sync last deployed, someone changed it by
+/// hand. Without a stamp, differences are attributed to local changes.
+fn classify(local_matches: bool, remote_matches_stamp: Option<bool>, running: bool) -> StackStatus {
+    if !local_matches {
+        if remote_matches_stamp == Some(false) {
+            StackStatus::RemoteDrifted
+        } else {
+            StackStatus::LocalChanged
+        }
+    } else if !running {
+        StackStatus::Stopped
+    } else {
+        StackStatus::InSync
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_in_sync() {
+        assert_eq!(classify(true, Some(true), true), StackStatus::InSync);
+        assert_eq!(classify(true, None, true), StackStatus::InSync);
+    }
+
+    #[test]
+    fn test_classify_stopped() {
+        assert_eq!(classify(true, Some(true), false), StackStatus::Stopped);
+    }
+
+    #[test]
+    fn test_classify_local_changed() {
+        assert_eq!(classify(false, Some(true), true), StackStatus::LocalChanged);
+        assert_eq!(classify(false, None, true), StackStatus::LocalChanged);
+    }
+
+    #[test]
+    fn test_classify_remote_drifted() {
+        assert_eq!(
+            classify(false, Some(false), true),
+            StackStatus::RemoteDrifted
+        );
+    }
+
+    #[test]
+    fn test_ssh_matches_with_unresolved_secret() {
+        use crate::config::{PartialConfigFile, SshGlobalConfig};
+        use crate::secrets;
+
+        let partial: PartialConfigFile =
+            toml::from_str("[stacks.web]\ncompose_file = \"compose.yaml\"\n").unwrap();
+        let global = ResolvedGlobalConfig::Ssh(SshGlobalConfig {
+            host: "host".to_string(),
+            ssh_user: None,
+            ssh_key: None,
+            host_dir: "/stacks".to_string(),
+        });
+        let config = partial
+            .resolve("web", &global, std::path::Path::new("."))
+            .unwrap();
+        let compose = "services:\n  web:\n    image: nginx\n";
+        let remote = RemoteStack {
+            compose: compose.to_string(),
+            env: Some("LOG=info\nTOKEN=hunter2\n".to_string()),
+            running: true,
+            ..Default::default()
+        };
+        let env = |token: &str| {
+            vec![
+                EnvVar {
+                    name: "LOG".to_string(),
+                    value: "info".to_string(),
+                },
+                EnvVar {
+                    name: "TOKEN".to_string(),
+                    value: token.to_string(),
+                },
+            ]
+        };
+        // An unresolved secret under the default `replace` strategy keeps
+        // the deployed value instead of showing up as a change
+        assert!(ssh_matches(&config, &remote, compose, &env(secrets::UNRESOLVED)).unwrap());
+        assert!(ssh_matches(&config, &remote, compose, &env("hunter2")).unwrap());
+        assert!(!ssh_matches(&config, &remote, compose, &env("rotated")).unwrap());
+    }
+
+    #[test]
+    fn test_disabled_status() {
+        assert_eq!(disabled_status(false), StackStatus::Disabled);
+        assert_eq!(disabled_status(true), StackStatus::LocalChanged);
+    }
+
+    #[test]
+    fn test_drift_statuses() {
+        assert!(!StackStatus::InSync.is_drift());
+        assert!(!StackStatus::Disabled.is_drift());
+        assert!(StackStatus::LocalChanged.is_drift());
+        assert!(StackStatus::RemoteDrifted.is_drift());
+        assert!(StackStatus::Missing.is_drift());
+        assert!(StackStatus::Stopped.is_drift());
+    }
+}
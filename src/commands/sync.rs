@@ -1,36 +1,76 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
+use super::{
+    diff_files, ensure_compose_valid, ensure_variables, report_image_changes,
+    report_structured_diff,
+};
+use crate::compose;
 use crate::config::{self, Config, ResolvedGlobalConfig, resolve_stacks};
-use crate::portainer::{self, PortainerClient};
+use crate::lock::{self, LockTarget};
+use crate::lockfile::{self, LockFile};
+use crate::portainer::{self, PortainerClient, StackDeploy};
 use crate::reporter::Reporter;
-use crate::ssh::SshClient;
+use crate::ssh::{DeployRequest, SshClient};
+use crate::stamp::DeployStamp;
 
-pub fn sync_command(
-    config_path: &str,
-    stacks: &[String],
-    dry_run: bool,
-    verbose: bool,
-    redeploy: bool,
-) -> Result<()> {
+/// How `sync` deploys, from its command-line flags.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncOptions {
+    /// Preview the changes without deploying.
+    pub dry_run: bool,
+    /// Show the stack's details.
+    pub verbose: bool,
+    /// Pull images and recreate containers even when nothing changed.
+    pub redeploy: bool,
+    /// How long to wait for another sync to release a stack's lock.
+    pub lock_timeout: Duration,
+    /// Compare compose files as YAML.
+    pub semantic: bool,
+    /// Deploy images pinned to the lock file.
+    pub pinned: bool,
+    /// Deploy even when validation or the variable check fails.
+    pub skip_check: bool,
+}
+
+pub fn sync_command(config_path: &str, stacks: &[String], options: &SyncOptions) -> Result<()> {
     let (global_config, configs) = resolve_stacks(config_path, stacks)?;
+    let lock_file = lockfile::load_for(options.pinned, &configs)?;
+    let lock_file = lock_file.as_ref();
+    if !options.skip_check {
+        for config in configs.iter().filter(|c| c.enabled) {
+            ensure_compose_valid(config)?;
+        }
+    }
     match &global_config {
         ResolvedGlobalConfig::Portainer(p) => {
             for config in &configs {
                 let client = portainer::PortainerClient::new(&p.host, &p.api_key);
-                if dry_run {
-                    sync_portainer_dry_run(config, &client, verbose, redeploy)?;
+                if options.dry_run {
+                    sync_portainer_dry_run(config, &client, options, lock_file)?;
                 } else {
-                    sync_portainer(config, &client, redeploy)?;
+                    let _lock = lock::acquire(
+                        LockTarget::Portainer(&client),
+                        &config.name,
+                        options.lock_timeout,
+                    )?;
+                    sync_portainer(config, &client, options, lock_file)?;
                 }
             }
         }
         ResolvedGlobalConfig::Ssh(s) => {
             let client = SshClient::new(s);
             for config in &configs {
-                if dry_run {
-                    sync_ssh_dry_run(config, &client, s, verbose, redeploy)?;
+                if options.dry_run {
+                    sync_ssh_dry_run(config, &client, s, options, lock_file)?;
                 } else {
-                    sync_ssh(config, &client, s, redeploy)?;
+                    let _lock = lock::acquire(
+                        LockTarget::Ssh(&client),
+                        &config.name,
+                        options.lock_timeout,
+                    )?;
+                    sync_ssh(config, &client, s, options, lock_file)?;
                 }
             }
         }
@@ -38,11 +78,38 @@ pub fn sync_command(
     Ok(())
 }
 
+/// The env currently deployed on Portainer, if the stack exists.
+fn remote_env(existing: Option<&portainer::Stack>) -> &[config::EnvVar] {
+    existing.map(|e| e.env.as_slice()).unwrap_or_default()
+}
+
+/// Compare compose files, reporting when `--semantic` had to fall back to
+/// comparing text.
+fn compose_matches(name: &str, remote: &str, local: &str, semantic: bool) -> bool {
+    let (matches, fallback) = compose::compose_matches(remote, local, semantic);
+    if let Some(reason) = fallback {
+        Reporter::semantic_fallback(name, reason);
+    }
+    matches
+}
+
+/// Files uploaded by an earlier deploy that are no longer part of the stack.
+fn stale_files(
+    remote: &BTreeMap<String, String>,
+    local: &BTreeMap<String, Vec<u8>>,
+) -> Vec<String> {
+    remote
+        .keys()
+        .filter(|path| !local.contains_key(*path))
+        .cloned()
+        .collect()
+}
+
 fn sync_portainer_dry_run(
     config: &Config,
     client: &PortainerClient,
-    verbose: bool,
-    redeploy: bool,
+    options: &SyncOptions,
+    lock: Option<&LockFile>,
 ) -> Result<()> {
     if !config.enabled {
         match client.find_stack_by_name(&config.name)? {
@@ -59,27 +126,41 @@ fn sync_portainer_dry_run(
         return Ok(());
     }
 
-    let compose_path = config.compose_path();
-    let compose_content = std::fs::read_to_string(&compose_path).context(format!(
-        "Failed to read compose file: {}",
-        compose_path.display()
-    ))?;
-    let env_vars = match config.env_path() {
-        Some(path) => config::parse_env_file(&path)?,
-        None => vec![],
-    };
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    let compose_content = config.merge_compose(&compose_files)?;
+    let local_env = config.env_vars()?;
+    let existing = client.find_stack_by_name(&config.name)?;
+    let env_vars = config.deployed_env(remote_env(existing.as_ref()), &local_env);
+    if !options.skip_check {
+        ensure_variables(config, &compose_content, &env_vars)?;
+    }
 
-    match client.find_stack_by_name(&config.name)? {
+    match existing {
         Some(existing) => {
             let remote_compose = client.get_stack_file(existing.id)?;
-            if remote_compose.trim_end() == compose_content.trim_end() && existing.env == env_vars {
-                if redeploy {
+            if compose_matches(
+                &config.name,
+                &remote_compose,
+                &compose_content,
+                options.semantic,
+            ) && existing.env == env_vars
+            {
+                if options.redeploy {
                     Reporter::would_redeploy(&config.name);
                 } else {
                     Reporter::up_to_date(&config.name);
                 }
             } else {
                 Reporter::would_update(&config.name, existing.id);
+                report_image_changes(&remote_compose, &compose_content);
+                report_structured_diff(
+                    &remote_compose,
+                    &compose_content,
+                    &existing.env,
+                    &env_vars,
+                    &[],
+                    options.semantic,
+                );
             }
         }
         None => {
@@ -87,13 +168,11 @@ fn sync_portainer_dry_run(
         }
     }
 
-    if verbose {
-        let env_info = config
-            .env_path()
-            .map(|p| (p.display().to_string(), env_vars.len()));
+    if options.verbose {
+        let env_info = config.env_source().map(|source| (source, env_vars.len()));
         Reporter::stack_details(
             &config.host,
-            compose_path.display(),
+            config.compose_source(),
             compose_content.len(),
             env_info,
             config.endpoint_id,
@@ -103,7 +182,12 @@ fn sync_portainer_dry_run(
     Ok(())
 }
 
-fn sync_portainer(config: &Config, client: &PortainerClient, redeploy: bool) -> Result<()> {
+fn sync_portainer(
+    config: &Config,
+    client: &PortainerClient,
+    options: &SyncOptions,
+    lock: Option<&LockFile>,
+) -> Result<()> {
     if !config.enabled {
         match client.find_stack_by_name(&config.name)? {
             Some(existing) if existing.status == 1 => {
@@ -121,48 +205,45 @@ fn sync_portainer(config: &Config, client: &PortainerClient, redeploy: bool) ->
         return Ok(());
     }
 
-    let compose_path = config.compose_path();
-    let compose_content = std::fs::read_to_string(&compose_path).context(format!(
-        "Failed to read compose file: {}",
-        compose_path.display()
-    ))?;
-    let env_vars = match config.env_path() {
-        Some(path) => config::parse_env_file(&path)?,
-        None => vec![],
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    let compose_content = config.merge_compose(&compose_files)?;
+    let local_env = config.env_vars()?;
+    let existing = client.find_stack_by_name(&config.name)?;
+    let env_vars = config.deployed_env(remote_env(existing.as_ref()), &local_env);
+    if !options.skip_check {
+        ensure_variables(config, &compose_content, &env_vars)?;
+    }
+    let stamp = DeployStamp::for_env_vars(config, &compose_content, &env_vars);
+    let deploy = StackDeploy {
+        file_content: &compose_content,
+        env: &env_vars,
+        stamp: config.portainer_stamp.then_some(&stamp),
     };
 
-    match client.find_stack_by_name(&config.name)? {
+    match existing {
         Some(existing) => {
             let remote_compose = client.get_stack_file(existing.id)?;
-            let needs_update =
-                remote_compose.trim_end() != compose_content.trim_end() || existing.env != env_vars;
+            let needs_update = !compose_matches(
+                &config.name,
+                &remote_compose,
+                &compose_content,
+                options.semantic,
+            ) || existing.env != env_vars;
             let was_inactive = existing.status == 2;
 
             if needs_update {
                 Reporter::updating(&config.name);
-                let stack = client.update_stack(
-                    existing.id,
-                    config.endpoint_id,
-                    &compose_content,
-                    env_vars,
-                    false,
-                    true,
-                )?;
+                let stack =
+                    client.update_stack(existing.id, config.endpoint_id, &deploy, false, true)?;
                 Reporter::updated(&stack.name, stack.id);
             } else if was_inactive {
                 Reporter::starting(&config.name);
                 let stack = client.start_stack(existing.id, config.endpoint_id)?;
                 Reporter::started(&stack.name, stack.id);
-            } else if redeploy {
+            } else if options.redeploy {
                 Reporter::redeploying(&config.name);
-                let stack = client.update_stack(
-                    existing.id,
-                    config.endpoint_id,
-                    &compose_content,
-                    env_vars,
-                    false,
-                    true,
-                )?;
+                let stack =
+                    client.update_stack(existing.id, config.endpoint_id, &deploy, false, true)?;
                 Reporter::redeployed(&stack.name, stack.id);
             } else {
                 Reporter::up_to_date(&config.name);
@@ -170,12 +251,7 @@ fn sync_portainer(config: &Config, client: &PortainerClient, redeploy: bool) ->
         }
         None => {
             Reporter::creating(&config.name);
-            let stack = client.create_stack(
-                config.endpoint_id,
-                &config.name,
-                &compose_content,
-                env_vars,
-            )?;
+            let stack = client.create_stack(config.endpoint_id, &config.name, &deploy)?;
             Reporter::created(&stack.name, stack.id);
         }
     }
@@ -187,69 +263,85 @@ fn sync_ssh_dry_run(
     config: &Config,
     client: &SshClient,
     ssh_config: &config::SshGlobalConfig,
-    verbose: bool,
-    redeploy: bool,
+    options: &SyncOptions,
+    lock: Option<&LockFile>,
 ) -> Result<()> {
     if !config.enabled {
-        let exists = client.stack_exists(&config.name)?;
-        if exists {
-            let running = client.stack_is_running(&config.name)?;
-            if running {
+        match client.get_stack(&config.name)? {
+            Some(remote) if remote.running => {
                 Reporter::would_stop(&config.name, client.host());
-            } else {
+            }
+            Some(_) => {
                 Reporter::already_stopped(&config.name);
             }
-        } else {
-            Reporter::disabled(&config.name);
+            None => {
+                Reporter::disabled(&config.name);
+            }
         }
         return Ok(());
     }
 
-    let compose_path = config.compose_path();
-    let compose_content = std::fs::read_to_string(&compose_path).context(format!(
-        "Failed to read compose file: {}",
-        compose_path.display()
-    ))?;
-    let env_content = match config.env_path() {
-        Some(path) => Some(
-            std::fs::read_to_string(&path)
-                .context(format!("Failed to read env file: {}", path.display()))?,
-        ),
-        None => None,
-    };
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    let compose_content = config.merge_compose(&compose_files)?;
+    let local_env = config.env_vars()?;
+    let remote = client.get_stack(&config.name)?;
+    let env_content =
+        config.deployed_env_content(remote.as_ref().and_then(|r| r.env.as_deref()), &local_env)?;
+    if !options.skip_check {
+        let env_vars = config::parse_env_str(env_content.as_deref().unwrap_or_default())?;
+        ensure_variables(config, &compose_content, &env_vars)?;
+    }
+    let files = config.stack_files(&compose_content)?;
+    if !files.skipped.is_empty() {
+        Reporter::files_not_uploaded(&config.name, &files.skipped);
+    }
 
-    let exists = client.stack_exists(&config.name)?;
-    if exists {
-        let remote_compose = client.get_compose_content(&config.name)?;
-        let remote_env = client.get_env_content(&config.name)?;
-        let compose_changed = remote_compose.trim_end() != compose_content.trim_end();
-        let env_changed = remote_env.as_deref().map(|s| s.trim_end())
-            != env_content.as_deref().map(|s| s.trim_end());
+    match remote {
+        Some(remote) => {
+            let compose_changed = !compose_matches(
+                &config.name,
+                &remote.compose,
+                &compose_content,
+                options.semantic,
+            );
+            let remote_env = remote.env_for(env_content.is_some());
+            let env_changed =
+                remote_env.map(|s| s.trim_end()) != env_content.as_deref().map(|s| s.trim_end());
+            let files_changed = !diff_files(&remote.files, &files).is_empty();
 
-        if compose_changed || env_changed {
-            Reporter::would_update(&config.name, client.host());
-        } else {
-            let running = client.stack_is_running(&config.name)?;
-            if !running {
+            if compose_changed || env_changed || files_changed || !remote.running {
                 Reporter::would_update(&config.name, client.host());
-            } else if redeploy {
+                report_image_changes(&remote.compose, &compose_content);
+                report_structured_diff(
+                    &remote.compose,
+                    &compose_content,
+                    &config::parse_env_str(remote_env.unwrap_or_default())?,
+                    &config::parse_env_str(env_content.as_deref().unwrap_or_default())?,
+                    &diff_files(&remote.files, &files),
+                    options.semantic,
+                );
+            } else if options.redeploy {
                 Reporter::would_redeploy(&config.name);
             } else {
                 Reporter::up_to_date(&config.name);
             }
         }
-    } else {
-        Reporter::would_create(&config.name);
+        None => {
+            Reporter::would_create(&config.name);
+        }
     }
 
-    if verbose {
-        let env_info = config.env_path().map(|p| {
-            let vars = config::parse_env_file(&p).unwrap_or_default();
-            (p.display().to_string(), vars.len())
+    if options.verbose {
+        // Count from the content already resolved, so secret commands
+        // don't run twice
+        let env_info = config.env_source().map(|source| {
+            let vars = config::parse_env_str(env_content.as_deref().unwrap_or_default())
+                .unwrap_or_default();
+            (source, vars.len())
         });
         Reporter::ssh_stack_details(
             &ssh_config.host,
-            compose_path.display(),
+            config.compose_source(),
             compose_content.len(),
             env_info,
             &ssh_config.host_dir,
@@ -263,81 +355,91 @@ fn sync_ssh(
     config: &Config,
     client: &SshClient,
     ssh_config: &config::SshGlobalConfig,
-    redeploy: bool,
+    options: &SyncOptions,
+    lock: Option<&LockFile>,
 ) -> Result<()> {
     if !config.enabled {
-        let exists = client.stack_exists(&config.name)?;
-        if exists {
-            let running = client.stack_is_running(&config.name)?;
-            if running {
+        match client.get_stack(&config.name)? {
+            Some(remote) if remote.running => {
                 Reporter::stopping(&config.name);
                 client.stop_stack(&config.name)?;
                 Reporter::stopped(&config.name, &ssh_config.host);
-            } else {
+            }
+            Some(_) => {
                 Reporter::already_stopped(&config.name);
             }
-        } else {
-            Reporter::disabled(&config.name);
+            None => {
+                Reporter::disabled(&config.name);
+            }
         }
         return Ok(());
     }
 
-    let compose_path = config.compose_path();
-    let compose_content = std::fs::read_to_string(&compose_path).context(format!(
-        "Failed to read compose file: {}",
-        compose_path.display()
-    ))?;
-    let env_content = match config.env_path() {
-        Some(path) => Some(
-            std::fs::read_to_string(&path)
-                .context(format!("Failed to read env file: {}", path.display()))?,
-        ),
-        None => None,
+    let compose_files = lockfile::maybe_pin(lock, &config.name, config.read_compose_files()?)?;
+    let compose_content = config.merge_compose(&compose_files)?;
+    let local_env = config.env_vars()?;
+    let remote = client.get_stack(&config.name)?;
+    let env_content =
+        config.deployed_env_content(remote.as_ref().and_then(|r| r.env.as_deref()), &local_env)?;
+    if !options.skip_check {
+        let env_vars = config::parse_env_str(env_content.as_deref().unwrap_or_default())?;
+        ensure_variables(config, &compose_content, &env_vars)?;
+    }
+    let files = config.stack_files(&compose_content)?;
+    if !files.skipped.is_empty() {
+        Reporter::files_not_uploaded(&config.name, &files.skipped);
+    }
+    let stamp = DeployStamp::new(config, &compose_content, env_content.as_deref());
+    let request = DeployRequest {
+        compose_files: &compose_files,
+        env_content: env_content.as_deref(),
+        stack_files: &files.files,
+        stale: &[],
+        remove_env: false,
+        stamp: &stamp,
+        pull: options.redeploy,
     };
 
-    let exists = client.stack_exists(&config.name)?;
-    if exists {
-        let remote_compose = client.get_compose_content(&config.name)?;
-        let remote_env = client.get_env_content(&config.name)?;
-        let compose_changed = remote_compose.trim_end() != compose_content.trim_end();
-        let env_changed = remote_env.as_deref().map(|s| s.trim_end())
-            != env_content.as_deref().map(|s| s.trim_end());
-        let running = client.stack_is_running(&config.name)?;
-
-        if compose_changed || env_changed {
-            Reporter::updating(&config.name);
-            client.deploy_stack(
-                &config.name,
-                &compose_content,
-                env_content.as_deref(),
-                redeploy,
-            )?;
-            Reporter::updated(&config.name, &ssh_config.host);
-        } else if !running {
-            Reporter::starting(&config.name);
-            client.deploy_stack(
+    match remote {
+        Some(remote) => {
+            let compose_changed = !compose_matches(
                 &config.name,
+                &remote.compose,
                 &compose_content,
-                env_content.as_deref(),
-                redeploy,
-            )?;
-            Reporter::started(&config.name, &ssh_config.host);
-        } else if redeploy {
-            Reporter::redeploying(&config.name);
-            client.deploy_stack(&config.name, &compose_content, env_content.as_deref(), true)?;
-            Reporter::redeployed(&config.name, &ssh_config.host);
-        } else {
-            Reporter::up_to_date(&config.name);
+                options.semantic,
+            );
+            let remote_env = remote.env_for(env_content.is_some());
+            let env_changed =
+                remote_env.map(|s| s.trim_end()) != env_content.as_deref().map(|s| s.trim_end());
+            let files_changed = !diff_files(&remote.files, &files).is_empty();
+            let stale = stale_files(&remote.files, &files.files);
+            let request = DeployRequest {
+                stale: &stale,
+                remove_env: env_content.is_none() && remote.wrote_env(),
+                ..request
+            };
+
+            if compose_changed || env_changed || files_changed {
+                Reporter::updating(&config.name);
+                client.deploy_stack(&config.name, &request)?;
+                Reporter::updated(&config.name, &ssh_config.host);
+            } else if !remote.running {
+                Reporter::starting(&config.name);
+                client.deploy_stack(&config.name, &request)?;
+                Reporter::started(&config.name, &ssh_config.host);
+            } else if options.redeploy {
+                Reporter::redeploying(&config.name);
+                client.deploy_stack(&config.name, &request)?;
+                Reporter::redeployed(&config.name, &ssh_config.host);
+            } else {
+                Reporter::up_to_date(&config.name);
+            }
+        }
+        None => {
+            Reporter::creating(&config.name);
+            client.deploy_stack(&config.name, &request)?;
+            Reporter::created(&config.name, &ssh_config.host);
         }
-    } else {
-        Reporter::creating(&config.name);
-        client.deploy_stack(
-            &config.name,
-            &compose_content,
-            env_content.as_deref(),
-            redeploy,
-        )?;
-        Reporter::created(&config.name, &ssh_config.host);
     }
 
     Ok(())
@@ -0,0 +1,35 @@
+use anyhow::Result;
+
+use crate::config::{ResolvedGlobalConfig, resolve_stacks};
+use crate::lock::LockTarget;
+use crate::portainer::PortainerClient;
+use crate::reporter::Reporter;
+use crate::ssh::SshClient;
+
+pub fn unlock_command(config_path: &str, stacks: &[String]) -> Result<()> {
+    let (global_config, configs) = resolve_stacks(config_path, stacks)?;
+    match &global_config {
+        ResolvedGlobalConfig::Portainer(p) => {
+            let client = PortainerClient::new(&p.host, &p.api_key);
+            for config in &configs {
+                unlock(LockTarget::Portainer(&client), &config.name)?;
+            }
+        }
+        ResolvedGlobalConfig::Ssh(s) => {
+            let client = SshClient::new(s);
+            for config in &configs {
+                unlock(LockTarget::Ssh(&client), &config.name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn unlock(target: LockTarget, name: &str) -> Result<()> {
+    if target.force_unlock(name)? {
+        Reporter::unlocked(name);
+    } else {
+        Reporter::not_locked(name);
+    }
+    Ok(())
+}
@@ -4,6 +4,7 @@ use crate::config::{Config, ResolvedGlobalConfig, resolve_stacks};
 use crate::portainer::{self, PortainerClient};
 use crate::reporter::Reporter;
 use crate::ssh::SshClient;
+use crate::stamp::DeployStamp;
 
 pub fn view_command(config_path: &str, stacks: &[String], verbose: bool) -> Result<()> {
     let (global_config, configs) = resolve_stacks(config_path, stacks)?;
@@ -54,6 +55,9 @@ fn view_portainer(config: &Config, client: &PortainerClient, verbose: bool) -> R
             format_timestamp(stack.update_date),
             stack.env.len(),
         );
+        if let Some(stamp) = &stack.stamp {
+            report_stamp(stamp);
+        }
     }
 
     Ok(())
@@ -65,13 +69,12 @@ fn view_ssh(
     ssh_config: &crate::config::SshGlobalConfig,
     verbose: bool,
 ) -> Result<()> {
-    let exists = client.stack_exists(&config.name)?;
-    if !exists {
+    let Some(remote) = client.get_stack(&config.name)? else {
         Reporter::not_found(&config.name);
         return Ok(());
-    }
+    };
 
-    let running = client.stack_is_running(&config.name)?;
+    let running = remote.running;
     let status = if running { "active" } else { "inactive" };
 
     Reporter::view(&config.name, &ssh_config.host, status);
@@ -83,12 +86,26 @@ fn view_ssh(
             None
         };
         Reporter::ssh_view_details(&ssh_config.host, &ssh_config.host_dir, ps_output.as_deref());
+        if let Some(stamp) = &remote.stamp {
+            report_stamp(stamp);
+        }
     }
 
     Ok(())
 }
 
-fn format_timestamp(ts: u64) -> String {
+fn report_stamp(stamp: &DeployStamp) {
+    Reporter::stamp_details(
+        &stamp.deployed_by,
+        &stamp.machine,
+        format_timestamp(stamp.deployed_at),
+        stamp.git_commit.as_deref(),
+        &stamp.compose_sha256,
+        stamp.env_hmac.as_deref(),
+    );
+}
+
+pub fn format_timestamp(ts: u64) -> String {
     if ts == 0 {
         return "n/a".to_string();
     }
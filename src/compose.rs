@@ -0,0 +1,1071 @@
+use anyhow::{Context, Result};
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::config::EnvVar;
+
+/// One structural difference between two compose files, addressed by a
+/// dotted path such as `services.web.image` or `services.web.ports[0]`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComposeChange {
+    Added {
+        path: String,
+        value: String,
+    },
+    Removed {
+        path: String,
+        value: String,
+    },
+    Changed {
+        path: String,
+        old: String,
+        new: String,
+    },
+}
+
+/// Parse a compose file into a YAML value, with anchors resolved and `<<`
+/// merge keys applied so that equivalent spellings compare equal.
+pub fn parse(content: &str) -> Result<Value> {
+    let mut value: Value =
+        serde_yaml::from_str(content).context("Failed to parse compose file as YAML")?;
+    value
+        .apply_merge()
+        .context("Failed to apply YAML merge keys")?;
+    Ok(value)
+}
+
+/// Compare two compose files structurally. Key order, quoting, comments and
+/// formatting are ignored; list order is not, since it is significant for
+/// things like `command` and `entrypoint`.
+pub fn semantic_diff(old: &str, new: &str) -> Result<Vec<ComposeChange>> {
+    let old = parse(old)?;
+    let new = parse(new)?;
+    let mut changes = Vec::new();
+    diff_values("", &old, &new, &mut changes);
+    Ok(changes)
+}
+
+/// Whether the deployed compose file matches the local one. With `semantic`
+/// set, files are compared as YAML, falling back to a text comparison when
+/// either side doesn't parse; the reason is returned for the caller to
+/// report.
+pub fn compose_matches(remote: &str, local: &str, semantic: bool) -> (bool, Option<anyhow::Error>) {
+    let fallback = if semantic {
+        match semantic_diff(remote, local) {
+            Ok(changes) => return (changes.is_empty(), None),
+            Err(e) => Some(e),
+        }
+    } else {
+        None
+    };
+    (remote.trim_end() == local.trim_end(), fallback)
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<ComposeChange>) {
+    match (old, new) {
+        (Value::Mapping(old_map), Value::Mapping(new_map)) => {
+            for (key, new_value) in new_map {
+                let child = child_path(path, key);
+                match old_map.get(key) {
+                    Some(old_value) => diff_values(&child, old_value, new_value, changes),
+                    None => changes.push(ComposeChange::Added {
+                        path: child,
+                        value: display_value(new_value),
+                    }),
+                }
+            }
+            for (key, old_value) in old_map {
+                if !new_map.contains_key(key) {
+                    changes.push(ComposeChange::Removed {
+                        path: child_path(path, key),
+                        value: display_value(old_value),
+                    });
+                }
+            }
+        }
+        (Value::Sequence(old_seq), Value::Sequence(new_seq)) => {
+            for i in 0..old_seq.len().max(new_seq.len()) {
+                let child = format!("{}[{}]", path, i);
+                match (old_seq.get(i), new_seq.get(i)) {
+                    (Some(o), Some(n)) => diff_values(&child, o, n, changes),
+                    (None, Some(n)) => changes.push(ComposeChange::Added {
+                        path: child,
+                        value: display_value(n),
+                    }),
+                    (Some(o), None) => changes.push(ComposeChange::Removed {
+                        path: child,
+                        value: display_value(o),
+                    }),
+                    (None, None) => {}
+                }
+            }
+        }
+        (Value::Tagged(o), Value::Tagged(n)) if o.tag == n.tag => {
+            diff_values(path, &o.value, &n.value, changes)
+        }
+        _ if old == new => {}
+        _ => changes.push(ComposeChange::Changed {
+            path: path.to_string(),
+            old: display_value(old),
+            new: display_value(new),
+        }),
+    }
+}
+
+/// Append a mapping key to a path. Keys that would be ambiguous in dotted
+/// form, like Traefik labels, are quoted in brackets.
+fn child_path(path: &str, key: &Value) -> String {
+    let key = match key {
+        Value::String(s) => s.clone(),
+        other => display_value(other),
+    };
+    let plain = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match (path.is_empty(), plain) {
+        (true, true) => key,
+        (false, true) => format!("{}.{}", path, key),
+        (_, false) => format!("{}[{:?}]", path, key),
+    }
+}
+
+/// Render a value on one line: scalars as written, collections in flow style.
+pub fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        // Quote strings that would otherwise read as another type, so that
+        // `80` -> `"80"` is visible
+        Value::String(s) => match serde_yaml::from_str::<Value>(s) {
+            Ok(Value::String(parsed)) if parsed == *s => s.clone(),
+            _ => format!("{:?}", s),
+        },
+        Value::Tagged(t) => format!("{} {}", t.tag, display_value(&t.value)),
+        Value::Sequence(_) | Value::Mapping(_) => {
+            serde_json::to_string(value).unwrap_or_else(|_| "…".to_string())
+        }
+    }
+}
+
+/// An image reference split into its parts, e.g.
+/// `ghcr.io/org/app:1.2@sha256:...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageRef {
+    /// Registry and repository, e.g. `ghcr.io/org/app` or `nginx`.
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Self {
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.to_string())),
+            None => (reference, None),
+        };
+        // A colon after the last slash starts the tag; earlier ones belong to
+        // a registry port (`registry:5000/app`)
+        let tag_start = name
+            .rfind(':')
+            .filter(|&i| name.rfind('/').is_none_or(|slash| i > slash));
+        let (repository, tag) = match tag_start {
+            Some(i) => (&name[..i], Some(name[i + 1..].to_string())),
+            None => (name, None),
+        };
+        Self {
+            repository: repository.to_string(),
+            tag,
+            digest,
+        }
+    }
+
+    /// The tag or digest that identifies this version of the image. Docker
+    /// pulls `latest` when neither is given.
+    pub fn version(&self) -> String {
+        match (&self.tag, &self.digest) {
+            (Some(tag), _) => tag.clone(),
+            (None, Some(digest)) => format!("@{}", digest),
+            (None, None) => "latest".to_string(),
+        }
+    }
+
+    /// Like `version`, with a shortened digest after the tag, to tell apart
+    /// references that share a tag but are pinned to different digests.
+    fn version_with_digest(&self) -> String {
+        match (&self.tag, &self.digest) {
+            (Some(tag), Some(digest)) => {
+                let short = match digest.split_once(':') {
+                    Some((algorithm, hex)) => format!("{}:{:.12}", algorithm, hex),
+                    None => format!("{:.12}", digest),
+                };
+                format!("{}@{}", tag, short)
+            }
+            _ => self.version(),
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a service's image changed between two compose files. Services
+/// without an `image` (build-only) are listed when added or removed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageChange {
+    /// Same repository, different tag or digest.
+    Bumped {
+        service: String,
+        repository: String,
+        old: String,
+        new: String,
+    },
+    /// The service now uses a different repository.
+    Replaced {
+        service: String,
+        old: String,
+        new: String,
+    },
+    ServiceAdded {
+        service: String,
+        image: Option<String>,
+    },
+    ServiceRemoved {
+        service: String,
+        image: Option<String>,
+    },
+}
+
+/// The `image` of each service, in file order.
+pub fn service_images(compose: &Value) -> Vec<(String, Option<String>)> {
+    let Some(services) = compose.get("services").and_then(|s| s.as_mapping()) else {
+        return vec![];
+    };
+    services
+        .iter()
+        .filter_map(|(name, service)| {
+            let name = name.as_str()?.to_string();
+            let image = service
+                .get("image")
+                .and_then(|i| i.as_str())
+                .map(|i| i.to_string());
+            Some((name, image))
+        })
+        .collect()
+}
+
+/// Summarize image changes per service between two compose files.
+pub fn image_changes(old: &str, new: &str) -> Result<Vec<ImageChange>> {
+    let old = service_images(&parse(old)?);
+    let new = service_images(&parse(new)?);
+    let mut changes = Vec::new();
+
+    for (service, new_image) in &new {
+        let Some((_, old_image)) = old.iter().find(|(s, _)| s == service) else {
+            changes.push(ImageChange::ServiceAdded {
+                service: service.clone(),
+                image: new_image.clone(),
+            });
+            continue;
+        };
+        let (Some(old_image), Some(new_image)) = (old_image, new_image) else {
+            continue;
+        };
+        if old_image == new_image {
+            continue;
+        }
+        let (old_ref, new_ref) = (ImageRef::parse(old_image), ImageRef::parse(new_image));
+        if old_ref.repository == new_ref.repository {
+            // Only the digest changed, e.g. a re-pinned tag
+            let (old, new) = if old_ref.version() == new_ref.version() {
+                (old_ref.version_with_digest(), new_ref.version_with_digest())
+            } else {
+                (old_ref.version(), new_ref.version())
+            };
+            changes.push(ImageChange::Bumped {
+                service: service.clone(),
+                repository: new_ref.repository.clone(),
+                old,
+                new,
+            });
+        } else {
+            changes.push(ImageChange::Replaced {
+                service: service.clone(),
+                old: old_image.clone(),
+                new: new_image.clone(),
+            });
+        }
+    }
+    for (service, old_image) in &old {
+        if !new.iter().any(|(s, _)| s == service) {
+            changes.push(ImageChange::ServiceRemoved {
+                service: service.clone(),
+                image: old_image.clone(),
+            });
+        }
+    }
+
+    Ok(changes)
+}
+
+/// How a stack's compose file and env file line up.
+#[derive(Debug, Default, PartialEq)]
+pub struct VariableCheck {
+    /// Referenced without a default but not defined, so deployed blank
+    pub missing: Vec<String>,
+    /// Referenced with a default but not defined, so the default is used
+    pub defaulted: Vec<String>,
+    /// Defined in the env file but never referenced
+    pub unused: Vec<String>,
+    /// Breaks the stack's env schema, as messages
+    pub invalid: Vec<String>,
+}
+
+impl VariableCheck {
+    /// Compare the variables a compose file reads with the env vars it is
+    /// deployed with. Unused vars aren't reported when a service loads an
+    /// `env_file`, since that passes every var to the container.
+    pub fn new(compose_content: &str, env: &[EnvVar]) -> Result<Self> {
+        let compose = parse(compose_content)?;
+        let refs = variable_refs(&compose);
+        let defined = |name: &str| env.iter().any(|v| v.name == name);
+
+        let mut check = Self::default();
+        for (name, has_default) in &refs {
+            match (defined(name), has_default) {
+                (true, _) => {}
+                (false, true) => check.defaulted.push(name.clone()),
+                (false, false) => check.missing.push(name.clone()),
+            }
+        }
+        if !uses_env_file(&compose) {
+            check.unused = env
+                .iter()
+                .filter(|v| !refs.contains_key(&v.name))
+                .map(|v| v.name.clone())
+                .collect();
+        }
+        Ok(check)
+    }
+
+    pub fn passed(&self) -> bool {
+        self.missing.is_empty() && self.invalid.is_empty()
+    }
+}
+
+/// Variables a compose file reads from the stack env: `${VAR}` and `$VAR`
+/// interpolations in any value, and `environment:` entries without a value,
+/// which docker compose passes through. Maps each name to whether every
+/// reference to it has a fallback (`${VAR:-default}`, `${VAR:+alt}`), so an
+/// unset value can't end up blank.
+pub fn variable_refs(compose: &Value) -> BTreeMap<String, bool> {
+    let mut refs = BTreeMap::new();
+    collect_interpolations(compose, &mut refs);
+
+    let services = compose.get("services").and_then(|s| s.as_mapping());
+    for service in services.into_iter().flat_map(|s| s.values()) {
+        let names: Vec<&str> = match service.get("environment") {
+            Some(Value::Sequence(entries)) => entries
+                .iter()
+                .filter_map(|e| e.as_str())
+                .filter(|e| !e.contains('='))
+                .collect(),
+            Some(Value::Mapping(entries)) => entries
+                .iter()
+                .filter(|(_, value)| value.is_null())
+                .filter_map(|(name, _)| name.as_str())
+                .collect(),
+            _ => vec![],
+        };
+        for name in names {
+            add_ref(&mut refs, name.trim(), false);
+        }
+    }
+    refs
+}
+
+/// Whether any service loads an `env_file`, which hands it every variable
+/// rather than only the referenced ones.
+pub fn uses_env_file(compose: &Value) -> bool {
+    compose
+        .get("services")
+        .and_then(|s| s.as_mapping())
+        .is_some_and(|services| services.values().any(|s| s.get("env_file").is_some()))
+}
+
+/// Relative paths a compose file reads from the project directory: bind
+/// mount sources (`./nginx.conf:/etc/nginx/nginx.conf`), `env_file`
+/// entries, and `file:` of top-level `configs` and `secrets`. Paths built
+/// from variables are skipped, since they can't be resolved locally.
+pub fn local_file_refs(compose: &Value) -> Vec<String> {
+    let mut refs = Vec::new();
+    let services = compose.get("services").and_then(|s| s.as_mapping());
+    for service in services.into_iter().flat_map(|s| s.values()) {
+        let volumes = service.get("volumes").and_then(|v| v.as_sequence());
+        for volume in volumes.into_iter().flatten() {
+            let source = match volume {
+                Value::String(short) => short.split(':').next(),
+                Value::Mapping(long)
+                    if long.get("type").and_then(|t| t.as_str()) == Some("bind") =>
+                {
+                    long.get("source").and_then(|s| s.as_str())
+                }
+                _ => None,
+            };
+            if let Some(source) = source.filter(|s| s.starts_with('.')) {
+                refs.push(source.to_string());
+            }
+        }
+        let env_files = match service.get("env_file") {
+            Some(Value::Sequence(items)) => items.iter().collect(),
+            Some(single) => vec![single],
+            None => vec![],
+        };
+        for entry in env_files {
+            let path = match entry {
+                Value::Mapping(long) => long.get("path").and_then(|p| p.as_str()),
+                short => short.as_str(),
+            };
+            refs.extend(path.map(String::from));
+        }
+    }
+    for kind in ["configs", "secrets"] {
+        let defined = compose.get(kind).and_then(|c| c.as_mapping());
+        for definition in defined.into_iter().flat_map(|d| d.values()) {
+            refs.extend(
+                definition
+                    .get("file")
+                    .and_then(|f| f.as_str())
+                    .map(String::from),
+            );
+        }
+    }
+    refs.retain(|r| !r.starts_with('/') && !r.starts_with('~') && !r.contains('$'));
+    refs.sort();
+    refs.dedup();
+    refs
+}
+
+fn collect_interpolations(value: &Value, refs: &mut BTreeMap<String, bool>) {
+    match value {
+        Value::String(s) => scan_interpolations(s, false, refs),
+        Value::Sequence(items) => items.iter().for_each(|v| collect_interpolations(v, refs)),
+        Value::Mapping(map) => map.values().for_each(|v| collect_interpolations(v, refs)),
+        Value::Tagged(tagged) => collect_interpolations(&tagged.value, refs),
+        _ => {}
+    }
+}
+
+/// Find the references in one string. `fallback` is set while scanning a
+/// default, where a reference only matters if the outer variable is unset.
+fn scan_interpolations(s: &str, fallback: bool, refs: &mut BTreeMap<String, bool>) {
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            rest = after;
+        } else if let Some(body) = after.strip_prefix('{') {
+            let Some(end) = closing_brace(body) else {
+                return;
+            };
+            let expr = &body[..end];
+            let name_len = expr
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(expr.len());
+            let (name, op) = expr.split_at(name_len);
+            let op = op.strip_prefix(':').unwrap_or(op);
+            let has_default = op.starts_with('-') || op.starts_with('+');
+            add_ref(refs, name, fallback || has_default);
+            if has_default {
+                scan_interpolations(&op[1..], true, refs);
+            }
+            rest = &body[end + 1..];
+        } else {
+            let name_len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            add_ref(refs, &after[..name_len], fallback);
+            rest = &after[name_len..];
+        }
+    }
+}
+
+fn closing_brace(body: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in body.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn add_ref(refs: &mut BTreeMap<String, bool>, name: &str, has_default: bool) {
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return;
+    }
+    let entry = refs.entry(name.to_string()).or_insert(true);
+    *entry &= has_default;
+}
+
+/// Merge compose files in order, later files overriding earlier ones, the
+/// way `docker compose -f a.yaml -f b.yaml` does. A single file is returned
+/// unchanged, so its formatting and comments are kept.
+pub fn merge_files(files: &[String]) -> Result<String> {
+    if let [single] = files {
+        return Ok(single.clone());
+    }
+    let mut merged = Value::Mapping(Mapping::new());
+    for (i, content) in files.iter().enumerate() {
+        let value = parse(content).context(format!("Invalid compose file #{}", i + 1))?;
+        merged = merge_value(&[], merged, value).unwrap_or(Value::Null);
+    }
+    serde_yaml::to_string(&merged).context("Failed to serialize merged compose file")
+}
+
+/// How a list in an override file combines with the one it overrides.
+enum ListMerge {
+    /// The override replaces the list, e.g. `command`.
+    Replace,
+    /// `KEY=value` items merge by key, e.g. `environment`.
+    ByKey,
+    /// Items merge by mount target, e.g. `volumes`.
+    ByTarget,
+    /// Items are appended unless already present, e.g. `ports`.
+    Append,
+}
+
+fn list_merge(path: &[String]) -> ListMerge {
+    if path.len() < 3 || path[0] != "services" {
+        return ListMerge::Append;
+    }
+    match path[2..].join(".").as_str() {
+        "command" | "entrypoint" | "healthcheck.test" => ListMerge::Replace,
+        "environment" | "labels" | "annotations" | "sysctls" | "build.args" | "build.labels"
+        | "deploy.labels" => ListMerge::ByKey,
+        "volumes" | "devices" | "secrets" | "configs" => ListMerge::ByTarget,
+        _ => ListMerge::Append,
+    }
+}
+
+/// Merge `over` into `base`. Returns `None` when `over` is tagged `!reset`,
+/// which removes the value; `!override` replaces it without merging.
+fn merge_value(path: &[String], base: Value, over: Value) -> Option<Value> {
+    let over = match over {
+        Value::Tagged(tagged) if tagged.tag == "reset" => return None,
+        Value::Tagged(tagged) if tagged.tag == "override" => return Some(tagged.value),
+        over => over,
+    };
+    let rule = list_merge(path);
+    let (base, over) = match rule {
+        ListMerge::ByKey => (key_value_mapping(base), key_value_mapping(over)),
+        _ => (base, over),
+    };
+    // Merge new mappings into an empty one, so tags inside them are applied
+    let base = match (base, &over) {
+        (Value::Null, Value::Mapping(_)) => Value::Mapping(Mapping::new()),
+        (base, _) => base,
+    };
+    match (base, over) {
+        (Value::Mapping(mut base), Value::Mapping(over)) => {
+            for (key, value) in over {
+                let mut child = path.to_vec();
+                child.push(key.as_str().map(String::from).unwrap_or_default());
+                let merged = match base.remove(&key) {
+                    Some(existing) => merge_value(&child, existing, value),
+                    None => merge_value(&child, Value::Null, value),
+                };
+                if let Some(merged) = merged {
+                    base.insert(key, merged);
+                }
+            }
+            Some(Value::Mapping(base))
+        }
+        (Value::Sequence(mut base), Value::Sequence(over)) => {
+            match rule {
+                ListMerge::Replace | ListMerge::ByKey => return Some(Value::Sequence(over)),
+                ListMerge::ByTarget => {
+                    for item in over {
+                        let target = mount_target(&item);
+                        match base.iter_mut().find(|b| mount_target(b) == target) {
+                            Some(existing) => *existing = item,
+                            None => base.push(item),
+                        }
+                    }
+                }
+                ListMerge::Append => {
+                    for item in over {
+                        if !base.contains(&item) {
+                            base.push(item);
+                        }
+                    }
+                }
+            }
+            Some(Value::Sequence(base))
+        }
+        (_, over) => Some(over),
+    }
+}
+
+/// `["KEY=value", "BARE"]` as `{KEY: value, BARE: null}`, so both spellings
+/// of `environment` and `labels` merge by key.
+fn key_value_mapping(value: Value) -> Value {
+    let Value::Sequence(items) = value else {
+        return value;
+    };
+    let mut mapping = Mapping::new();
+    for item in items {
+        let item = scalar_string(&item);
+        match item.split_once('=') {
+            Some((key, value)) => mapping.insert(key.into(), value.into()),
+            None => mapping.insert(item.into(), Value::Null),
+        };
+    }
+    Value::Mapping(mapping)
+}
+
+/// A scalar as written, without the quoting `display_value` adds.
+fn scalar_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => display_value(other),
+    }
+}
+
+/// Where a `volumes`, `devices`, `secrets` or `configs` entry ends up in the
+/// container: `target` in the long syntax, the part after the first `:` in
+/// the short one.
+fn mount_target(item: &Value) -> String {
+    match item {
+        Value::Mapping(m) => m
+            .get("target")
+            .or_else(|| m.get("source"))
+            .map(scalar_string)
+            .unwrap_or_default(),
+        other => {
+            let short = scalar_string(other);
+            let mut parts = short.split(':');
+            let first = parts.next().unwrap_or_default();
+            parts.next().unwrap_or(first).to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed(path: &str, old: &str, new: &str) -> ComposeChange {
+        ComposeChange::Changed {
+            path: path.to_string(),
+            old: old.to_string(),
+            new: new.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_semantic_diff_ignores_formatting() {
+        let old = "services:\n  web:\n    image: nginx:1.25\n    restart: always\n";
+        let new =
+            "# web server\nservices:\n  web:\n    restart: \"always\"\n    image: 'nginx:1.25'\n";
+        assert!(semantic_diff(old, new).unwrap().is_empty());
+        assert!(matches!(compose_matches(old, new, true), (true, None)));
+        assert!(matches!(compose_matches(old, new, false), (false, None)));
+    }
+
+    #[test]
+    fn test_semantic_diff_reports_changed_path() {
+        let old = "services:\n  web:\n    image: nginx:1.25\n";
+        let new = "services:\n  web:\n    image: nginx:1.27\n";
+        assert_eq!(
+            semantic_diff(old, new).unwrap(),
+            vec![changed("services.web.image", "nginx:1.25", "nginx:1.27")]
+        );
+    }
+
+    #[test]
+    fn test_semantic_diff_added_and_removed() {
+        let old = "services:\n  web:\n    image: nginx\n  old:\n    image: redis\n";
+        let new = "services:\n  web:\n    image: nginx\n    ports:\n      - \"80:80\"\n";
+        assert_eq!(
+            semantic_diff(old, new).unwrap(),
+            vec![
+                ComposeChange::Added {
+                    path: "services.web.ports".to_string(),
+                    value: "[\"80:80\"]".to_string(),
+                },
+                ComposeChange::Removed {
+                    path: "services.old".to_string(),
+                    value: "{\"image\":\"redis\"}".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_semantic_diff_list_items_by_index() {
+        let old = "command: [a, b]\n";
+        let new = "command: [a, c, d]\n";
+        assert_eq!(
+            semantic_diff(old, new).unwrap(),
+            vec![
+                changed("command[1]", "b", "c"),
+                ComposeChange::Added {
+                    path: "command[2]".to_string(),
+                    value: "d".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_semantic_diff_quotes_dotted_keys() {
+        let old = "labels:\n  traefik.enable: \"false\"\n";
+        let new = "labels:\n  traefik.enable: \"true\"\n";
+        assert_eq!(
+            semantic_diff(old, new).unwrap(),
+            vec![changed(
+                "labels[\"traefik.enable\"]",
+                "\"false\"",
+                "\"true\""
+            )]
+        );
+    }
+
+    #[test]
+    fn test_semantic_diff_applies_merge_keys() {
+        let old =
+            "x: &base\n  restart: always\nservices:\n  web:\n    <<: *base\n    image: nginx\n";
+        let new = "x: &base\n  restart: always\nservices:\n  web:\n    image: nginx\n    restart: always\n";
+        assert!(semantic_diff(old, new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_semantic_diff_distinguishes_types() {
+        let changes = semantic_diff("port: 80\n", "port: \"80\"\n").unwrap();
+        assert_eq!(changes, vec![changed("port", "80", "\"80\"")]);
+    }
+
+    #[test]
+    fn test_compose_matches_falls_back_to_text() {
+        let broken = "services: [\n";
+        assert!(matches!(
+            compose_matches(broken, "services: [\n\n", true),
+            (true, Some(_))
+        ));
+        assert!(matches!(
+            compose_matches(broken, "services: {}\n", true),
+            (false, Some(_))
+        ));
+    }
+
+    #[test]
+    fn test_image_ref_parse() {
+        let image = ImageRef::parse("registry:5000/org/app:1.2@sha256:abc");
+        assert_eq!(image.repository, "registry:5000/org/app");
+        assert_eq!(image.tag.as_deref(), Some("1.2"));
+        assert_eq!(image.digest.as_deref(), Some("sha256:abc"));
+        assert_eq!(image.to_string(), "registry:5000/org/app:1.2@sha256:abc");
+
+        let image = ImageRef::parse("registry:5000/app");
+        assert_eq!(image.repository, "registry:5000/app");
+        assert_eq!(image.version(), "latest");
+    }
+
+    #[test]
+    fn test_image_changes() {
+        let old = "services:
+  web:
+    image: nginx:1.25
+  db:
+    image: postgres:15
+  worker:
+    build: .
+  cache:
+    image: redis:7
+";
+        let new = "services:
+  web:
+    image: nginx:1.27
+  db:
+    image: ghcr.io/org/postgres:15
+  worker:
+    build: .
+  proxy:
+    image: traefik:v3
+";
+        assert_eq!(
+            image_changes(old, new).unwrap(),
+            vec![
+                ImageChange::Bumped {
+                    service: "web".to_string(),
+                    repository: "nginx".to_string(),
+                    old: "1.25".to_string(),
+                    new: "1.27".to_string(),
+                },
+                ImageChange::Replaced {
+                    service: "db".to_string(),
+                    old: "postgres:15".to_string(),
+                    new: "ghcr.io/org/postgres:15".to_string(),
+                },
+                ImageChange::ServiceAdded {
+                    service: "proxy".to_string(),
+                    image: Some("traefik:v3".to_string()),
+                },
+                ImageChange::ServiceRemoved {
+                    service: "cache".to_string(),
+                    image: Some("redis:7".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_image_changes_digest_only() {
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let old = format!("services:\n  web:\n    image: nginx:1.25@sha256:{}\n", a);
+        let new = format!("services:\n  web:\n    image: nginx:1.25@sha256:{}\n", b);
+        assert_eq!(
+            image_changes(&old, &new).unwrap(),
+            vec![ImageChange::Bumped {
+                service: "web".to_string(),
+                repository: "nginx".to_string(),
+                old: "1.25@sha256:aaaaaaaaaaaa".to_string(),
+                new: "1.25@sha256:bbbbbbbbbbbb".to_string(),
+            }]
+        );
+
+        // Pinning a tag shows the digest it was pinned to
+        let changes = image_changes("services:\n  web:\n    image: nginx:1.25\n", &new).unwrap();
+        assert!(matches!(
+            &changes[..],
+            [ImageChange::Bumped { old, new, .. }] if old == "1.25" && new == "1.25@sha256:bbbbbbbbbbbb"
+        ));
+    }
+
+    #[test]
+    fn test_image_changes_none_when_only_config_changes() {
+        let old = "services:\n  web:\n    image: nginx:1.25\n    restart: always\n";
+        let new = "services:\n  web:\n    image: nginx:1.25\n";
+        assert!(image_changes(old, new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_variable_refs() {
+        let compose = parse(
+            r#"
+services:
+  web:
+    image: "nginx:${TAG:-latest}"
+    command: ["--url", "$BASE_URL/api", "--price", "$$5"]
+    environment:
+      - DATABASE_URL
+      - LOG_LEVEL=${LOG_LEVEL:?set a log level}
+      - FIXED=1
+  worker:
+    image: app:${TAG}
+    environment:
+      API_KEY:
+      MODE: ${MODE:-${DEFAULT_MODE}}
+      EXTRA: ${EXTRA:+--extra}
+"#,
+        )
+        .unwrap();
+        let refs = variable_refs(&compose);
+        let refs: Vec<(&str, bool)> = refs
+            .iter()
+            .map(|(name, has_default)| (name.as_str(), *has_default))
+            .collect();
+        assert_eq!(
+            refs,
+            vec![
+                ("API_KEY", false),
+                ("BASE_URL", false),
+                ("DATABASE_URL", false),
+                ("DEFAULT_MODE", true),
+                ("EXTRA", true),
+                ("LOG_LEVEL", false),
+                ("MODE", true),
+                ("TAG", false),
+            ]
+        );
+        assert!(!uses_env_file(&compose));
+        assert!(uses_env_file(
+            &parse("services:\n  web:\n    env_file: stack.env\n").unwrap()
+        ));
+    }
+
+    fn env_vars(names: &[&str]) -> Vec<EnvVar> {
+        names
+            .iter()
+            .map(|name| EnvVar {
+                name: name.to_string(),
+                value: "x".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_variable_check() {
+        let compose = "services:\n  web:\n    image: app:${TAG:-latest}\n    environment:\n      - DATABASE_URL\n      - PORT=${PORT}\n";
+        let check = VariableCheck::new(compose, &env_vars(&["PORT", "OLD_KEY"])).unwrap();
+        assert_eq!(
+            check,
+            VariableCheck {
+                missing: vec!["DATABASE_URL".to_string()],
+                defaulted: vec!["TAG".to_string()],
+                unused: vec!["OLD_KEY".to_string()],
+                invalid: vec![],
+            }
+        );
+        assert!(!check.passed());
+    }
+
+    #[test]
+    fn test_variable_check_env_file_uses_every_var() {
+        let compose = "services:\n  web:\n    image: nginx\n    env_file: stack.env\n";
+        let check = VariableCheck::new(compose, &env_vars(&["OLD_KEY"])).unwrap();
+        assert!(check.passed());
+        assert!(check.unused.is_empty());
+    }
+
+    fn merged(files: &[&str]) -> Value {
+        let files: Vec<String> = files.iter().map(|f| f.to_string()).collect();
+        parse(&merge_files(&files).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_merge_files_single_file_unchanged() {
+        let compose = "# comment\nservices:\n  web:\n    image: nginx\n";
+        assert_eq!(merge_files(&[compose.to_string()]).unwrap(), compose);
+    }
+
+    #[test]
+    fn test_merge_files_follows_compose_rules() {
+        let base = r#"
+services:
+  web:
+    image: nginx:1.25
+    command: ["nginx", "-g", "daemon off;"]
+    ports: ["80:80"]
+    environment:
+      - LOG=info
+      - TZ=UTC
+    volumes:
+      - ./html:/usr/share/nginx/html
+      - logs:/var/log/nginx
+    labels:
+      team: web
+  cache:
+    image: redis
+"#;
+        let prod = r#"
+services:
+  web:
+    image: nginx:1.27
+    command: ["nginx"]
+    ports: ["443:443", "80:80"]
+    environment:
+      LOG: warn
+    volumes:
+      - /srv/html:/usr/share/nginx/html:ro
+    labels: !reset {}
+  cache: !reset null
+  worker:
+    image: app
+"#;
+        let expected = parse(
+            r#"
+services:
+  web:
+    image: nginx:1.27
+    command: ["nginx"]
+    ports: ["80:80", "443:443"]
+    environment:
+      LOG: warn
+      TZ: UTC
+    volumes:
+      - /srv/html:/usr/share/nginx/html:ro
+      - logs:/var/log/nginx
+  worker:
+    image: app
+"#,
+        )
+        .unwrap();
+        assert_eq!(merged(&[base, prod]), expected);
+    }
+
+    #[test]
+    fn test_merge_files_override_tag_replaces() {
+        let base = "services:\n  web:\n    ports: [\"80:80\"]\n";
+        let over = "services:\n  web:\n    ports: !override [\"8080:80\"]\n";
+        assert_eq!(
+            merged(&[base, over]),
+            parse("services:\n  web:\n    ports: [\"8080:80\"]\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_files_reports_invalid_file() {
+        let files = ["services: {}\n".to_string(), "services: [\n".to_string()];
+        let err = format!("{:#}", merge_files(&files).unwrap_err());
+        assert!(err.contains("#2"));
+    }
+
+    #[test]
+    fn test_local_file_refs() {
+        let compose = parse(
+            r#"
+services:
+  web:
+    image: nginx
+    env_file: [web.env, { path: ./optional.env, required: false }]
+    volumes:
+      - ./nginx.conf:/etc/nginx/nginx.conf:ro
+      - data:/var/lib/data
+      - /etc/localtime:/etc/localtime:ro
+      - ${CERTS}:/certs
+      - type: bind
+        source: ../shared/tls.pem
+        target: /tls.pem
+  db:
+    image: postgres
+    env_file: db.env
+configs:
+  app:
+    file: ./app.toml
+secrets:
+  token:
+    environment: TOKEN
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            local_file_refs(&compose),
+            vec![
+                "../shared/tls.pem",
+                "./app.toml",
+                "./nginx.conf",
+                "./optional.env",
+                "db.env",
+                "web.env",
+            ]
+        );
+    }
+}
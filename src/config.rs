@@ -1,7 +1,15 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+
+use crate::compose;
+use crate::dotenv;
+use crate::encryption::{self, Keys};
+use crate::reporter::Reporter;
+use crate::schema::EnvSchema;
+use crate::secrets;
+use crate::template;
 
 #[derive(Debug, Deserialize, Clone, Default, PartialEq)]
 #[serde(rename_all = "lowercase")]
@@ -11,10 +19,53 @@ pub enum DeployMode {
     Ssh,
 }
 
+/// How a stack's local env is combined with the env already on the remote.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnvStrategy {
+    /// The remote env becomes the local env.
+    #[default]
+    Replace,
+    /// Local values are set over the remote env; remote-only keys are kept.
+    Merge,
+    /// Local keys are only added when the remote doesn't set them yet.
+    PreserveRemote,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct StackEntry {
-    pub compose_file: String,
+    pub compose_file: Option<String>,
+    /// A compose file with `{{ name }}` placeholders, rendered with the
+    /// config's vars. It comes before `compose_file` and `compose_files`.
+    pub compose_template: Option<String>,
+    /// More compose files, merged in order after `compose_file` like
+    /// `docker compose -f` does.
+    #[serde(default)]
+    pub compose_files: Vec<String>,
     pub env_file: Option<String>,
+    /// More env files, merged in order after `env_file`. Later files
+    /// override earlier ones.
+    #[serde(default)]
+    pub env_files: Vec<String>,
+    /// Env vars set directly in the config, for values that aren't
+    /// secret. They override the env files.
+    #[serde(default, deserialize_with = "env_table")]
+    pub env: HashMap<String, String>,
+    /// Schema the env file is validated against: a TOML file, or an env
+    /// file such as `.env.example` whose keys are all required.
+    pub env_schema: Option<String>,
+    pub env_strategy: Option<EnvStrategy>,
+    /// Patterns (`*` matches anything) for env keys stack-sync leaves
+    /// alone on the remote.
+    #[serde(default)]
+    pub ignore_env: Vec<String>,
+    /// Files uploaded next to the compose file in SSH mode, on top of the
+    /// ones the compose file references.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Values for this stack that override the config's `[vars]`.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
     pub endpoint_id: Option<u64>,
     pub enabled: Option<bool>,
 }
@@ -22,12 +73,38 @@ pub struct StackEntry {
 #[derive(Debug)]
 pub struct Config {
     pub name: String,
-    pub compose_file: String,
-    pub env_file: Option<String>,
+    pub compose_files: Vec<String>,
+    /// Whether the first of `compose_files` is a template.
+    pub compose_template: bool,
+    pub env_files: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub common_env: HashMap<String, String>,
+    pub env_schema: Option<String>,
+    pub env_strategy: EnvStrategy,
+    pub ignore_env: Vec<String>,
+    pub files: Vec<String>,
     pub host: String,
     pub endpoint_id: u64,
     pub enabled: bool,
     pub base_dir: PathBuf,
+    pub vars: HashMap<String, String>,
+    pub secret_providers: HashMap<String, String>,
+    pub age_identity: Option<String>,
+    pub docker_validate: bool,
+    pub portainer_stamp: bool,
+}
+
+/// Files uploaded next to the compose file in SSH mode.
+#[derive(Debug, Default, PartialEq)]
+pub struct StackFiles {
+    /// Content of each file, by path relative to the compose file.
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// Paths the compose file references that exist locally but aren't
+    /// uploaded: directories, and paths outside the stack directory.
+    pub skipped: Vec<String>,
+    /// Env files whose content holds unresolved secrets, so it can't be
+    /// compared with the uploaded file.
+    pub unresolved: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -36,6 +113,57 @@ pub struct EnvVar {
     pub value: String,
 }
 
+/// Read an env table whose values may be written as TOML strings, numbers
+/// or booleans (`PUID = 1000`), keeping each as the string it is deployed as.
+fn env_table<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct EnvValue(String);
+
+    impl<'de> Deserialize<'de> for EnvValue {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct ValueVisitor;
+
+            impl serde::de::Visitor<'_> for ValueVisitor {
+                type Value = EnvValue;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("a string, number or boolean")
+                }
+
+                fn visit_str<E>(self, v: &str) -> Result<EnvValue, E> {
+                    Ok(EnvValue(v.to_string()))
+                }
+
+                fn visit_i64<E>(self, v: i64) -> Result<EnvValue, E> {
+                    Ok(EnvValue(v.to_string()))
+                }
+
+                fn visit_u64<E>(self, v: u64) -> Result<EnvValue, E> {
+                    Ok(EnvValue(v.to_string()))
+                }
+
+                fn visit_f64<E>(self, v: f64) -> Result<EnvValue, E> {
+                    Ok(EnvValue(format!("{:?}", v)))
+                }
+
+                fn visit_bool<E>(self, v: bool) -> Result<EnvValue, E> {
+                    Ok(EnvValue(v.to_string()))
+                }
+            }
+
+            deserializer.deserialize_any(ValueVisitor)
+        }
+    }
+
+    let table = HashMap::<String, EnvValue>::deserialize(deserializer)?;
+    Ok(table
+        .into_iter()
+        .map(|(name, value)| (name, value.0))
+        .collect())
+}
+
 fn default_endpoint_id() -> u64 {
     2
 }
@@ -50,6 +178,31 @@ pub struct PartialConfigFile {
     pub ssh_user: Option<String>,
     pub ssh_key: Option<String>,
     pub host_dir: Option<String>,
+    /// age identity file used to decrypt `.age` and SOPS env files.
+    pub age_identity: Option<String>,
+    /// age public keys that `env encrypt` and `env edit` encrypt to.
+    /// Defaults to the recipients of `age_identity`.
+    pub age_recipients: Option<Vec<String>>,
+    /// Also validate compose files with a local `docker compose config`
+    /// before syncing, when docker is installed.
+    pub docker_validate: Option<bool>,
+    /// Save the deployment stamp as a stack env var in Portainer mode.
+    /// Defaults to true.
+    pub portainer_stamp: Option<bool>,
+    /// Fail on unknown keys in any config file instead of ignoring them.
+    pub strict: Option<bool>,
+    /// Values for `${VAR}` references in env files. Inherited from parent
+    /// configs, with nearer configs taking priority per name.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// Commands that fetch `secret://<provider>/<path>` env values, by
+    /// provider name. Inherited like `vars`.
+    #[serde(default)]
+    pub secret_providers: HashMap<String, String>,
+    /// Env vars every stack gets, before its own env files. Inherited like
+    /// `vars`.
+    #[serde(default, deserialize_with = "env_table")]
+    pub common_env: HashMap<String, String>,
     #[serde(default)]
     pub stacks: HashMap<String, StackEntry>,
 }
@@ -97,14 +250,47 @@ impl PartialConfigFile {
             ResolvedGlobalConfig::Ssh(s) => (s.host.clone(), 0),
         };
 
+        let compose_files: Vec<String> = entry
+            .compose_template
+            .iter()
+            .chain(&entry.compose_file)
+            .chain(&entry.compose_files)
+            .cloned()
+            .collect();
+        if compose_files.is_empty() {
+            anyhow::bail!(
+                "Stack '{}' has no compose file. Set 'compose_file', 'compose_files' or 'compose_template'.",
+                stack_name
+            );
+        }
+        let mut vars = self.vars.clone();
+        vars.extend(entry.vars.clone());
+
         Ok(Config {
             name: stack_name.to_string(),
-            compose_file: entry.compose_file.clone(),
-            env_file: entry.env_file.clone(),
+            compose_files,
+            compose_template: entry.compose_template.is_some(),
+            env_files: entry
+                .env_file
+                .iter()
+                .chain(&entry.env_files)
+                .cloned()
+                .collect(),
+            env: entry.env.clone(),
+            common_env: self.common_env.clone(),
+            env_schema: entry.env_schema.clone(),
+            env_strategy: entry.env_strategy.unwrap_or_default(),
+            ignore_env: entry.ignore_env.clone(),
+            files: entry.files.clone(),
             host,
             endpoint_id,
             enabled: entry.enabled.unwrap_or(true),
             base_dir: base_dir.to_path_buf(),
+            vars,
+            secret_providers: self.secret_providers.clone(),
+            age_identity: self.age_identity.clone(),
+            docker_validate: self.docker_validate.unwrap_or(false),
+            portainer_stamp: self.portainer_stamp.unwrap_or(true),
         })
     }
 
@@ -122,6 +308,16 @@ struct ConfigChainResult {
     ssh_user: Option<String>,
     ssh_key: Option<String>,
     host_dir: Option<String>,
+    age_identity: Option<String>,
+    age_recipients: Option<Vec<String>>,
+    docker_validate: Option<bool>,
+    portainer_stamp: Option<bool>,
+    strict: Option<bool>,
+    vars: HashMap<String, String>,
+    secret_providers: HashMap<String, String>,
+    common_env: HashMap<String, String>,
+    /// Every config file read, nearest first.
+    files: Vec<PathBuf>,
     local_config: Option<PartialConfigFile>,
     local_config_path: Option<PathBuf>,
 }
@@ -129,16 +325,15 @@ struct ConfigChainResult {
 /// Walk up directories from start_dir to $HOME, collecting config values.
 /// If explicit_local_file is provided, it is used as the local config instead of
 /// the first .stack-sync.toml found during the walk.
+/// Connection fields stop being inherited once the nearer configs set all of
+/// them, so a parent can't switch a complete config to another host or mode;
+/// inherited settings and tables like `[vars]` come from every config up to $HOME.
 /// Returns partial results - validation happens in resolve_config_chain().
 fn walk_config_chain(
     start_dir: &Path,
     explicit_local_file: Option<&Path>,
+    home_dir: Option<PathBuf>,
 ) -> Result<ConfigChainResult> {
-    let home_dir = std::env::var("HOME")
-        .ok()
-        .map(PathBuf::from)
-        .and_then(|p| p.canonicalize().ok());
-
     // Start with env var for API key (highest priority)
     let mut api_key = std::env::var("PORTAINER_API_KEY").ok();
     let mut mode: Option<DeployMode> = None;
@@ -147,12 +342,20 @@ fn walk_config_chain(
     let mut ssh_user: Option<String> = None;
     let mut ssh_key: Option<String> = None;
     let mut host_dir: Option<String> = None;
+    let mut age_identity: Option<String> = None;
+    let mut age_recipients: Option<Vec<String>> = None;
+    let mut docker_validate: Option<bool> = None;
+    let mut portainer_stamp: Option<bool> = None;
+    let mut strict: Option<bool> = None;
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut vars: HashMap<String, String> = HashMap::new();
+    let mut secret_providers: HashMap<String, String> = HashMap::new();
+    let mut common_env: HashMap<String, String> = HashMap::new();
     let mut local_config: Option<PartialConfigFile> = None;
     let mut local_config_path: Option<PathBuf> = None;
 
     // If an explicit local file was provided, load it before the walk so the walk
     // won't replace it with a .stack-sync.toml found in the same directory.
-    let mut skip_walk = false;
     if let Some(explicit) = explicit_local_file {
         let content = std::fs::read_to_string(explicit).context(format!(
             "Failed to read config file: {}",
@@ -185,28 +388,37 @@ fn walk_config_chain(
         if host_dir.is_none() {
             host_dir = partial.host_dir.clone();
         }
+        if age_identity.is_none() {
+            age_identity = partial.age_identity.clone();
+        }
+        if age_recipients.is_none() {
+            age_recipients = partial.age_recipients.clone();
+        }
+        if docker_validate.is_none() {
+            docker_validate = partial.docker_validate;
+        }
+        if portainer_stamp.is_none() {
+            portainer_stamp = partial.portainer_stamp;
+        }
+        if strict.is_none() {
+            strict = partial.strict;
+        }
+        files.push(explicit.to_path_buf());
+        inherit_entries(&mut vars, &partial.vars);
+        inherit_entries(&mut secret_providers, &partial.secret_providers);
+        inherit_entries(&mut common_env, &partial.common_env);
 
         local_config = Some(partial);
         local_config_path = Some(explicit.to_path_buf());
-
-        // Skip directory walk if we already have everything we need
-        let resolved_mode = mode.clone().unwrap_or_default();
-        skip_walk = match resolved_mode {
-            DeployMode::Portainer => api_key.is_some() && host.is_some() && endpoint_id.is_some(),
-            DeployMode::Ssh => host.is_some() && host_dir.is_some(),
-        };
     }
+    let explicit_canonical = explicit_local_file.and_then(|p| p.canonicalize().ok());
 
     // Canonicalize starting directory
     let start_canonical = start_dir
         .canonicalize()
         .context(format!("Directory not found: {}", start_dir.display()))?;
 
-    let mut current = if skip_walk {
-        None
-    } else {
-        Some(start_canonical.as_path())
-    };
+    let mut current = Some(start_canonical.as_path());
 
     while let Some(dir) = current {
         // Check if we've escaped $HOME via symlinks
@@ -219,7 +431,7 @@ fn walk_config_chain(
         }
 
         let config_path = dir.join(".stack-sync.toml");
-        if config_path.exists() {
+        if config_path.exists() && config_path.canonicalize().ok() != explicit_canonical {
             let content = std::fs::read_to_string(&config_path).context(format!(
                 "Failed to read config file: {}",
                 config_path.display()
@@ -232,33 +444,11 @@ fn walk_config_chain(
             // First config found becomes the local config (has stacks)
             if local_config.is_none() {
                 local_config = Some(partial.clone());
-                local_config_path = Some(config_path);
+                local_config_path = Some(config_path.clone());
             }
 
-            // Inherit values if not already set (earlier configs have priority)
-            if mode.is_none() {
-                mode = partial.mode;
-            }
-            if api_key.is_none() {
-                api_key = partial.portainer_api_key;
-            }
-            if host.is_none() {
-                host = partial.host;
-            }
-            if endpoint_id.is_none() {
-                endpoint_id = partial.endpoint_id;
-            }
-            if ssh_user.is_none() {
-                ssh_user = partial.ssh_user;
-            }
-            if ssh_key.is_none() {
-                ssh_key = partial.ssh_key;
-            }
-            if host_dir.is_none() {
-                host_dir = partial.host_dir;
-            }
-
-            // Early termination - mode-aware
+            // Inherit values if not already set (earlier configs have priority).
+            // Connection fields only while the nearer configs are incomplete.
             let resolved_mode = mode.clone().unwrap_or_default();
             let have_all = match resolved_mode {
                 DeployMode::Portainer => {
@@ -266,9 +456,48 @@ fn walk_config_chain(
                 }
                 DeployMode::Ssh => host.is_some() && host_dir.is_some(),
             };
-            if have_all {
-                break;
+            if !have_all {
+                if mode.is_none() {
+                    mode = partial.mode;
+                }
+                if api_key.is_none() {
+                    api_key = partial.portainer_api_key;
+                }
+                if host.is_none() {
+                    host = partial.host;
+                }
+                if endpoint_id.is_none() {
+                    endpoint_id = partial.endpoint_id;
+                }
+                if ssh_user.is_none() {
+                    ssh_user = partial.ssh_user;
+                }
+                if ssh_key.is_none() {
+                    ssh_key = partial.ssh_key;
+                }
+                if host_dir.is_none() {
+                    host_dir = partial.host_dir;
+                }
+            }
+            if age_identity.is_none() {
+                age_identity = partial.age_identity;
+            }
+            if age_recipients.is_none() {
+                age_recipients = partial.age_recipients;
+            }
+            if docker_validate.is_none() {
+                docker_validate = partial.docker_validate;
             }
+            if portainer_stamp.is_none() {
+                portainer_stamp = partial.portainer_stamp;
+            }
+            if strict.is_none() {
+                strict = partial.strict;
+            }
+            files.push(config_path);
+            inherit_entries(&mut vars, &partial.vars);
+            inherit_entries(&mut secret_providers, &partial.secret_providers);
+            inherit_entries(&mut common_env, &partial.common_env);
         }
 
         // Stop at $HOME
@@ -289,16 +518,22 @@ fn walk_config_chain(
         ssh_user,
         ssh_key,
         host_dir,
+        age_identity,
+        age_recipients,
+        docker_validate,
+        portainer_stamp,
+        strict,
+        vars,
+        secret_providers,
+        common_env,
+        files,
         local_config,
         local_config_path,
     })
 }
 
-/// Resolve the config chain and validate required fields.
-/// Returns (ResolvedGlobalConfig, PartialConfigFile, config_path).
-pub fn resolve_config_chain(
-    start_path: &Path,
-) -> Result<(ResolvedGlobalConfig, PartialConfigFile, PathBuf)> {
+/// Walk the config chain from a config file or directory.
+fn walk_from(start_path: &Path) -> Result<ConfigChainResult> {
     // If path is a file, use its parent as the walk start and pass it as the explicit local file.
     // Otherwise treat the path as a directory.
     let (start_dir, explicit_local_file): (&Path, Option<&Path>) = if start_path.is_file() {
@@ -312,12 +547,154 @@ pub fn resolve_config_chain(
         // Path doesn't exist yet, try to use it as a directory
         (start_path, None)
     };
+    let home_dir = std::env::var("HOME")
+        .ok()
+        .map(PathBuf::from)
+        .and_then(|p| p.canonicalize().ok());
+    walk_config_chain(start_dir, explicit_local_file, home_dir)
+}
+
+/// The config files the chain reads, nearest first: the local config, then
+/// the parents it inherits from.
+pub fn config_chain_files(start_path: &Path) -> Result<Vec<PathBuf>> {
+    Ok(walk_from(start_path)?.files)
+}
+
+/// Read a config file as untyped TOML, to look at the keys it sets.
+pub fn read_config_value(path: &Path) -> Result<toml::Table> {
+    let content = std::fs::read_to_string(path)
+        .context(format!("Failed to read config file: {}", path.display()))?;
+    toml::from_str(&content).context(format!("Failed to parse config file: {}", path.display()))
+}
+
+/// Keys of a config file, as `PartialConfigFile` reads them.
+pub fn config_keys() -> &'static [&'static str] {
+    serde_fields::<PartialConfigFile>()
+}
+
+/// Keys of a `[stacks.<name>]` table, as `StackEntry` reads them.
+pub fn stack_keys() -> &'static [&'static str] {
+    serde_fields::<StackEntry>()
+}
+
+/// The field names a derived `Deserialize` asks its deserializer for, so
+/// the key lists can't drift from the structs.
+fn serde_fields<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    use serde::de::{Error, Visitor, value};
+
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, value::Error> {
+            Err(value::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, value::Error> {
+            *self.0 = fields;
+            Err(value::Error::custom("only reading field names"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
 
-    let result = walk_config_chain(start_dir, explicit_local_file)?;
+    let mut fields: &'static [&'static str] = &[];
+    T::deserialize(FieldNames(&mut fields)).ok();
+    fields
+}
+
+/// Keys of a config file that stack-sync doesn't know, as `key` or
+/// `stacks.<name>.key`. Tables of names (`[vars]`, `[stacks.<name>.env]`,
+/// ...) can hold anything.
+pub fn unknown_keys(config: &toml::Table) -> Vec<String> {
+    let mut unknown: Vec<String> = config
+        .keys()
+        .filter(|key| !config_keys().contains(&key.as_str()))
+        .cloned()
+        .collect();
+    let stacks = config.get("stacks").and_then(|s| s.as_table());
+    for (name, stack) in stacks.into_iter().flatten() {
+        let keys = stack.as_table().into_iter().flat_map(|t| t.keys());
+        for key in keys.filter(|key| !stack_keys().contains(&key.as_str())) {
+            unknown.push(format!("stacks.{}.{}", name, key));
+        }
+    }
+    unknown
+}
+
+/// Add table entries not already set by a nearer config.
+fn inherit_entries(entries: &mut HashMap<String, String>, parent: &HashMap<String, String>) {
+    for (name, value) in parent {
+        entries.entry(name.clone()).or_insert_with(|| value.clone());
+    }
+}
+
+/// Resolve the config chain and validate required fields.
+/// Returns (ResolvedGlobalConfig, PartialConfigFile, config_path).
+pub fn resolve_config_chain(
+    start_path: &Path,
+) -> Result<(ResolvedGlobalConfig, PartialConfigFile, PathBuf)> {
+    let result = walk_from(start_path)?;
+    if result.strict == Some(true) {
+        for file in &result.files {
+            let unknown = unknown_keys(&read_config_value(file)?);
+            if !unknown.is_empty() {
+                anyhow::bail!(
+                    "{} has unknown keys: {}. Fix them, or run 'stack-sync config check' for details.",
+                    file.display(),
+                    unknown.join(", ")
+                );
+            }
+        }
+    }
+    resolve_chain_result(result)
+}
 
-    let local_config = result
+/// Resolve the config chain like `resolve_config_chain`, but without
+/// failing on unknown keys in strict mode, for `config check` to report
+/// them instead.
+pub fn resolve_config_chain_lenient(
+    start_path: &Path,
+) -> Result<(ResolvedGlobalConfig, PartialConfigFile, PathBuf)> {
+    resolve_chain_result(walk_from(start_path)?)
+}
+
+/// Only the age settings of the config chain, for the `env` helpers. Connection
+/// settings aren't checked and no config file is required, so encryption works
+/// offline without a Portainer or SSH host.
+pub fn age_config(start_path: &Path) -> Result<PartialConfigFile> {
+    let result = walk_from(start_path)?;
+    Ok(PartialConfigFile {
+        age_identity: result.age_identity,
+        age_recipients: result.age_recipients,
+        ..Default::default()
+    })
+}
+
+fn resolve_chain_result(
+    result: ConfigChainResult,
+) -> Result<(ResolvedGlobalConfig, PartialConfigFile, PathBuf)> {
+    let mut local_config = result
         .local_config
         .context("No config file found. Create a .stack-sync.toml file with stack definitions.")?;
+    local_config.vars = result.vars;
+    local_config.secret_providers = result.secret_providers;
+    local_config.common_env = result.common_env;
+    local_config.age_identity = result.age_identity;
+    local_config.age_recipients = result.age_recipients;
+    local_config.docker_validate = result.docker_validate;
+    local_config.portainer_stamp = result.portainer_stamp;
+    local_config.strict = result.strict;
 
     let local_config_path = result
         .local_config_path
@@ -361,42 +738,380 @@ pub fn resolve_config_chain(
 }
 
 impl Config {
-    pub fn compose_path(&self) -> PathBuf {
-        self.base_dir.join(&self.compose_file)
+    pub fn compose_paths(&self) -> Vec<PathBuf> {
+        self.compose_files
+            .iter()
+            .map(|f| self.base_dir.join(f))
+            .collect()
+    }
+
+    /// The stack's compose files, for display.
+    pub fn compose_source(&self) -> String {
+        self.compose_paths()
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The content of each compose file, in order, with the template
+    /// rendered.
+    pub fn read_compose_files(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        for (i, path) in self.compose_paths().iter().enumerate() {
+            let content = std::fs::read_to_string(path)
+                .context(format!("Failed to read compose file: {}", path.display()))?;
+            files.push(if i == 0 && self.compose_template {
+                template::render(&content, &self.template_vars()).context(format!(
+                    "Failed to render compose template: {}",
+                    path.display()
+                ))?
+            } else {
+                content
+            });
+        }
+        Ok(files)
     }
 
-    pub fn env_path(&self) -> Option<PathBuf> {
-        self.env_file.as_ref().map(|f| self.base_dir.join(f))
+    /// Values for compose template placeholders: the stack's name and host,
+    /// overridden by `[vars]` and the stack's own vars.
+    pub fn template_vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::from([
+            ("stack".to_string(), self.name.clone()),
+            ("host".to_string(), self.host.clone()),
+        ]);
+        vars.extend(self.vars.clone());
+        vars
+    }
+
+    /// The compose file as deployed: the stack's compose files merged in
+    /// order, or the file itself when there is only one.
+    pub fn compose_content(&self) -> Result<String> {
+        self.merge_compose(&self.read_compose_files()?)
+    }
+
+    /// Merge the stack's compose files once they have been read (and
+    /// possibly pinned).
+    pub fn merge_compose(&self, files: &[String]) -> Result<String> {
+        compose::merge_files(files).context(format!(
+            "Failed to merge compose files: {}",
+            self.compose_source()
+        ))
+    }
+
+    /// Files uploaded next to the compose file in SSH mode, by path relative
+    /// to it: the local files the compose file references, plus `files`.
+    /// References that aren't regular files here (data directories, paths
+    /// outside the stack directory) are left alone on the host; those that
+    /// exist locally are returned as skipped for the caller to report. An
+    /// env file of the stack is uploaded with its own vars resolved, not as
+    /// written.
+    pub fn stack_files(&self, compose_content: &str) -> Result<StackFiles> {
+        let compose_paths = self.compose_paths();
+        let compose_dir = compose_paths[0].parent().unwrap_or(&self.base_dir);
+        let mut referenced = Vec::new();
+        let mut skipped = Vec::new();
+        for reference in compose::local_file_refs(&compose::parse(compose_content)?) {
+            match stack_relative_path(&reference) {
+                Some(relative) if compose_dir.join(&relative).is_file() => {
+                    referenced.push(relative)
+                }
+                _ if Path::new(&reference).is_relative()
+                    && compose_dir.join(&reference).exists() =>
+                {
+                    skipped.push(reference)
+                }
+                _ => {}
+            }
+        }
+        let mut explicit = Vec::new();
+        for file in &self.files {
+            explicit.push(stack_relative_path(file).context(format!(
+                "File '{}' of stack '{}' is outside the stack directory",
+                file, self.name
+            ))?);
+        }
+
+        let relatives: Vec<String> = referenced.into_iter().chain(explicit).collect();
+        let env_paths = self.env_paths();
+        let env_layers = if relatives
+            .iter()
+            .any(|relative| env_paths.contains(&compose_dir.join(relative)))
+        {
+            self.env_layers()?
+        } else {
+            vec![]
+        };
+        let mut stack_files = StackFiles {
+            skipped,
+            ..Default::default()
+        };
+        for relative in relatives {
+            // stack-sync writes `.env` itself
+            if relative == ".env" || stack_files.files.contains_key(&relative) {
+                continue;
+            }
+            let path = compose_dir.join(&relative);
+            let content = match env_layers.iter().find(|(layer, _)| *layer == path) {
+                Some((_, vars)) => {
+                    if vars.iter().any(|v| secrets::is_unresolved(&v.value)) {
+                        stack_files.unresolved.push(relative.clone());
+                    }
+                    vars.iter()
+                        .map(|v| dotenv::format_var(v) + "\n")
+                        .collect::<String>()
+                        .into_bytes()
+                }
+                None => std::fs::read(&path)
+                    .context(format!("Failed to read file: {}", path.display()))?,
+            };
+            stack_files.files.insert(relative, content);
+        }
+        Ok(stack_files)
+    }
+
+    pub fn env_paths(&self) -> Vec<PathBuf> {
+        self.env_files
+            .iter()
+            .map(|f| self.base_dir.join(f))
+            .collect()
+    }
+
+    /// Whether the stack is deployed with any env vars.
+    pub fn has_env(&self) -> bool {
+        !self.env_files.is_empty() || !self.env.is_empty() || !self.common_env.is_empty()
+    }
+
+    /// Where the stack's env comes from, for display: its env files, or
+    /// the config when it only has inline vars.
+    pub fn env_source(&self) -> Option<String> {
+        if !self.has_env() {
+            return None;
+        }
+        let paths = self.env_paths();
+        if paths.is_empty() {
+            return Some("(config)".to_string());
+        }
+        Some(
+            paths
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    pub fn env_schema(&self) -> Result<Option<EnvSchema>> {
+        self.env_schema
+            .as_ref()
+            .map(|f| EnvSchema::load(&self.base_dir.join(f)))
+            .transpose()
+    }
+
+    /// The stack's env vars with references resolved, as they are deployed:
+    /// `common_env`, then each env file in order, then the stack's `env`
+    /// table, with later values overriding earlier ones. Env files can
+    /// reference keys from earlier layers. Encrypted env files are
+    /// decrypted in memory.
+    pub fn env_vars(&self) -> Result<Vec<EnvVar>> {
+        let merged = self.merged_env()?;
+        let unresolved: Vec<&str> = merged
+            .iter()
+            .filter(|v| secrets::is_unresolved(&v.value))
+            .map(|v| v.name.as_str())
+            .collect();
+        if !unresolved.is_empty() {
+            Reporter::unresolved_secrets(&self.name, &unresolved, secrets::mode());
+        }
+        Ok(merged)
+    }
+
+    /// The env vars like [`Config::env_vars`], without noting unresolved
+    /// secrets.
+    pub fn merged_env(&self) -> Result<Vec<EnvVar>> {
+        let mut merged = table_vars(&self.common_env);
+        for (_, vars) in self.env_layers()? {
+            merge_env(&mut merged, vars);
+        }
+        merge_env(&mut merged, table_vars(&self.env));
+        Ok(merged)
+    }
+
+    /// Each env file with only its own vars, resolved against
+    /// `common_env` and the files before it.
+    fn env_layers(&self) -> Result<Vec<(PathBuf, Vec<EnvVar>)>> {
+        let keys = Keys {
+            identity: self.age_identity.as_deref(),
+            recipients: None,
+        };
+        let mut merged = table_vars(&self.common_env);
+        let mut layers = Vec::new();
+        for path in self.env_paths() {
+            let content = encryption::read_env_file(&path, &keys)?;
+            let vars = resolve_env_str(&content, &merged, &self.vars, &self.secret_providers)
+                .context(format!("Failed to parse env file: {}", path.display()))?;
+            merge_env(&mut merged, vars.clone());
+            layers.push((path, vars));
+        }
+        Ok(layers)
+    }
+
+    /// The env to deploy, combining the local env with the remote one
+    /// according to `env_strategy`. Keys matching `ignore_env` keep their
+    /// remote value, and their local value is never deployed. Local keys
+    /// come first, in local order, followed by the remote keys that are
+    /// kept. Unresolved secrets also keep their remote value, so read-only
+    /// commands don't report them as changed.
+    pub fn deployed_env(&self, remote: &[EnvVar], local: &[EnvVar]) -> Vec<EnvVar> {
+        let mut env: Vec<EnvVar> = local
+            .iter()
+            .filter(|v| !self.ignores_env(&v.name))
+            .map(|var| match remote.iter().find(|r| r.name == var.name) {
+                Some(existing)
+                    if self.env_strategy == EnvStrategy::PreserveRemote
+                        || secrets::is_unresolved(&var.value) =>
+                {
+                    existing.clone()
+                }
+                _ => var.clone(),
+            })
+            .collect();
+        let kept = remote.iter().filter(|r| {
+            !env.iter().any(|v| v.name == r.name)
+                && (self.env_strategy != EnvStrategy::Replace || self.ignores_env(&r.name))
+        });
+        env.extend(kept.cloned().collect::<Vec<_>>());
+        env
+    }
+
+    /// Content for the remote `.env` in SSH mode: the deployed vars, quoted
+    /// so docker compose reads them back without expanding anything again.
+    /// The remote content is only read when the strategy keeps remote keys,
+    /// or when unresolved secrets need their remote value back.
+    pub fn deployed_env_content(
+        &self,
+        remote: Option<&str>,
+        local: &[EnvVar],
+    ) -> Result<Option<String>> {
+        let keeps_remote = self.env_strategy != EnvStrategy::Replace
+            || !self.ignore_env.is_empty()
+            || local.iter().any(|v| secrets::is_unresolved(&v.value));
+        let remote = if keeps_remote {
+            parse_env_str(remote.unwrap_or_default()).context("Failed to parse remote env file")?
+        } else {
+            vec![]
+        };
+        let env = self.deployed_env(&remote, local);
+        if env.is_empty() && !self.has_env() {
+            return Ok(None);
+        }
+        Ok(Some(
+            env.iter().map(|v| dotenv::format_var(v) + "\n").collect(),
+        ))
+    }
+
+    fn ignores_env(&self, name: &str) -> bool {
+        self.ignore_env
+            .iter()
+            .any(|pattern| wildcard_match(pattern, name))
     }
 }
 
-pub fn parse_env_file(path: &Path) -> Result<Vec<EnvVar>> {
-    let content = std::fs::read_to_string(path)
-        .context(format!("Failed to read env file: {}", path.display()))?;
-    Ok(parse_env_str(&content))
+/// Parse env file content, resolving `${VAR}` references against earlier
+/// keys (in this file, then in `earlier`), then the process environment,
+/// then the config's `[vars]`, and fetching `secret://` and `cmd://` values
+/// with the configured providers.
+pub fn resolve_env_str(
+    content: &str,
+    earlier: &[EnvVar],
+    vars: &HashMap<String, String>,
+    secret_providers: &HashMap<String, String>,
+) -> Result<Vec<EnvVar>> {
+    dotenv::parse_interpolated(
+        content,
+        &|name| {
+            earlier
+                .iter()
+                .find(|v| v.name == name)
+                .map(|v| v.value.clone())
+                .or_else(|| std::env::var(name).ok())
+                .or_else(|| vars.get(name).cloned())
+        },
+        &|value| secrets::resolve(value, secret_providers),
+    )
 }
 
-pub fn parse_env_str(content: &str) -> Vec<EnvVar> {
-    content
-        .lines()
-        .filter(|line| {
-            let trimmed = line.trim();
-            !trimmed.is_empty() && !trimmed.starts_with('#')
-        })
-        .filter_map(|line| {
-            let (key, value) = line.split_once('=')?;
-            Some(EnvVar {
-                name: key.trim().to_string(),
-                value: value.trim().to_string(),
-            })
+/// A relative path with `.` and `..` resolved, or `None` when it is absolute
+/// or leaves the directory it is relative to.
+fn stack_relative_path(path: &str) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Match `name` against a pattern where `*` matches any run of characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Env vars from a config table, sorted by name. Values are taken literally.
+fn table_vars(table: &HashMap<String, String>) -> Vec<EnvVar> {
+    let mut vars: Vec<EnvVar> = table
+        .iter()
+        .map(|(name, value)| EnvVar {
+            name: name.clone(),
+            value: value.clone(),
         })
-        .collect()
+        .collect();
+    vars.sort_by(|a, b| a.name.cmp(&b.name));
+    vars
+}
+
+/// Layer `vars` over `merged`, replacing values in place so keys keep the
+/// position they were first defined at.
+fn merge_env(merged: &mut Vec<EnvVar>, vars: Vec<EnvVar>) {
+    for var in vars {
+        match merged.iter_mut().find(|v| v.name == var.name) {
+            Some(existing) => existing.value = var.value,
+            None => merged.push(var),
+        }
+    }
+}
+
+/// Parse env file content with docker compose's dotenv rules, so Portainer
+/// receives the same values docker compose would read from the file.
+pub fn parse_env_str(content: &str) -> Result<Vec<EnvVar>> {
+    dotenv::parse(content)
 }
 
 pub fn write_env_file(path: &Path, vars: &[EnvVar]) -> Result<()> {
     let content: String = vars
         .iter()
-        .map(|v| format!("{}={}", v.name, v.value))
+        .map(dotenv::format_var)
         .collect::<Vec<_>>()
         .join("\n");
     std::fs::write(path, content).context(format!("Failed to write env file: {}", path.display()))
@@ -430,8 +1145,17 @@ pub fn append_stack_to_config(
     ))?;
 
     let entry = StackEntry {
-        compose_file: compose_file.to_string(),
+        compose_file: Some(compose_file.to_string()),
+        compose_template: None,
+        compose_files: vec![],
         env_file: env_file.map(String::from),
+        env_files: vec![],
+        env: HashMap::new(),
+        env_schema: None,
+        env_strategy: None,
+        ignore_env: vec![],
+        files: vec![],
+        vars: HashMap::new(),
         endpoint_id: None,
         enabled: None,
     };
@@ -490,6 +1214,25 @@ fn serialize_config(config: &PartialConfigFile) -> Result<String> {
     if let Some(ref dir) = config.host_dir {
         lines.push(format!("host_dir = {:?}", dir));
     }
+    if let Some(ref identity) = config.age_identity {
+        lines.push(format!("age_identity = {:?}", identity));
+    }
+    if let Some(ref recipients) = config.age_recipients {
+        lines.push(format!("age_recipients = {:?}", recipients));
+    }
+    if let Some(docker_validate) = config.docker_validate {
+        lines.push(format!("docker_validate = {}", docker_validate));
+    }
+    if let Some(portainer_stamp) = config.portainer_stamp {
+        lines.push(format!("portainer_stamp = {}", portainer_stamp));
+    }
+    if let Some(strict) = config.strict {
+        lines.push(format!("strict = {}", strict));
+    }
+
+    push_table(&mut lines, "vars", &config.vars);
+    push_table(&mut lines, "secret_providers", &config.secret_providers);
+    push_table(&mut lines, "common_env", &config.common_env);
 
     // Sort stack names for deterministic output
     let mut stack_names: Vec<_> = config.stacks.keys().collect();
@@ -499,21 +1242,78 @@ fn serialize_config(config: &PartialConfigFile) -> Result<String> {
         let entry = &config.stacks[name];
         lines.push(String::new());
         lines.push(format!("[stacks.{}]", name));
-        lines.push(format!("compose_file = {:?}", entry.compose_file));
+        if let Some(ref template) = entry.compose_template {
+            lines.push(format!("compose_template = {:?}", template));
+        }
+        if let Some(ref compose) = entry.compose_file {
+            lines.push(format!("compose_file = {:?}", compose));
+        }
+        if !entry.compose_files.is_empty() {
+            lines.push(format!("compose_files = {:?}", entry.compose_files));
+        }
         if let Some(ref env) = entry.env_file {
             lines.push(format!("env_file = {:?}", env));
         }
+        if !entry.env_files.is_empty() {
+            lines.push(format!("env_files = {:?}", entry.env_files));
+        }
+        if let Some(ref schema) = entry.env_schema {
+            lines.push(format!("env_schema = {:?}", schema));
+        }
+        if let Some(strategy) = entry.env_strategy {
+            let strategy = match strategy {
+                EnvStrategy::Replace => "replace",
+                EnvStrategy::Merge => "merge",
+                EnvStrategy::PreserveRemote => "preserve-remote",
+            };
+            lines.push(format!("env_strategy = {:?}", strategy));
+        }
+        if !entry.ignore_env.is_empty() {
+            lines.push(format!("ignore_env = {:?}", entry.ignore_env));
+        }
+        if !entry.files.is_empty() {
+            lines.push(format!("files = {:?}", entry.files));
+        }
         if let Some(endpoint_id) = entry.endpoint_id {
             lines.push(format!("endpoint_id = {}", endpoint_id));
         }
         if entry.enabled == Some(false) {
             lines.push("enabled = false".to_string());
         }
+        push_table(&mut lines, &format!("stacks.{}.env", name), &entry.env);
+        push_table(&mut lines, &format!("stacks.{}.vars", name), &entry.vars);
     }
 
     Ok(lines.join("\n") + "\n")
 }
 
+/// Append a `[name]` table of strings, sorted by key, unless it is empty.
+fn push_table(lines: &mut Vec<String>, name: &str, entries: &HashMap<String, String>) {
+    if entries.is_empty() {
+        return;
+    }
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort();
+    lines.push(String::new());
+    lines.push(format!("[{}]", name));
+    for (key, value) in entries {
+        lines.push(format!("{} = {:?}", toml_key(key), value));
+    }
+}
+
+/// A TOML key, quoted unless it is a valid bare key.
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
 /// Create a parent config file with Portainer credentials
 pub fn write_parent_config(
     path: &Path,
@@ -609,7 +1409,7 @@ mod tests {
     #[test]
     fn test_parse_env_str_basic() {
         let input = "FOO=bar\nBAZ=qux";
-        let vars = parse_env_str(input);
+        let vars = parse_env_str(input).unwrap();
         assert_eq!(vars.len(), 2);
         assert_eq!(vars[0].name, "FOO");
         assert_eq!(vars[0].value, "bar");
@@ -620,14 +1420,14 @@ mod tests {
     #[test]
     fn test_parse_env_str_skips_comments_and_blanks() {
         let input = "# comment\nFOO=bar\n\n  # another\nBAZ=qux\n";
-        let vars = parse_env_str(input);
+        let vars = parse_env_str(input).unwrap();
         assert_eq!(vars.len(), 2);
     }
 
     #[test]
     fn test_parse_env_str_handles_values_with_equals() {
         let input = "URL=https://example.com?foo=bar";
-        let vars = parse_env_str(input);
+        let vars = parse_env_str(input).unwrap();
         assert_eq!(vars.len(), 1);
         assert_eq!(vars[0].name, "URL");
         assert_eq!(vars[0].value, "https://example.com?foo=bar");
@@ -635,7 +1435,7 @@ mod tests {
 
     #[test]
     fn test_parse_env_str_empty() {
-        let vars = parse_env_str("");
+        let vars = parse_env_str("").unwrap();
         assert!(vars.is_empty());
     }
 
@@ -654,10 +1454,14 @@ mod tests {
                 name: "BAZ".to_string(),
                 value: "qux=123".to_string(),
             },
+            EnvVar {
+                name: "GREETING".to_string(),
+                value: "it's a \"test\" # not a comment".to_string(),
+            },
         ];
         write_env_file(&path, &vars).unwrap();
-        let parsed = parse_env_file(&path).unwrap();
-        assert_eq!(parsed.len(), 2);
+        let parsed = parse_env_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(parsed, vars);
         assert_eq!(parsed[0].name, "FOO");
         assert_eq!(parsed[0].value, "bar");
         assert_eq!(parsed[1].name, "BAZ");
@@ -676,7 +1480,8 @@ compose_file = "compose.yaml"
         let config: PartialConfigFile = toml::from_str(toml_str).unwrap();
         let global = portainer_global();
         let resolved = config.resolve("my-stack", &global, Path::new(".")).unwrap();
-        assert_eq!(resolved.env_file, None);
+        assert!(resolved.env_files.is_empty());
+        assert!(!resolved.has_env());
     }
 
     #[test]
@@ -831,4 +1636,508 @@ compose_file = "compose.yaml"
         assert_eq!(resolved.endpoint_id, 0);
         assert_eq!(resolved.host, "192.168.0.20");
     }
+
+    #[test]
+    fn test_env_vars_resolve_config_vars() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-vars-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(".env"),
+            "HOST=${STACK_SYNC_TEST_DOMAIN}\nURL=https://${HOST}/api\nPRICE='$5'\n",
+        )
+        .unwrap();
+
+        let toml_str = r#"
+[vars]
+STACK_SYNC_TEST_DOMAIN = "example.com"
+
+[stacks.my-stack]
+compose_file = "compose.yaml"
+env_file = ".env"
+"#;
+        let config: PartialConfigFile = toml::from_str(toml_str).unwrap();
+        let resolved = config
+            .resolve("my-stack", &portainer_global(), &dir)
+            .unwrap();
+        let vars = resolved.env_vars().unwrap();
+        assert_eq!(vars[1].value, "https://example.com/api");
+        assert_eq!(
+            resolved.deployed_env_content(None, &vars).unwrap().unwrap(),
+            "HOST=example.com\nURL=https://example.com/api\nPRICE='$5'\n"
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_env_vars_layers_env_files_and_tables() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-layers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.env"), "DB=app\nLOG=info\n").unwrap();
+        std::fs::write(
+            dir.join("app.prod.env"),
+            "LOG=warn\nURL=http://${DB}/${TZ}\n",
+        )
+        .unwrap();
+
+        let toml_str = r#"
+[common_env]
+TZ = "UTC"
+PUID = "1000"
+
+[stacks.my-stack]
+compose_file = "compose.yaml"
+env_file = "app.env"
+env_files = ["app.prod.env"]
+
+[stacks.my-stack.env]
+PUID = "1001"
+REPLICAS = "2"
+"#;
+        let config: PartialConfigFile = toml::from_str(toml_str).unwrap();
+        let resolved = config
+            .resolve("my-stack", &portainer_global(), &dir)
+            .unwrap();
+        assert_eq!(resolved.env_files, vec!["app.env", "app.prod.env"]);
+        let vars: Vec<(String, String)> = resolved
+            .env_vars()
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.name, v.value))
+            .collect();
+        let expected = [
+            ("PUID", "1001"),
+            ("TZ", "UTC"),
+            ("DB", "app"),
+            ("LOG", "warn"),
+            ("URL", "http://app/UTC"),
+            ("REPLICAS", "2"),
+        ];
+        assert_eq!(vars, expected.map(|(n, v)| (n.to_string(), v.to_string())));
+
+        let content = serialize_config(&config).unwrap();
+        assert!(content.contains("[common_env]\nPUID = \"1000\"\nTZ = \"UTC\"\n"));
+        assert!(content.contains("env_files = [\"app.prod.env\"]\n"));
+        assert!(content.contains("[stacks.my-stack.env]\nPUID = \"1001\"\nREPLICAS = \"2\""));
+        let reparsed: PartialConfigFile = toml::from_str(&content).unwrap();
+        assert_eq!(
+            reparsed.stacks["my-stack"].env,
+            config.stacks["my-stack"].env
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_env_tables_accept_scalars() {
+        let config: PartialConfigFile = toml::from_str(
+            r#"
+[common_env]
+PUID = 1000
+DEBUG = true
+
+[stacks.x]
+compose_file = "compose.yaml"
+
+[stacks.x.env]
+PUID = 1000
+RATIO = 1.0
+NAME = "app"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.common_env["PUID"], "1000");
+        assert_eq!(config.common_env["DEBUG"], "true");
+        let env = &config.stacks["x"].env;
+        assert_eq!(env["PUID"], "1000");
+        assert_eq!(env["RATIO"], "1.0");
+        assert_eq!(env["NAME"], "app");
+
+        let err = toml::from_str::<PartialConfigFile>("[common_env]\nLIST = [1]\n").unwrap_err();
+        assert!(err.to_string().contains("a string, number or boolean"));
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<EnvVar> {
+        pairs
+            .iter()
+            .map(|(name, value)| EnvVar {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_deployed_env_strategies() {
+        let remote = env(&[("A", "remote"), ("UI_ONLY", "x"), ("SECRET_KEY", "s")]);
+        let local = env(&[("A", "local"), ("B", "new"), ("SECRET_KEY", "local")]);
+        let partial: PartialConfigFile =
+            toml::from_str("[stacks.web]\ncompose_file = \"compose.yaml\"\n").unwrap();
+        let mut config = partial
+            .resolve("web", &portainer_global(), Path::new("."))
+            .unwrap();
+
+        assert_eq!(config.deployed_env(&remote, &local), local);
+
+        config.env_strategy = EnvStrategy::Merge;
+        assert_eq!(
+            config.deployed_env(&remote, &local),
+            env(&[
+                ("A", "local"),
+                ("B", "new"),
+                ("SECRET_KEY", "local"),
+                ("UI_ONLY", "x")
+            ])
+        );
+
+        config.env_strategy = EnvStrategy::PreserveRemote;
+        assert_eq!(
+            config.deployed_env(&remote, &local),
+            env(&[
+                ("A", "remote"),
+                ("B", "new"),
+                ("SECRET_KEY", "s"),
+                ("UI_ONLY", "x")
+            ])
+        );
+
+        config.env_strategy = EnvStrategy::Replace;
+        config.ignore_env = vec!["SECRET_*".to_string()];
+        assert_eq!(
+            config.deployed_env(&remote, &local),
+            env(&[("A", "local"), ("B", "new"), ("SECRET_KEY", "s")])
+        );
+        // Removing every local var clears the remote, except ignored keys
+        assert_eq!(
+            config.deployed_env(&remote, &[]),
+            env(&[("SECRET_KEY", "s")])
+        );
+    }
+
+    #[test]
+    fn test_deployed_env_keeps_remote_for_unresolved_secrets() {
+        let remote = env(&[("A", "remote"), ("DB_URL", "postgres://u:pw@db")]);
+        let unresolved = format!("postgres://u:{}@db", secrets::UNRESOLVED);
+        let local = env(&[
+            ("A", "local"),
+            ("DB_URL", &unresolved),
+            ("NEW", secrets::UNRESOLVED),
+        ]);
+        let partial: PartialConfigFile =
+            toml::from_str("[stacks.web]\ncompose_file = \"compose.yaml\"\n").unwrap();
+        let config = partial
+            .resolve("web", &portainer_global(), Path::new("."))
+            .unwrap();
+        assert_eq!(
+            config.deployed_env(&remote, &local),
+            env(&[
+                ("A", "local"),
+                ("DB_URL", "postgres://u:pw@db"),
+                ("NEW", secrets::UNRESOLVED)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_deployed_env_content_keeps_remote_secrets_under_replace() {
+        let partial: PartialConfigFile =
+            toml::from_str("[stacks.web]\ncompose_file = \"compose.yaml\"\n").unwrap();
+        let config = partial
+            .resolve("web", &portainer_global(), Path::new("."))
+            .unwrap();
+        assert_eq!(config.env_strategy, EnvStrategy::Replace);
+        let local = env(&[("A", "1"), ("TOKEN", secrets::UNRESOLVED)]);
+        let content = config
+            .deployed_env_content(Some("A=1\nTOKEN=hunter2\nOLD=x\n"), &local)
+            .unwrap();
+        assert_eq!(content.as_deref(), Some("A=1\nTOKEN=hunter2\n"));
+    }
+
+    #[test]
+    fn test_compose_template() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("app.compose.yaml.tmpl"),
+            "services:\n  {{ stack }}:\n    image: app:{{ tag }}\n    \
+             labels:\n      - host={{ domain }}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("compose.prod.yaml"), "services: {}\n").unwrap();
+
+        let toml_str = r#"
+[vars]
+tag = "1.0"
+domain = "example.com"
+
+[stacks.web]
+compose_template = "app.compose.yaml.tmpl"
+compose_files = ["compose.prod.yaml"]
+
+[stacks.web.vars]
+tag = "2.0"
+"#;
+        let config: PartialConfigFile = toml::from_str(toml_str).unwrap();
+        let web = config.resolve("web", &portainer_global(), &dir).unwrap();
+        assert_eq!(
+            web.read_compose_files().unwrap(),
+            [
+                "services:\n  web:\n    image: app:2.0\n    labels:\n      - host=example.com\n",
+                "services: {}\n",
+            ]
+        );
+
+        let content = serialize_config(&config).unwrap();
+        assert!(content.contains("compose_template = \"app.compose.yaml.tmpl\"\n"));
+        assert!(content.contains("[stacks.web.vars]\ntag = \"2.0\""));
+
+        std::fs::write(dir.join("app.compose.yaml.tmpl"), "image: {{ missing }}\n").unwrap();
+        let err = format!("{:#}", web.read_compose_files().unwrap_err());
+        assert!(err.contains("app.compose.yaml.tmpl"));
+        assert!(err.contains("unknown template variable 'missing'"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_stack_relative_path() {
+        assert_eq!(
+            stack_relative_path("./nginx/site.conf").as_deref(),
+            Some("nginx/site.conf")
+        );
+        assert_eq!(
+            stack_relative_path("a/../b.conf").as_deref(),
+            Some("b.conf")
+        );
+        assert_eq!(stack_relative_path("../shared.conf"), None);
+        assert_eq!(stack_relative_path("/etc/hosts"), None);
+        assert_eq!(stack_relative_path("."), None);
+    }
+
+    #[test]
+    fn test_stack_files() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-files-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nginx")).unwrap();
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(dir.join("nginx/site.conf"), "server {}\n").unwrap();
+        std::fs::write(dir.join("app.env"), "SECRET=cmd://printf hunter2\n").unwrap();
+        std::fs::write(dir.join("app.prod.env"), "LOG=warn\nURL=db:${SECRET}\n").unwrap();
+        std::fs::write(dir.join("extra.txt"), "extra\n").unwrap();
+        std::fs::write(dir.join("icon.png"), [0x89, b'P', b'N', b'G', 0xff]).unwrap();
+
+        let toml_str = r#"
+[stacks.web]
+compose_file = "compose.yaml"
+env_files = ["app.env", "app.prod.env"]
+files = ["extra.txt", "icon.png"]
+
+[stacks.web.env]
+REPLICAS = "2"
+
+[stacks.escape]
+compose_file = "compose.yaml"
+files = ["../outside.txt"]
+"#;
+        let config: PartialConfigFile = toml::from_str(toml_str).unwrap();
+        let compose = "services:\n  web:\n    image: nginx\n    \
+                       env_file: [./app.env, app.prod.env]\n    \
+                       volumes:\n      - ./nginx/site.conf:/etc/nginx/conf.d/site.conf:ro\n      \
+                       - ./data:/data\n      - ./.env:/app/.env\n";
+        let web = config.resolve("web", &portainer_global(), &dir).unwrap();
+        let files = web.stack_files(compose).unwrap();
+        assert_eq!(
+            files.files,
+            BTreeMap::from([
+                // Each env file is uploaded resolved, with only its own vars
+                ("app.env".to_string(), b"SECRET=hunter2\n".to_vec()),
+                (
+                    "app.prod.env".to_string(),
+                    b"LOG=warn\nURL=db:hunter2\n".to_vec()
+                ),
+                ("extra.txt".to_string(), b"extra\n".to_vec()),
+                ("icon.png".to_string(), vec![0x89, b'P', b'N', b'G', 0xff]),
+                ("nginx/site.conf".to_string(), b"server {}\n".to_vec()),
+            ])
+        );
+        assert_eq!(files.skipped, ["./data"]);
+        assert!(files.unresolved.is_empty());
+
+        let escape = config.resolve("escape", &portainer_global(), &dir).unwrap();
+        let err = escape.stack_files("services: {}\n").unwrap_err();
+        assert!(err.to_string().contains("outside the stack directory"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_walk_inherits_tables_past_a_complete_config() {
+        let home = std::env::temp_dir().join(format!("stack-sync-chain-{}", std::process::id()));
+        let project = home.join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let home = home.canonicalize().unwrap();
+        std::fs::write(
+            home.join(".stack-sync.toml"),
+            r#"
+mode = "ssh"
+host = "other.example.com"
+age_identity = "~/.age/key.txt"
+
+[vars]
+DOMAIN = "example.com"
+REGION = "home"
+
+[secret_providers]
+pass = "pass show"
+
+[common_env]
+TZ = "UTC"
+"#,
+        )
+        .unwrap();
+        let local = project.join(".stack-sync.toml");
+        std::fs::write(
+            &local,
+            r#"
+portainer_api_key = "key"
+host = "https://portainer.example.com"
+endpoint_id = 2
+
+[vars]
+REGION = "local"
+"#,
+        )
+        .unwrap();
+
+        for chain in [
+            walk_config_chain(&project, Some(&local), Some(home.clone())).unwrap(),
+            walk_config_chain(&project, None, Some(home.clone())).unwrap(),
+        ] {
+            assert_eq!(chain.mode, None);
+            assert_eq!(chain.host.as_deref(), Some("https://portainer.example.com"));
+            assert_eq!(chain.age_identity.as_deref(), Some("~/.age/key.txt"));
+            assert_eq!(chain.vars["DOMAIN"], "example.com");
+            assert_eq!(chain.secret_providers["pass"], "pass show");
+            assert_eq!(chain.common_env["TZ"], "UTC");
+            assert_eq!(chain.vars["REGION"], "local");
+            assert_eq!(chain.files.len(), 2);
+        }
+
+        std::fs::remove_dir_all(&home).ok();
+    }
+
+    #[test]
+    fn test_age_config_needs_no_connection_settings() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-age-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(".stack-sync.toml"),
+            "age_identity = \"key.txt\"\nage_recipients = [\"age1abc\"]\n",
+        )
+        .unwrap();
+
+        assert!(resolve_config_chain(&dir).is_err());
+        let config = age_config(&dir).unwrap();
+        assert_eq!(config.age_identity.as_deref(), Some("key.txt"));
+        assert_eq!(config.age_recipients, Some(vec!["age1abc".to_string()]));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let table: toml::Table = toml::from_str(
+            r#"
+host = "x"
+hots = "y"
+
+[vars]
+anything = "goes"
+
+[stacks.web]
+compose_file = "compose.yaml"
+env_flie = ".env"
+
+[stacks.web.env]
+ANY = "1"
+"#,
+        )
+        .unwrap();
+        assert_eq!(unknown_keys(&table), ["hots", "stacks.web.env_flie"]);
+    }
+
+    #[test]
+    fn test_keys_come_from_the_structs() {
+        assert_eq!(config_keys().first(), Some(&"mode"));
+        assert_eq!(config_keys().last(), Some(&"stacks"));
+        assert_eq!(config_keys().len(), 16);
+        assert_eq!(stack_keys().first(), Some(&"compose_file"));
+        assert_eq!(stack_keys().last(), Some(&"enabled"));
+        assert_eq!(stack_keys().len(), 13);
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("SECRET_*", "SECRET_KEY"));
+        assert!(wildcard_match("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(wildcard_match("A*B*C", "AxxBxxC"));
+        assert!(wildcard_match("EXACT", "EXACT"));
+        assert!(!wildcard_match("EXACT", "EXACTLY"));
+        assert!(!wildcard_match("SECRET_*", "MY_SECRET_KEY"));
+        assert!(!wildcard_match("AB*BA", "ABA"));
+    }
+
+    #[test]
+    fn test_resolve_compose_files() {
+        let config: PartialConfigFile = toml::from_str(
+            r#"
+[stacks.layered]
+compose_file = "compose.yaml"
+compose_files = ["compose.prod.yaml"]
+
+[stacks.list]
+compose_files = ["a.yaml", "b.yaml"]
+
+[stacks.none]
+env_file = ".env"
+"#,
+        )
+        .unwrap();
+        let dir = Path::new("/stacks");
+        let resolve = |name| config.resolve(name, &portainer_global(), dir);
+        assert_eq!(
+            resolve("layered").unwrap().compose_files,
+            vec!["compose.yaml", "compose.prod.yaml"]
+        );
+        assert_eq!(
+            resolve("list").unwrap().compose_source(),
+            "/stacks/a.yaml, /stacks/b.yaml"
+        );
+        assert!(resolve("none").is_err());
+
+        let content = serialize_config(&config).unwrap();
+        assert!(content.contains("compose_files = [\"a.yaml\", \"b.yaml\"]\n"));
+    }
+
+    #[test]
+    fn test_serialize_config_keeps_vars() {
+        let config: PartialConfigFile = toml::from_str(
+            "host = \"h\"\n\n[vars]\nDOMAIN = \"example.com\"\n\"odd.name\" = \"x\"\n",
+        )
+        .unwrap();
+        let content = serialize_config(&config).unwrap();
+        assert!(content.contains("[vars]\nDOMAIN = \"example.com\"\n\"odd.name\" = \"x\"\n"));
+        let reparsed: PartialConfigFile = toml::from_str(&content).unwrap();
+        assert_eq!(reparsed.vars, config.vars);
+    }
+
+    #[test]
+    fn test_resolve_env_str_resolves_secrets_before_references() {
+        let content = "PASSWORD=secret://test/db\nURL=postgres://app:${PASSWORD}@db\nTOKEN=cmd://printf tok\n";
+        let providers = HashMap::from([("test".to_string(), "printf 's3cret-%s'".to_string())]);
+
+        let vars = resolve_env_str(content, &[], &HashMap::new(), &providers).unwrap();
+        assert_eq!(vars[0].value, "s3cret-db");
+        assert_eq!(vars[1].value, "postgres://app:s3cret-db@db");
+        assert_eq!(vars[2].value, "tok");
+    }
 }
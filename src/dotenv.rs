@@ -0,0 +1,464 @@
+use anyhow::{Context, Result, bail};
+
+use crate::config::EnvVar;
+
+/// Looks up a variable referenced from an env file by name.
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Final step applied to each value once it is expanded, such as fetching
+/// a secret it references.
+type Resolve<'a> = &'a dyn Fn(String) -> Result<String>;
+
+/// Parse env file content the way docker compose reads `.env` files:
+///
+/// - blank lines and lines starting with `#` are skipped
+/// - an `export ` prefix is ignored
+/// - single-quoted values are literal and may span lines
+/// - double-quoted values may span lines and support `\n`, `\r`, `\t`, `\\`
+///   and `\"` escapes
+/// - unquoted values end at a ` #` comment and are trimmed
+/// - a bare `NAME` takes its value from the process environment, and is
+///   skipped when that isn't set
+///
+/// Later definitions of a name replace earlier ones. Values are taken
+/// literally; see `parse_interpolated` for `${VAR}` references.
+pub fn parse(content: &str) -> Result<Vec<EnvVar>> {
+    parse_values(content, None, &Ok)
+}
+
+/// Parse env file content like `parse`, expanding `$VAR`, `${VAR}`,
+/// `${VAR:-default}` and the other compose forms in unquoted and
+/// double-quoted values. A name resolves to an earlier key in the file
+/// first, then to `lookup`; a reference to a name that is set nowhere is an
+/// error rather than a silently empty value. `$$` (and `\$` in double
+/// quotes) is a literal `$`.
+///
+/// Each value defined in the file then goes through `resolve`, before later
+/// keys can reference it.
+pub fn parse_interpolated(content: &str, lookup: Lookup, resolve: Resolve) -> Result<Vec<EnvVar>> {
+    parse_values(content, Some(lookup), resolve)
+}
+
+fn parse_values(content: &str, lookup: Option<Lookup>, resolve: Resolve) -> Result<Vec<EnvVar>> {
+    let mut vars: Vec<EnvVar> = Vec::new();
+    let mut rest = content;
+    let mut line_no = 1;
+
+    while !rest.is_empty() {
+        let (line, after) = rest.split_once('\n').unwrap_or((rest, ""));
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            rest = after;
+            line_no += 1;
+            continue;
+        }
+
+        let statement = trimmed
+            .strip_prefix("export ")
+            .map(|s| s.trim_start())
+            .unwrap_or(trimmed);
+        let Some((name, raw_value)) = statement.split_once('=') else {
+            let name = validate_name(strip_comment(statement).trim(), line_no)?;
+            if let Ok(value) = std::env::var(name) {
+                set(&mut vars, name, value);
+            }
+            rest = after;
+            line_no += 1;
+            continue;
+        };
+        let name = validate_name(name.trim(), line_no)?;
+        let value = raw_value.trim_start();
+
+        match value.chars().next() {
+            Some(quote @ ('\'' | '"')) => {
+                // Quoted values may continue past this line, so scan from the
+                // opening quote through the rest of the content
+                let start = line.trim_end().len() - value.len() + 1;
+                let body = &rest[start..];
+                let Some(end) = closing_quote(body, quote) else {
+                    bail!(
+                        "Unterminated quoted value for '{}' on line {}",
+                        name,
+                        line_no
+                    );
+                };
+                let raw = &body[..end];
+                let (trailing, next) = body[end + 1..]
+                    .split_once('\n')
+                    .unwrap_or((&body[end + 1..], ""));
+                let trailing = trailing.trim();
+                if !trailing.is_empty() && !trailing.starts_with('#') {
+                    bail!(
+                        "Unexpected '{}' after quoted value for '{}' on line {}",
+                        trailing,
+                        name,
+                        line_no
+                    );
+                }
+                let value = if quote == '"' {
+                    expand(raw, true, &vars, lookup)
+                        .context(format!("Invalid value for '{}' on line {}", name, line_no))?
+                } else {
+                    raw.to_string()
+                };
+                let value = resolve(value)
+                    .context(format!("Failed to resolve '{}' on line {}", name, line_no))?;
+                set(&mut vars, name, value);
+                line_no += raw.matches('\n').count() + 1;
+                rest = next;
+            }
+            _ => {
+                let raw = strip_comment(raw_value).trim();
+                let value = expand(raw, false, &vars, lookup)
+                    .context(format!("Invalid value for '{}' on line {}", name, line_no))?;
+                let value = resolve(value)
+                    .context(format!("Failed to resolve '{}' on line {}", name, line_no))?;
+                set(&mut vars, name, value);
+                rest = after;
+                line_no += 1;
+            }
+        }
+    }
+
+    Ok(vars)
+}
+
+fn validate_name(name: &str, line_no: usize) -> Result<&str> {
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'')
+    {
+        bail!("Invalid variable name '{}' on line {}", name, line_no);
+    }
+    Ok(name)
+}
+
+fn set(vars: &mut Vec<EnvVar>, name: &str, value: String) {
+    match vars.iter_mut().find(|v| v.name == name) {
+        Some(existing) => existing.value = value,
+        None => vars.push(EnvVar {
+            name: name.to_string(),
+            value,
+        }),
+    }
+}
+
+/// Byte offset of the quote that closes a value, skipping `\"` escapes in
+/// double-quoted values.
+fn closing_quote(body: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in body.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote == '"' => escaped = true,
+            c if c == quote => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A `#` starts a comment in an unquoted value only after whitespace, so
+/// `COLOR=#fff` keeps its value but `COLOR= #fff` is empty.
+fn strip_comment(value: &str) -> &str {
+    let bytes = value.as_bytes();
+    for (i, c) in value.char_indices() {
+        if c == '#' && i > 0 && bytes[i - 1].is_ascii_whitespace() {
+            return &value[..i];
+        }
+    }
+    value
+}
+
+/// Expand a raw value: backslash escapes when `escapes` is set (double
+/// quotes), and variable references when a `lookup` is given.
+fn expand(raw: &str, escapes: bool, vars: &[EnvVar], lookup: Option<Lookup>) -> Result<String> {
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if escapes => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 'r')) => value.push('\r'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, other)) => value.push(other),
+                None => value.push('\\'),
+            },
+            '$' if lookup.is_some() => {
+                let resolve = |name: &str| {
+                    vars.iter()
+                        .find(|v| v.name == name)
+                        .map(|v| v.value.clone())
+                        .or_else(|| lookup.and_then(|lookup| lookup(name)))
+                };
+                match chars.peek().map(|&(_, c)| c) {
+                    Some('$') => {
+                        chars.next();
+                        value.push('$');
+                    }
+                    Some('{') => {
+                        let start = i + 2;
+                        let Some(len) = closing_brace(&raw[start..]) else {
+                            bail!("Unterminated '${{' in '{}'", raw);
+                        };
+                        value.push_str(&expand_reference(&raw[start..start + len], &resolve)?);
+                        while chars.next_if(|&(j, _)| j <= start + len).is_some() {}
+                    }
+                    Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                        let start = i + 1;
+                        let mut end = start;
+                        while let Some((j, c)) =
+                            chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
+                        {
+                            end = j + c.len_utf8();
+                        }
+                        let name = &raw[start..end];
+                        value.push_str(&resolve(name).context(not_set(name))?);
+                    }
+                    _ => value.push('$'),
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    Ok(value)
+}
+
+/// Length of a `${...}` body, up to the brace that closes it. Defaults may
+/// hold references of their own, so nested braces are counted.
+fn closing_brace(body: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in body.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expand the body of a `${...}` reference: `NAME`, `NAME:-default`,
+/// `NAME-default`, `NAME:?error`, `NAME?error`, `NAME:+alternate` or
+/// `NAME+alternate`. The colon forms treat an empty value as unset.
+fn expand_reference(body: &str, resolve: Lookup) -> Result<String> {
+    let name_len = body
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(body.len());
+    let (name, op) = body.split_at(name_len);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        bail!("Invalid variable reference '${{{}}}'", body);
+    }
+    let value = resolve(name);
+    let (colon, op) = match op.strip_prefix(':') {
+        Some(op) => (true, op),
+        None => (false, op),
+    };
+    let set = value.as_deref().is_some_and(|v| !(colon && v.is_empty()));
+    let word = |word: &str| expand(word, false, &[], Some(resolve));
+
+    match op.chars().next() {
+        None if !colon => value.context(not_set(name)),
+        Some('-') => match value {
+            Some(value) if set => Ok(value),
+            _ => word(&op[1..]),
+        },
+        Some('+') if set => word(&op[1..]),
+        Some('+') => Ok(String::new()),
+        Some('?') => match value {
+            Some(value) if set => Ok(value),
+            _ if op.len() > 1 => bail!("{}: {}", name, word(&op[1..])?),
+            _ => Err(anyhow::anyhow!(not_set(name))),
+        },
+        _ => bail!("Invalid variable reference '${{{}}}'", body),
+    }
+}
+
+fn not_set(name: &str) -> String {
+    format!(
+        "Variable '{}' is not set. Define it earlier in the env file, in the environment \
+         or under [vars], or give a default with '${{{}:-default}}'.",
+        name, name
+    )
+}
+
+/// Format one variable as an env file line, quoting the value when it
+/// wouldn't read back unchanged otherwise. Single quotes are preferred since
+/// nothing inside them is special; values containing a single quote are
+/// double-quoted with escapes.
+pub fn format_var(var: &EnvVar) -> String {
+    let value = &var.value;
+    let plain = !value
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '#' | '"' | '\'' | '\\' | '$' | '`'));
+    if plain {
+        return format!("{}={}", var.name, value);
+    }
+    if !value.contains('\'') {
+        return format!("{}='{}'", var.name, value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '$' => escaped.push_str("\\$"),
+            c => escaped.push(c),
+        }
+    }
+    format!("{}=\"{}\"", var.name, escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str, value: &str) -> EnvVar {
+        EnvVar {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_quotes() {
+        let vars =
+            parse("A=\"hello world\"\nB='single $quoted'\nC=\"say \\\"hi\\\"\\n\"\n").unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                var("A", "hello world"),
+                var("B", "single $quoted"),
+                var("C", "say \"hi\"\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_export_and_comments() {
+        let vars = parse("export FOO=bar\nBAZ=qux # trailing comment\nCOLOR=#fff\nQ='x' # note\n")
+            .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                var("FOO", "bar"),
+                var("BAZ", "qux"),
+                var("COLOR", "#fff"),
+                var("Q", "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_multiline() {
+        let vars =
+            parse("KEY=\"-----BEGIN-----\nabc\n-----END-----\"\nNEXT='a\nb'\nLAST=1\n").unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                var("KEY", "-----BEGIN-----\nabc\n-----END-----"),
+                var("NEXT", "a\nb"),
+                var("LAST", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_later_definition_wins() {
+        let vars = parse("A=1\nB=2\nA=3\n").unwrap();
+        assert_eq!(vars, vec![var("A", "3"), var("B", "2")]);
+    }
+
+    #[test]
+    fn test_parse_bare_name_inherits_environment() {
+        let vars = parse("HOME # from the environment\nSTACK_SYNC_SURELY_UNSET_VAR\n").unwrap();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars[0].name, "HOME");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse("A=1\nB=\"open\nC=2\n").unwrap_err().to_string();
+        assert!(err.contains("'B' on line 2"), "{}", err);
+        assert!(parse("A B=1\n").is_err());
+        assert!(parse("A=\"x\" y\n").is_err());
+    }
+
+    #[test]
+    fn test_format_var_round_trips() {
+        let vars = vec![
+            var("PLAIN", "abc=123"),
+            var("EMPTY", ""),
+            var("SPACES", "hello world"),
+            var("DOLLAR", "pa$$word"),
+            var("QUOTE", "it's \"quoted\"\\n"),
+            var("BOTH", "it's $HOME"),
+            var("MULTI", "line one\nline two"),
+            var("HASH", "a #b"),
+        ];
+        let content: String = vars.iter().map(|v| format_var(v) + "\n").collect();
+        assert_eq!(parse(&content).unwrap(), vars);
+        assert_eq!(parse_interpolated(&content, &lookup, &Ok).unwrap(), vars);
+        assert_eq!(format_var(&vars[0]), "PLAIN=abc=123");
+        assert_eq!(format_var(&vars[2]), "SPACES='hello world'");
+    }
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "DOMAIN" => Some("example.com".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_interpolated_references() {
+        let content = "HOST=app.${DOMAIN}\n\
+                       URL=\"https://$HOST/api\"\n\
+                       RAW='${HOST}'\n\
+                       PORT=${PORT:-8080}\n\
+                       BLANK=${EMPTY:-fallback}/${EMPTY-kept}\n\
+                       ALT=${DOMAIN:+set}${MISSING:+unset}\n\
+                       NESTED=${MISSING:-${HOST}:${PORT}}\n\
+                       COST=\"$$5 \\$6\" # both literal\n";
+        let vars = parse_interpolated(content, &lookup, &Ok).unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                var("HOST", "app.example.com"),
+                var("URL", "https://app.example.com/api"),
+                var("RAW", "${HOST}"),
+                var("PORT", "8080"),
+                var("BLANK", "fallback/"),
+                var("ALT", "set"),
+                var("NESTED", "app.example.com:8080"),
+                var("COST", "$5 $6"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_interpolated_errors() {
+        let err = parse_interpolated("A=1\nB=${MISSING}\n", &lookup, &Ok)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'B' on line 2"), "{}", err);
+        let err = format!(
+            "{:#}",
+            parse_interpolated("A=${MISSING:?set it}\n", &lookup, &Ok).unwrap_err()
+        );
+        assert!(err.contains("MISSING: set it"), "{}", err);
+        assert!(parse_interpolated("A=${DOMAIN\n", &lookup, &Ok).is_err());
+        assert!(parse_interpolated("A=${1X}\n", &lookup, &Ok).is_err());
+    }
+
+    #[test]
+    fn test_parse_leaves_references() {
+        assert_eq!(parse("A=${B}\n").unwrap(), vec![var("A", "${B}")]);
+    }
+}
@@ -0,0 +1,469 @@
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::{Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::ssh::shellexpand_tilde;
+
+/// Env var SOPS itself reads the age identity from; used as a fallback when
+/// no `age_identity` is configured.
+const SOPS_AGE_KEY_FILE: &str = "SOPS_AGE_KEY_FILE";
+
+/// How an encrypted env file is protected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encryption {
+    /// An age file (armored or binary), recognized by its `.age` extension.
+    Age,
+    /// A SOPS-encrypted dotenv file, recognized by its `sops_*` metadata keys.
+    Sops,
+}
+
+impl Encryption {
+    pub fn detect(path: &Path, content: &[u8]) -> Option<Self> {
+        if path.extension().is_some_and(|ext| ext == "age") {
+            return Some(Self::Age);
+        }
+        let sops = content
+            .split(|&b| b == b'\n')
+            .any(|line| line.starts_with(b"sops_version=") || line.starts_with(b"sops_mac="));
+        sops.then_some(Self::Sops)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Age => "age",
+            Self::Sops => "SOPS",
+        }
+    }
+}
+
+/// Keys for encrypted env files, from the `age_identity` and
+/// `age_recipients` config fields.
+pub struct Keys<'a> {
+    pub identity: Option<&'a str>,
+    pub recipients: Option<&'a [String]>,
+}
+
+impl Keys<'_> {
+    /// The identity file: `age_identity`, or `$SOPS_AGE_KEY_FILE`.
+    fn identity_path(&self) -> Option<PathBuf> {
+        match self.identity {
+            Some(identity) => Some(PathBuf::from(shellexpand_tilde(identity))),
+            None => std::env::var_os(SOPS_AGE_KEY_FILE).map(PathBuf::from),
+        }
+    }
+
+    fn identity_file(&self) -> Result<IdentityFile<age::NoCallbacks>> {
+        let path = self.identity_path().context(format!(
+            "No age identity configured. Add 'age_identity' to a .stack-sync.toml config \
+             file or set {}.",
+            SOPS_AGE_KEY_FILE
+        ))?;
+        IdentityFile::from_file(path.to_string_lossy().into_owned()).context(format!(
+            "Failed to read age identity file: {}",
+            path.display()
+        ))
+    }
+
+    fn age_recipients(&self) -> Result<Vec<Box<dyn Recipient + Send>>> {
+        match self.recipients {
+            Some(recipients) => recipients
+                .iter()
+                .map(|r| {
+                    age::x25519::Recipient::from_str(r)
+                        .map(|r| Box::new(r) as Box<dyn Recipient + Send>)
+                        .map_err(|e| anyhow::anyhow!("Invalid age recipient '{}': {}", r, e))
+                })
+                .collect(),
+            None => Ok(self.identity_file()?.to_recipients()?),
+        }
+    }
+}
+
+/// Read an env file, decrypting it in memory when it is encrypted. The
+/// plaintext is never written to disk.
+pub fn read_env_file(path: &Path, keys: &Keys) -> Result<String> {
+    let content =
+        std::fs::read(path).context(format!("Failed to read env file: {}", path.display()))?;
+    let plaintext = match Encryption::detect(path, &content) {
+        None => content,
+        Some(Encryption::Age) => decrypt_age(&content, keys)
+            .context(format!("Failed to decrypt env file: {}", path.display()))?,
+        Some(Encryption::Sops) => sops(&["--decrypt"], path, keys)
+            .context(format!("Failed to decrypt env file: {}", path.display()))?,
+    };
+    String::from_utf8(plaintext).context(format!("Env file is not valid UTF-8: {}", path.display()))
+}
+
+fn decrypt_age(ciphertext: &[u8], keys: &Keys) -> Result<Vec<u8>> {
+    let identities = keys.identity_file()?.into_identities()?;
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(ciphertext))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))?;
+    let mut plaintext = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// Encrypt to the configured recipients as an ASCII-armored age file, so it
+/// diffs and reviews as text in git.
+fn encrypt_age(plaintext: &[u8], keys: &Keys) -> Result<Vec<u8>> {
+    let recipients = keys.age_recipients()?;
+    let encryptor =
+        Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as &dyn Recipient))?;
+    let mut ciphertext = Vec::new();
+    let armored = ArmoredWriter::wrap_output(&mut ciphertext, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armored)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?.finish()?;
+    Ok(ciphertext)
+}
+
+/// Run `sops` on a dotenv file and return its output. The age identity is
+/// passed through `SOPS_AGE_KEY_FILE`; with `age_recipients` configured,
+/// encryption uses them instead of `.sops.yaml` creation rules.
+fn sops(args: &[&str], path: &Path, keys: &Keys) -> Result<Vec<u8>> {
+    let mut command = Command::new("sops");
+    command
+        .args(args)
+        .args(["--input-type", "dotenv", "--output-type", "dotenv"]);
+    if args.contains(&"--encrypt")
+        && let Some(recipients) = keys.recipients
+    {
+        command.args(["--age", &recipients.join(",")]);
+    }
+    if let Some(identity) = keys.identity_path() {
+        command.env(SOPS_AGE_KEY_FILE, identity);
+    }
+    let output = command
+        .arg(path)
+        .output()
+        .context("Failed to run sops. Install sops to use SOPS-encrypted env files.")?;
+    if !output.status.success() {
+        bail!(
+            "sops failed (exit {}): {}",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Encrypt a plaintext env file and write the result to `output`.
+pub fn encrypt_file(
+    input: &Path,
+    output: &Path,
+    encryption: Encryption,
+    keys: &Keys,
+) -> Result<()> {
+    let ciphertext = match encryption {
+        Encryption::Age => {
+            let plaintext = std::fs::read(input)
+                .context(format!("Failed to read env file: {}", input.display()))?;
+            encrypt_age(&plaintext, keys)?
+        }
+        Encryption::Sops => sops(&["--encrypt"], input, keys)?,
+    };
+    std::fs::write(output, ciphertext)
+        .context(format!("Failed to write env file: {}", output.display()))
+}
+
+/// Decrypt an encrypted env file and return its plaintext.
+pub fn decrypt_file(path: &Path, keys: &Keys) -> Result<String> {
+    let content =
+        std::fs::read(path).context(format!("Failed to read env file: {}", path.display()))?;
+    if Encryption::detect(path, &content).is_none() {
+        bail!(
+            "{} is not an age or SOPS encrypted env file",
+            path.display()
+        );
+    }
+    read_env_file(path, keys)
+}
+
+/// Open an encrypted env file in `$VISUAL`/`$EDITOR` and re-encrypt it
+/// when it was changed. Returns whether the file changed.
+///
+/// SOPS files are handed to `sops`, which runs the editor itself. age files
+/// are decrypted to a file in a private temp directory that is removed
+/// afterwards, since editors need a real file.
+pub fn edit_file(path: &Path, keys: &Keys) -> Result<bool> {
+    let content =
+        std::fs::read(path).context(format!("Failed to read env file: {}", path.display()))?;
+    match Encryption::detect(path, &content) {
+        Some(Encryption::Age) => {}
+        Some(Encryption::Sops) => {
+            // sops exits with 200 when the file was saved unchanged
+            let mut command = Command::new("sops");
+            command.args(["--input-type", "dotenv", "--output-type", "dotenv"]);
+            if let Some(identity) = keys.identity_path() {
+                command.env(SOPS_AGE_KEY_FILE, identity);
+            }
+            let status = command
+                .arg(path)
+                .status()
+                .context("Failed to run sops. Install sops to use SOPS-encrypted env files.")?;
+            return match status.code() {
+                Some(0) => Ok(true),
+                Some(200) => Ok(false),
+                code => bail!("sops failed (exit {})", code.unwrap_or(-1)),
+            };
+        }
+        None => bail!(
+            "{} is not an age or SOPS encrypted env file",
+            path.display()
+        ),
+    }
+
+    ensure_recipients_kept(path, &content, keys)?;
+    let plaintext = decrypt_age(&content, keys)
+        .context(format!("Failed to decrypt env file: {}", path.display()))?;
+    let dir = private_temp_dir()?;
+    let result = edit_plaintext(&dir, path, &plaintext);
+    std::fs::remove_dir_all(&dir).ok();
+    let Some(edited) = result? else {
+        return Ok(false);
+    };
+    std::fs::write(path, encrypt_age(&edited, keys)?)
+        .context(format!("Failed to write env file: {}", path.display()))?;
+    Ok(true)
+}
+
+/// Without `age_recipients`, an edited age file is re-encrypted to the
+/// editor's own identity. Refuse when the file has more recipients than
+/// that, so editing a team file never silently locks the others out.
+fn ensure_recipients_kept(path: &Path, ciphertext: &[u8], keys: &Keys) -> Result<()> {
+    if keys.recipients.is_some() {
+        return Ok(());
+    }
+    let current = age_recipient_count(ciphertext)
+        .context(format!("Failed to read age header: {}", path.display()))?;
+    let kept = keys.age_recipients()?.len();
+    if current > kept {
+        bail!(
+            "{} is encrypted to {} recipients, but would be re-encrypted to your identity \
+             only. Set 'age_recipients' to every recipient of the file before editing it.",
+            path.display(),
+            current
+        );
+    }
+    Ok(())
+}
+
+/// The number of recipient stanzas in an age file's header, leaving out
+/// the "grease" stanzas age adds to keep parsers honest.
+fn age_recipient_count(ciphertext: &[u8]) -> Result<usize> {
+    let mut content = Vec::new();
+    ArmoredReader::new(ciphertext).read_to_end(&mut content)?;
+    let mut count = 0;
+    for line in content.split(|&b| b == b'\n') {
+        if line.starts_with(b"---") {
+            return Ok(count);
+        }
+        if let Some(stanza) = line.strip_prefix(b"-> ") {
+            let tag = stanza.split(|&b| b == b' ').next().unwrap_or_default();
+            if !tag.ends_with(b"-grease") {
+                count += 1;
+            }
+        }
+    }
+    bail!("not an age file")
+}
+
+/// Write the plaintext to `dir`, run the editor on it, and return the new
+/// content if it changed.
+fn edit_plaintext(dir: &Path, path: &Path, plaintext: &[u8]) -> Result<Option<Vec<u8>>> {
+    // Drop `.age` so the editor recognizes the file type
+    let name = path
+        .file_stem()
+        .map(|s| s.to_os_string())
+        .unwrap_or_else(|| ".env".into());
+    let file = dir.join(name);
+    std::fs::write(&file, plaintext).context("Failed to write temporary env file")?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Through the shell so editors configured with arguments (`code --wait`) work
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&file)
+        .status()
+        .context(format!("Failed to run editor: {}", editor))?;
+    if !status.success() {
+        bail!(
+            "Editor exited with status {}; {} was not changed",
+            status.code().unwrap_or(-1),
+            path.display()
+        );
+    }
+
+    let edited = std::fs::read(&file).context("Failed to read temporary env file")?;
+    Ok((edited != plaintext).then_some(edited))
+}
+
+/// A temp directory only the current user can read.
+fn private_temp_dir() -> Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let dir = std::env::temp_dir().join(format!("stack-sync-edit-{}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .context(format!(
+            "Failed to create temp directory: {}",
+            dir.display()
+        ))?;
+    Ok(dir)
+}
+
+/// Write decrypted content to a file only the current user can read,
+/// tightening the mode of an existing file before writing to it.
+pub fn write_plaintext(path: &Path, plaintext: &str) -> Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .context(format!("Failed to write env file: {}", path.display()))?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .context(format!("Failed to set permissions on {}", path.display()))?;
+    file.write_all(plaintext.as_bytes())
+        .context(format!("Failed to write env file: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "stack-sync-encryption-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            Encryption::detect(Path::new("app.env.age"), b"anything"),
+            Some(Encryption::Age)
+        );
+        assert_eq!(
+            Encryption::detect(
+                Path::new("app.env"),
+                b"A=ENC[AES256_GCM,data:x]\nsops_version=3.9.0\n"
+            ),
+            Some(Encryption::Sops)
+        );
+        assert_eq!(Encryption::detect(Path::new(".env"), b"A=1\n"), None);
+    }
+
+    #[test]
+    fn test_age_round_trip() {
+        let dir = temp_dir("age");
+        let identity = age::x25519::Identity::generate();
+        let identity_path = dir.join("key.txt");
+        std::fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        let identity_path = identity_path.to_string_lossy().into_owned();
+        let keys = Keys {
+            identity: Some(&identity_path),
+            recipients: None,
+        };
+
+        let plain = dir.join("app.env");
+        let encrypted = dir.join("app.env.age");
+        std::fs::write(&plain, "PASSWORD='s3cret'\n").unwrap();
+        encrypt_file(&plain, &encrypted, Encryption::Age, &keys).unwrap();
+
+        let ciphertext = std::fs::read_to_string(&encrypted).unwrap();
+        assert!(ciphertext.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(!ciphertext.contains("s3cret"));
+        assert_eq!(
+            read_env_file(&encrypted, &keys).unwrap(),
+            "PASSWORD='s3cret'\n"
+        );
+        assert!(decrypt_file(&plain, &keys).is_err());
+
+        let other = age::x25519::Identity::generate().to_public().to_string();
+        let other_keys = Keys {
+            identity: Some(&identity_path),
+            recipients: Some(&[other]),
+        };
+        encrypt_file(&plain, &encrypted, Encryption::Age, &other_keys).unwrap();
+        assert!(read_env_file(&encrypted, &keys).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_edit_keeps_other_recipients() {
+        let dir = temp_dir("recipients");
+        let identity = age::x25519::Identity::generate();
+        let identity_path = dir.join("key.txt");
+        std::fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        let identity_path = identity_path.to_string_lossy().into_owned();
+        let own_keys = Keys {
+            identity: Some(&identity_path),
+            recipients: None,
+        };
+        let path = Path::new("app.env.age");
+
+        let solo = encrypt_age(b"A=1\n", &own_keys).unwrap();
+        assert_eq!(age_recipient_count(&solo).unwrap(), 1);
+        assert!(ensure_recipients_kept(path, &solo, &own_keys).is_ok());
+
+        let team = vec![
+            identity.to_public().to_string(),
+            age::x25519::Identity::generate().to_public().to_string(),
+        ];
+        let team_keys = Keys {
+            identity: Some(&identity_path),
+            recipients: Some(&team),
+        };
+        let shared = encrypt_age(b"A=1\n", &team_keys).unwrap();
+        assert_eq!(age_recipient_count(&shared).unwrap(), 2);
+        let err = ensure_recipients_kept(path, &shared, &own_keys).unwrap_err();
+        assert!(err.to_string().contains("encrypted to 2 recipients"));
+        assert!(ensure_recipients_kept(path, &shared, &team_keys).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_recipient() {
+        let recipients = vec!["age1nope".to_string()];
+        let keys = Keys {
+            identity: None,
+            recipients: Some(&recipients),
+        };
+        let err = encrypt_age(b"A=1\n", &keys).unwrap_err().to_string();
+        assert!(err.contains("Invalid age recipient 'age1nope'"), "{}", err);
+    }
+
+    #[test]
+    fn test_write_plaintext_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("plaintext");
+        let path = dir.join("app.env");
+        std::fs::write(&path, "OLD=1\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_plaintext(&path, "A=1\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "A=1\n");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
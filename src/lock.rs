@@ -0,0 +1,315 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::portainer::PortainerClient;
+use crate::reporter::Reporter;
+use crate::ssh::SshClient;
+
+/// Locks older than this are assumed to belong to a sync that died without
+/// cleaning up, even when the holder's PID can't be checked.
+const STALE_AFTER_SECS: u64 = 60 * 60;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Who holds a deployment lock. Stored as JSON in SSH mode and encoded into
+/// a tag name in Portainer mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub owner: String,
+    pub machine: String,
+    pub pid: u32,
+    pub acquired: u64,
+}
+
+impl LockInfo {
+    pub fn current() -> Self {
+        Self {
+            owner: current_owner(),
+            machine: current_machine(),
+            pid: std::process::id(),
+            acquired: now(),
+        }
+    }
+
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.acquired)
+    }
+
+    /// A lock is stale when its holder ran on this machine and the process is
+    /// gone, or when it has been held for longer than any sync should take.
+    pub fn is_stale(&self) -> bool {
+        if self.age() > STALE_AFTER_SECS {
+            return true;
+        }
+        self.machine == current_machine() && !pid_alive(self.pid)
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{} (pid {}, {}s ago)",
+            self.owner,
+            self.machine,
+            self.pid,
+            self.age()
+        )
+    }
+}
+
+/// Outcome of a single attempt to take a lock.
+pub enum LockAttempt {
+    Acquired,
+    /// Someone else holds the lock.
+    Held(LockHolder),
+    /// The remote doesn't let us keep a lock record, so the sync goes ahead
+    /// unlocked. The reason is shown to the user.
+    Unavailable(&'static str),
+}
+
+/// Someone else's lock, as found on the remote.
+pub struct LockHolder {
+    /// `None` while the record is still being written or can't be parsed.
+    pub info: Option<LockInfo>,
+    /// Seconds since the lock was taken, when the record (or, in Portainer
+    /// mode, the tag name) says.
+    pub age: Option<u64>,
+    /// Identifies the record, so a stale lock can be broken without
+    /// touching a lock someone else took in the meantime.
+    pub record: LockRecord,
+}
+
+/// A lock record on the remote.
+pub enum LockRecord {
+    /// A Portainer tag, by ID.
+    Tag(u64),
+    /// The contents of the SSH lock file.
+    File(String),
+}
+
+impl LockHolder {
+    /// Like [`LockInfo::is_stale`]; a record that can't be parsed only goes
+    /// stale by age.
+    pub fn is_stale(&self) -> bool {
+        match &self.info {
+            Some(info) => info.is_stale(),
+            None => self.age.is_some_and(|age| age > STALE_AFTER_SECS),
+        }
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.info, self.age) {
+            (Some(info), _) => info.fmt(f),
+            (None, Some(age)) => write!(f, "an unknown holder ({}s ago)", age),
+            (None, None) => write!(f, "an unknown holder"),
+        }
+    }
+}
+
+/// The remote a lock is kept on.
+#[derive(Clone, Copy)]
+pub enum LockTarget<'a> {
+    Portainer(&'a PortainerClient),
+    Ssh(&'a SshClient),
+}
+
+impl LockTarget<'_> {
+    fn try_lock(&self, stack: &str, info: &LockInfo) -> Result<LockAttempt> {
+        match self {
+            LockTarget::Portainer(client) => client.try_lock(stack, info),
+            LockTarget::Ssh(client) => client.try_lock(stack, info),
+        }
+    }
+
+    /// Remove our own lock for `stack`, leaving it alone if someone else
+    /// has broken and re-taken it.
+    fn release(&self, stack: &str, info: &LockInfo) -> Result<()> {
+        match self {
+            LockTarget::Portainer(client) => client.release_lock(stack, info),
+            LockTarget::Ssh(client) => client.release_lock(stack, info),
+        }
+    }
+
+    /// Remove a stale holder's lock record, and only that one.
+    fn break_lock(&self, stack: &str, holder: &LockHolder) -> Result<()> {
+        match (self, &holder.record) {
+            (LockTarget::Portainer(client), LockRecord::Tag(id)) => client.remove_lock_tag(*id),
+            (LockTarget::Ssh(client), LockRecord::File(content)) => {
+                client.remove_lock_file(stack, content)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the lock for `stack` regardless of who holds it. Returns
+    /// whether a lock was present.
+    pub fn force_unlock(&self, stack: &str) -> Result<bool> {
+        match self {
+            LockTarget::Portainer(client) => client.force_unlock(stack),
+            LockTarget::Ssh(client) => client.force_unlock(stack),
+        }
+    }
+}
+
+/// A held deployment lock, released when dropped.
+pub struct StackLock<'a> {
+    target: LockTarget<'a>,
+    stack: String,
+    info: LockInfo,
+}
+
+impl Drop for StackLock<'_> {
+    fn drop(&mut self) {
+        // Best effort: a lock we fail to remove is detected as stale later
+        let _ = self.target.release(&self.stack, &self.info);
+    }
+}
+
+/// Take the deployment lock for `stack`, waiting up to `timeout` for another
+/// holder to release it. Stale locks are broken automatically. Returns
+/// `None`, with a warning, when the remote can't hold a lock for us.
+pub fn acquire<'a>(
+    target: LockTarget<'a>,
+    stack: &str,
+    timeout: Duration,
+) -> Result<Option<StackLock<'a>>> {
+    let info = LockInfo::current();
+    let start = Instant::now();
+    let mut reported_wait = false;
+
+    loop {
+        match target.try_lock(stack, &info)? {
+            LockAttempt::Acquired => {
+                return Ok(Some(StackLock {
+                    target,
+                    stack: stack.to_string(),
+                    info,
+                }));
+            }
+            LockAttempt::Unavailable(reason) => {
+                Reporter::lock_unavailable(stack, reason);
+                return Ok(None);
+            }
+            LockAttempt::Held(holder) if holder.is_stale() => {
+                Reporter::stale_lock(stack, &holder);
+                target.break_lock(stack, &holder)?;
+                continue;
+            }
+            LockAttempt::Held(holder) => {
+                if start.elapsed() >= timeout {
+                    anyhow::bail!(
+                        "Stack '{}' is locked by {}. Retry with --lock-timeout, or run \
+                         'stack-sync unlock {}' if the lock is abandoned.",
+                        stack,
+                        holder,
+                        stack
+                    );
+                }
+                if !reported_wait {
+                    Reporter::waiting_for_lock(stack, &holder);
+                    reported_wait = true;
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn current_owner() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+pub fn current_machine() -> String {
+    Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|h| !h.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn pid_alive(pid: u32) -> bool {
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .env("LC_ALL", "C")
+        .output()
+        // EPERM means the process exists but belongs to another user
+        .map(|o| o.status.success() || String::from_utf8_lossy(&o.stderr).contains("not permitted"))
+        // If liveness can't be checked, assume the holder is still running
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(machine: &str, pid: u32, acquired: u64) -> LockInfo {
+        LockInfo {
+            owner: "alice".to_string(),
+            machine: machine.to_string(),
+            pid,
+            acquired,
+        }
+    }
+
+    #[test]
+    fn test_current_lock_is_not_stale() {
+        assert!(!LockInfo::current().is_stale());
+    }
+
+    #[test]
+    fn test_old_lock_is_stale() {
+        let lock = info("elsewhere", 1, now() - STALE_AFTER_SECS - 1);
+        assert!(lock.is_stale());
+    }
+
+    #[test]
+    fn test_recent_remote_lock_is_not_stale() {
+        let lock = info("some-other-machine", 1, now());
+        assert!(!lock.is_stale());
+    }
+
+    #[test]
+    fn test_unparseable_holder_goes_stale_by_age() {
+        let holder = |age| LockHolder {
+            info: None,
+            age,
+            record: LockRecord::File("{".to_string()),
+        };
+        assert!(!holder(None).is_stale());
+        assert!(!holder(Some(10)).is_stale());
+        assert!(holder(Some(STALE_AFTER_SECS + 1)).is_stale());
+        assert_eq!(holder(Some(10)).to_string(), "an unknown holder (10s ago)");
+    }
+
+    #[test]
+    fn test_pid_alive() {
+        assert!(pid_alive(std::process::id()));
+        // Owned by root: alive even when signalling it isn't permitted
+        assert!(pid_alive(1));
+    }
+
+    #[test]
+    fn test_lock_info_json_round_trip() {
+        let lock = info("laptop", 42, 1700000000);
+        let json = serde_json::to_string(&lock).unwrap();
+        assert_eq!(serde_json::from_str::<LockInfo>(&json).unwrap(), lock);
+    }
+}
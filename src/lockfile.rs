@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::compose::{self, ImageRef};
+use crate::config::Config;
+
+pub const LOCK_FILE: &str = "stack-sync.lock";
+
+const HEADER: &str =
+    "# Generated by `stack-sync lock`. Image digests per stack; do not edit by hand.\n\n";
+
+/// Image digests resolved by `stack-sync lock`, kept next to the config
+/// file. Maps stack name to image reference (as written in the compose file)
+/// to digest.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LockFile {
+    #[serde(default)]
+    pub stacks: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl LockFile {
+    pub fn path(base_dir: &Path) -> PathBuf {
+        base_dir.join(LOCK_FILE)
+    }
+
+    /// Read the lock file in `base_dir`, or an empty one if there is none.
+    pub fn load(base_dir: &Path) -> Result<Self> {
+        let path = Self::path(base_dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .context(format!("Failed to read lock file: {}", path.display()))?;
+        toml::from_str(&content).context(format!("Failed to parse lock file: {}", path.display()))
+    }
+
+    pub fn save(&self, base_dir: &Path) -> Result<()> {
+        let path = Self::path(base_dir);
+        let content = toml::to_string(self).context("Failed to serialize lock file")?;
+        std::fs::write(&path, format!("{}{}", HEADER, content))
+            .context(format!("Failed to write lock file: {}", path.display()))
+    }
+
+    /// Rewrite a stack's compose files with every locked image pinned to its
+    /// digest. Fails when an image of the merged file is missing from the
+    /// lock file, so a pinned deploy never silently falls back to a moving
+    /// tag.
+    pub fn pin(&self, stack: &str, files: &[String]) -> Result<Vec<String>> {
+        let digests = self.stacks.get(stack).context(format!(
+            "Stack '{}' is not in {}. Run 'stack-sync lock {}'.",
+            stack, LOCK_FILE, stack
+        ))?;
+        let pinned: Vec<String> = files.iter().map(|f| pin_images(f, digests)).collect();
+
+        let merged = compose::merge_files(&pinned)?;
+        let services = compose::service_images(&compose::parse(&merged)?);
+        for (service, image) in services {
+            let Some(image) = image else { continue };
+            if is_lockable(&image) {
+                anyhow::bail!(
+                    "Image '{}' of service '{}' in stack '{}' is not pinned by {}. \
+                     Run 'stack-sync lock {}'.",
+                    image,
+                    service,
+                    stack,
+                    LOCK_FILE,
+                    stack
+                );
+            }
+        }
+        Ok(pinned)
+    }
+}
+
+/// The compose files to deploy: pinned when a lock file is in use, as is
+/// otherwise.
+pub fn maybe_pin(lock: Option<&LockFile>, stack: &str, files: Vec<String>) -> Result<Vec<String>> {
+    match lock {
+        Some(lock) => lock.pin(stack, &files),
+        None => Ok(files),
+    }
+}
+
+/// Load the lock file for `--pinned` deploys.
+pub fn load_for(pinned: bool, configs: &[Config]) -> Result<Option<LockFile>> {
+    match configs.first() {
+        Some(config) if pinned => LockFile::load(&config.base_dir).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Whether an image reference can be resolved to a digest: images already
+/// pinned don't need it, and references built from variables can't be
+/// resolved without the env.
+pub fn is_lockable(image: &str) -> bool {
+    !image.contains('$') && ImageRef::parse(image).digest.is_none()
+}
+
+/// Append digests to `image:` values, editing only those lines so the
+/// deployed file keeps the formatting and comments of the local one.
+fn pin_images(compose: &str, digests: &BTreeMap<String, String>) -> String {
+    let mut pinned: Vec<String> = compose
+        .lines()
+        .map(|line| pin_line(line, digests).unwrap_or_else(|| line.to_string()))
+        .collect();
+    if compose.ends_with('\n') {
+        pinned.push(String::new());
+    }
+    pinned.join("\n")
+}
+
+fn pin_line(line: &str, digests: &BTreeMap<String, String>) -> Option<String> {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+    let rest = trimmed.strip_prefix("image:")?;
+    let (value, comment) = match rest.find(" #") {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let value = value.trim();
+    let (quote, image) = match value.chars().next() {
+        Some(q @ ('"' | '\'')) if value.len() > 1 && value.ends_with(q) => {
+            (q.to_string(), &value[1..value.len() - 1])
+        }
+        _ => (String::new(), value),
+    };
+    let digest = digests.get(image)?;
+    Some(format!(
+        "{}image: {}{}@{}{}{}",
+        indent, quote, image, digest, quote, comment
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_file() -> LockFile {
+        let mut lock = LockFile::default();
+        lock.stacks.insert(
+            "web".to_string(),
+            BTreeMap::from([
+                ("nginx:1.25".to_string(), "sha256:aaa".to_string()),
+                ("redis:7".to_string(), "sha256:bbb".to_string()),
+            ]),
+        );
+        lock
+    }
+
+    #[test]
+    fn test_pin_keeps_formatting() {
+        let compose = "services:\n  web:\n    image: nginx:1.25 # frontend\n  cache:\n    image: \"redis:7\"\n";
+        assert_eq!(
+            lock_file().pin("web", &[compose.to_string()]).unwrap(),
+            [
+                "services:\n  web:\n    image: nginx:1.25@sha256:aaa # frontend\n  cache:\n    image: \"redis:7@sha256:bbb\"\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pin_rejects_unlocked_image() {
+        let compose = "services:\n  web:\n    image: nginx:1.27\n";
+        let err = lock_file()
+            .pin("web", &[compose.to_string()])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("nginx:1.27"));
+    }
+
+    #[test]
+    fn test_pin_rejects_unlocked_stack() {
+        assert!(
+            lock_file()
+                .pin("other", &["services: {}\n".to_string()])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_pin_skips_unlockable_images() {
+        let compose =
+            "services:\n  app:\n    image: app:${TAG}\n  db:\n    image: postgres@sha256:ccc\n";
+        assert_eq!(
+            lock_file().pin("web", &[compose.to_string()]).unwrap(),
+            [compose]
+        );
+    }
+
+    #[test]
+    fn test_lock_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-lockfile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(LockFile::load(&dir).unwrap(), LockFile::default());
+
+        lock_file().save(&dir).unwrap();
+        let content = std::fs::read_to_string(LockFile::path(&dir)).unwrap();
+        assert!(content.starts_with("# Generated by"));
+        assert!(content.contains("[stacks.web]\n\"nginx:1.25\" = \"sha256:aaa\""));
+        assert_eq!(LockFile::load(&dir).unwrap(), lock_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
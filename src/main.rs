@@ -1,13 +1,31 @@
 use anyhow::{Ok, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use reporter::{DataFormat, OutputFormat, Reporter};
+use std::time::Duration;
 
 mod commands;
+mod compose;
 mod config;
+mod dotenv;
+mod encryption;
+mod lock;
+mod lockfile;
 mod portainer;
+mod registry;
 mod reporter;
+mod schema;
+mod secrets;
 mod ssh;
+mod stamp;
 mod styles;
+mod template;
 mod update;
+mod validate;
+
+/// Exit code for `status` when any stack differs from the local config, and
+/// for `outdated` when any image has an update, so scripts can tell these
+/// apart from errors (exit 1).
+const DRIFT_EXIT_CODE: i32 = 2;
 
 #[derive(Parser)]
 #[command(name = "stack-sync", about = "Deploy and manage Portainer stacks")]
@@ -28,6 +46,25 @@ enum Cli {
         /// Pull new images and force-recreate containers after syncing
         #[arg(long, short = 'r')]
         redeploy: bool,
+        /// Seconds to wait for another sync to release a stack's lock
+        #[arg(long, default_value_t = 0)]
+        lock_timeout: u64,
+        /// Compare compose files as YAML, ignoring key order and formatting
+        #[arg(long)]
+        semantic: bool,
+        /// Deploy images pinned to the digests in stack-sync.lock
+        #[arg(long)]
+        pinned: bool,
+        /// Deploy even when compose validation or the variable check fails
+        #[arg(long)]
+        skip_check: bool,
+        /// With --dry-run, run secret commands to compare secret values (by
+        /// default they keep their deployed values)
+        #[arg(long)]
+        resolve_secrets: bool,
+        /// Output format
+        #[arg(long, visible_alias = "format", value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
     },
     /// Show differences between local files and deployed stacks
     Diff {
@@ -36,6 +73,62 @@ enum Cli {
         /// Path to the config file
         #[arg(short = 'C', long, default_value = ".")]
         config: String,
+        /// Report compose changes by YAML path instead of line by line
+        #[arg(long)]
+        semantic: bool,
+        /// Compare with images pinned to the digests in stack-sync.lock
+        #[arg(long)]
+        pinned: bool,
+        /// Run secret commands to compare secret values (by default they
+        /// keep their deployed values)
+        #[arg(long)]
+        resolve_secrets: bool,
+        /// Output format
+        #[arg(long, visible_alias = "format", value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// Summarize whether each stack matches the local config
+    Status {
+        /// Stack names to check (default: all stacks)
+        stacks: Vec<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+        /// Compare with images pinned to the digests in stack-sync.lock
+        #[arg(long)]
+        pinned: bool,
+        /// Run secret commands to compare secret values (by default they
+        /// keep their deployed values)
+        #[arg(long)]
+        resolve_secrets: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DataFormat::Text)]
+        output: DataFormat,
+    },
+    /// Check compose variable references against each stack's env file
+    Check {
+        /// Stack names to check (default: all stacks)
+        stacks: Vec<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+        /// Run secret commands, checking that they succeed
+        #[arg(long)]
+        resolve_secrets: bool,
+        /// Output format
+        #[arg(long, visible_alias = "format", value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// Check registries for newer image tags and digests
+    Outdated {
+        /// Stack names to check (default: all stacks)
+        stacks: Vec<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+        /// Output format
+        #[arg(long, visible_alias = "format", value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
     },
     /// Show the state of a stack in Portainer
     View {
@@ -47,6 +140,9 @@ enum Cli {
         /// Show detailed stack information
         #[arg(short = 'V', long)]
         verbose: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DataFormat::Text)]
+        output: DataFormat,
     },
     /// Import a stack from Portainer into the local config
     Import {
@@ -58,6 +154,35 @@ enum Cli {
         /// Overwrite existing files
         #[arg(long)]
         force: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DataFormat::Text)]
+        output: DataFormat,
+    },
+    /// Resolve image tags to digests and write them to stack-sync.lock
+    Lock {
+        /// Stack names to lock (default: all stacks)
+        stacks: Vec<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+    },
+    /// Encrypt, decrypt or edit env files with age or SOPS
+    Env {
+        #[command(subcommand)]
+        command: EnvCommand,
+    },
+    /// Check the config files or show the effective config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Remove deployment locks left behind by an interrupted sync
+    Unlock {
+        /// Stack names to unlock (default: all stacks)
+        stacks: Vec<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
     },
     /// Initialize config files for stack-sync
     Init {
@@ -95,9 +220,91 @@ enum Cli {
     Version,
 }
 
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Report unknown keys, missing files, invalid stack names and keys
+    /// that don't apply to the deploy mode
+    Check {
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DataFormat::Text)]
+        output: DataFormat,
+    },
+    /// Print the config as resolved from all config files, secrets masked
+    Show {
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+        /// Show which file or env var each value came from
+        #[arg(long)]
+        origin: bool,
+        /// Show env values set in the config, except keys that look secret
+        /// or that the stack's env schema marks secret
+        #[arg(long)]
+        show_values: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DataFormat::Text)]
+        output: DataFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum EnvCommand {
+    /// Encrypt a plaintext env file (writes <FILE>.age, or <FILE>.sops with --sops)
+    Encrypt {
+        /// Env file to encrypt
+        file: String,
+        /// Encrypt with SOPS instead of age
+        #[arg(long)]
+        sops: bool,
+        /// Where to write the encrypted file
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+    },
+    /// Decrypt an encrypted env file to stdout
+    Decrypt {
+        /// Encrypted env file
+        file: String,
+        /// Write the plaintext to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+    },
+    /// Edit an encrypted env file in $VISUAL or $EDITOR
+    Edit {
+        /// Encrypted env file
+        file: String,
+        /// Path to the config file
+        #[arg(short = 'C', long, default_value = ".")]
+        config: String,
+    },
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let result = run(cli);
+    Reporter::finish();
+    result
+}
 
+/// Resolve secrets when a command needs them, or leave them unresolved the
+/// way a read-only command treats them.
+fn secrets_mode(resolve: bool, unresolved: secrets::Mode) -> secrets::Mode {
+    if resolve {
+        secrets::Mode::Resolve
+    } else {
+        unresolved
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
     match cli {
         Cli::Sync {
             stacks,
@@ -105,18 +312,131 @@ fn main() -> Result<()> {
             dry_run,
             verbose,
             redeploy,
-        } => commands::sync_command(&config, &stacks, dry_run, verbose, redeploy)?,
-        Cli::Diff { stacks, config } => commands::diff_command(&config, &stacks)?,
+            lock_timeout,
+            semantic,
+            pinned,
+            skip_check,
+            resolve_secrets,
+            output,
+        } => {
+            if output == OutputFormat::Markdown && !dry_run {
+                anyhow::bail!("The Markdown report previews changes; use it with --dry-run");
+            }
+            Reporter::set_output_format(output);
+            secrets::set_mode(secrets_mode(
+                !dry_run || resolve_secrets,
+                secrets::Mode::KeepDeployed,
+            ));
+            commands::sync_command(
+                &config,
+                &stacks,
+                &commands::SyncOptions {
+                    dry_run,
+                    verbose,
+                    redeploy,
+                    lock_timeout: Duration::from_secs(lock_timeout),
+                    semantic,
+                    pinned,
+                    skip_check,
+                },
+            )?
+        }
+        Cli::Diff {
+            stacks,
+            config,
+            semantic,
+            pinned,
+            resolve_secrets,
+            output,
+        } => {
+            Reporter::set_output_format(output);
+            secrets::set_mode(secrets_mode(resolve_secrets, secrets::Mode::KeepDeployed));
+            commands::diff_command(&config, &stacks, semantic, pinned)?
+        }
+        Cli::Status {
+            stacks,
+            config,
+            pinned,
+            resolve_secrets,
+            output,
+        } => {
+            Reporter::set_output_format(output.into());
+            secrets::set_mode(secrets_mode(resolve_secrets, secrets::Mode::KeepDeployed));
+            if commands::status_command(&config, &stacks, pinned)? {
+                Reporter::finish();
+                std::process::exit(DRIFT_EXIT_CODE);
+            }
+        }
+        Cli::Check {
+            stacks,
+            config,
+            resolve_secrets,
+            output,
+        } => {
+            Reporter::set_output_format(output);
+            secrets::set_mode(secrets_mode(resolve_secrets, secrets::Mode::CheckPresence));
+            commands::check_command(&config, &stacks)?
+        }
+        Cli::Outdated {
+            stacks,
+            config,
+            output,
+        } => {
+            Reporter::set_output_format(output);
+            if commands::outdated_command(&config, &stacks)? {
+                Reporter::finish();
+                std::process::exit(DRIFT_EXIT_CODE);
+            }
+        }
         Cli::View {
             stacks,
             config,
             verbose,
-        } => commands::view_command(&config, &stacks, verbose)?,
+            output,
+        } => {
+            Reporter::set_output_format(output.into());
+            commands::view_command(&config, &stacks, verbose)?
+        }
         Cli::Import {
             stack,
             config,
             force,
-        } => commands::import_command(&config, &stack, force)?,
+            output,
+        } => {
+            Reporter::set_output_format(output.into());
+            commands::import_command(&config, &stack, force)?
+        }
+        Cli::Lock { stacks, config } => commands::lock_command(&config, &stacks)?,
+        Cli::Env { command } => match command {
+            EnvCommand::Encrypt {
+                file,
+                sops,
+                output,
+                config,
+            } => commands::env_encrypt_command(&config, &file, sops, output.as_deref())?,
+            EnvCommand::Decrypt {
+                file,
+                output,
+                config,
+            } => commands::env_decrypt_command(&config, &file, output.as_deref())?,
+            EnvCommand::Edit { file, config } => commands::env_edit_command(&config, &file)?,
+        },
+        Cli::Config { command } => match command {
+            ConfigCommand::Check { config, output } => {
+                Reporter::set_output_format(output.into());
+                commands::config_check_command(&config)?
+            }
+            ConfigCommand::Show {
+                config,
+                origin,
+                show_values,
+                output,
+            } => {
+                Reporter::set_output_format(output.into());
+                commands::config_show_command(&config, origin, show_values)?
+            }
+        },
+        Cli::Unlock { stacks, config } => commands::unlock_command(&config, &stacks)?,
         Cli::Init {
             mode,
             portainer_api_key,
@@ -2,6 +2,22 @@ use anyhow::{Context, Result};
 use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::config::EnvVar;
+use crate::lock::{LockAttempt, LockHolder, LockInfo, LockRecord};
+use crate::stamp::DeployStamp;
+
+/// Prefix of the Portainer tags used as deployment lock records.
+const LOCK_TAG_PREFIX: &str = "stack-sync-lock";
+
+/// Why Portainer mode syncs without a lock when the API key can't create tags.
+const LOCK_NEEDS_ADMIN: &str = "creating Portainer tags needs an administrator API key";
+
+/// How often `try_lock` recreates its tag when it vanishes right away.
+const LOCK_ATTEMPTS: usize = 3;
+
+/// Env var holding the stack's deployment stamp as JSON. Portainer stacks
+/// have no free-form metadata, but their env is saved with the stack in the
+/// same request that deploys it.
+const STAMP_ENV_VAR: &str = "STACK_SYNC_STAMP";
 
 fn deserialize_null_as_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
 where
@@ -30,6 +46,21 @@ pub struct Stack {
     pub updated_by: String,
     #[serde(default, rename = "updateDate")]
     pub update_date: u64,
+    /// Taken out of `env` when the stack is read.
+    #[serde(skip)]
+    pub stamp: Option<DeployStamp>,
+}
+
+impl Stack {
+    /// Move the deployment stamp out of the env vars, so the env only holds
+    /// the stack's own variables.
+    fn take_stamp(mut self) -> Self {
+        if let Some(index) = self.env.iter().position(|v| v.name == STAMP_ENV_VAR) {
+            let var = self.env.remove(index);
+            self.stamp = serde_json::from_str(&var.value).ok();
+        }
+        self
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,6 +69,27 @@ pub struct StackFileResponse {
     pub stack_file_content: String,
 }
 
+/// The part of Docker's image inspect response we use.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageInspect {
+    #[serde(default)]
+    pub repo_digests: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Tag {
+    #[serde(rename = "ID")]
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateTagPayload {
+    pub name: String,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateStackPayload {
@@ -47,17 +99,29 @@ pub struct CreateStackPayload {
     pub env: Vec<EnvVar>,
 }
 
+/// The content `create_stack` and `update_stack` deploy.
+#[derive(Debug)]
+pub struct StackDeploy<'a> {
+    pub file_content: &'a str,
+    pub env: &'a [EnvVar],
+    /// Left out when the config turns off `portainer_stamp`.
+    pub stamp: Option<&'a DeployStamp>,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateStackPayload {
     pub stack_file_content: String,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Always sent: Portainer keeps the current env when it is left out, so
+    /// an empty list is what clears it.
     pub env: Vec<EnvVar>,
     pub prune: bool,
     pub pull_image: bool,
 }
 
+// Tests sit next to the payload types they mostly cover.
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use super::*;
 
@@ -102,6 +166,36 @@ mod tests {
         assert_eq!(stack.creation_date, 0);
     }
 
+    #[test]
+    fn test_stack_stamp_is_taken_out_of_env() {
+        let stamp = DeployStamp {
+            deployed_by: "alice".to_string(),
+            deployed_at: 1700000000,
+            machine: "laptop".to_string(),
+            git_commit: None,
+            compose_sha256: "00".to_string(),
+            env_hmac: None,
+            env_salt: String::new(),
+        };
+        let env = vec![EnvVar {
+            name: "FOO".to_string(),
+            value: "bar".to_string(),
+        }];
+        let json = serde_json::json!({
+            "Id": 1,
+            "Name": "test",
+            "EndpointId": 1,
+            "Type": 2,
+            "Status": 1,
+            "Env": with_stamp(&env, Some(&stamp)).unwrap(),
+        });
+        let stack = serde_json::from_value::<Stack>(json).unwrap().take_stamp();
+        assert_eq!(stack.env, env);
+        assert_eq!(stack.stamp, Some(stamp));
+        // With `portainer_stamp = false` the env is deployed as it is
+        assert_eq!(with_stamp(&env, None).unwrap(), env);
+    }
+
     #[test]
     fn test_stack_file_response_deserialize() {
         let json = r#"{"StackFileContent": "version: '3'\nservices:\n  web:\n    image: nginx"}"#;
@@ -148,7 +242,67 @@ mod tests {
         assert_eq!(json["stackFileContent"], "version: '3'");
         assert_eq!(json["prune"], false);
         assert_eq!(json["pullImage"], true);
-        assert!(json.get("env").is_none());
+        assert_eq!(json["env"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_tag_deserialize() {
+        let json = r#"{"ID": 7, "Name": "stack-sync-lock:web:alice@laptop:42:1700000000"}"#;
+        let tag: Tag = serde_json::from_str(json).unwrap();
+        assert_eq!(tag.id, 7);
+        assert!(tag.name.starts_with("stack-sync-lock:web:"));
+    }
+
+    #[test]
+    fn test_lock_tag_round_trip() {
+        let info = LockInfo {
+            owner: "alice".to_string(),
+            machine: "laptop".to_string(),
+            pid: 4242,
+            acquired: 1700000000,
+        };
+        let name = lock_tag_name("my-stack", &info);
+        assert_eq!(
+            name,
+            "stack-sync-lock:my-stack:alice@laptop:4242:1700000000"
+        );
+        assert_eq!(parse_lock_tag(&name), Some(info));
+    }
+
+    #[test]
+    fn test_lock_tag_age() {
+        let now = crate::lock::now();
+        let name = format!("stack-sync-lock:web:not a holder:{}", now - 30);
+        assert_eq!(parse_lock_tag(&name), None);
+        assert!(lock_tag_age(&name).is_some_and(|age| (30..35).contains(&age)));
+        assert_eq!(lock_tag_age("stack-sync-lock:web:garbage"), None);
+    }
+
+    #[test]
+    fn test_try_lock_without_tag_permission() {
+        use std::io::{BufRead, BufReader, Write};
+
+        // A Portainer that refuses to create tags, as it does for non-admins
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let client = PortainerClient::new(&host, "key");
+        let attempt = client.try_lock("web", &LockInfo::current()).unwrap();
+        assert!(matches!(
+            attempt,
+            LockAttempt::Unavailable(LOCK_NEEDS_ADMIN)
+        ));
     }
 
     #[test]
@@ -201,6 +355,12 @@ impl PortainerClient {
             .header("X-API-Key", &self.api_key)
     }
 
+    fn delete(&self, path: &str) -> ureq::RequestBuilder<ureq::typestate::WithoutBody> {
+        self.agent
+            .delete(&format!("{}{}", self.base_url, path))
+            .header("X-API-Key", &self.api_key)
+    }
+
     fn put(&self, path: &str) -> ureq::RequestBuilder<ureq::typestate::WithBody> {
         self.agent
             .put(&format!("{}{}", self.base_url, path))
@@ -215,7 +375,7 @@ impl PortainerClient {
             .body_mut()
             .read_json()
             .context("Failed to parse stacks response")?;
-        Ok(stacks)
+        Ok(stacks.into_iter().map(Stack::take_stamp).collect())
     }
 
     pub fn find_stack_by_name(&self, name: &str) -> Result<Option<Stack>> {
@@ -223,6 +383,24 @@ impl PortainerClient {
         Ok(stacks.into_iter().find(|s| s.name == name))
     }
 
+    /// The `RepoDigests` of an image on the endpoint's Docker host, via
+    /// Portainer's Docker API proxy. Returns `None` when the image isn't
+    /// present.
+    pub fn image_repo_digests(&self, endpoint_id: u64, image: &str) -> Result<Option<Vec<String>>> {
+        let path = format!("/endpoints/{}/docker/images/{}/json", endpoint_id, image);
+        match self.get(&path).call() {
+            Ok(mut response) => {
+                let inspect: ImageInspect = response
+                    .body_mut()
+                    .read_json()
+                    .context("Failed to parse image inspect response")?;
+                Ok(Some(inspect.repo_digests))
+            }
+            Err(ureq::Error::StatusCode(404)) => Ok(None),
+            Err(e) => Err(api_error("GET", &path, e)),
+        }
+    }
+
     pub fn get_stack_file(&self, id: u64) -> Result<String> {
         let path = format!("/stacks/{}/file", id);
         let resp: StackFileResponse = self
@@ -239,13 +417,12 @@ impl PortainerClient {
         &self,
         endpoint_id: u64,
         name: &str,
-        file_content: &str,
-        env: Vec<EnvVar>,
+        deploy: &StackDeploy,
     ) -> Result<Stack> {
         let payload = CreateStackPayload {
             name: name.to_string(),
-            stack_file_content: file_content.to_string(),
-            env,
+            stack_file_content: deploy.file_content.to_string(),
+            env: with_stamp(deploy.env, deploy.stamp)?,
         };
         let path = format!(
             "/stacks/create/standalone/string?endpointId={}",
@@ -258,21 +435,20 @@ impl PortainerClient {
             .body_mut()
             .read_json()
             .context("Failed to parse create stack response")?;
-        Ok(stack)
+        Ok(stack.take_stamp())
     }
 
     pub fn update_stack(
         &self,
         id: u64,
         endpoint_id: u64,
-        file_content: &str,
-        env: Vec<EnvVar>,
+        deploy: &StackDeploy,
         prune: bool,
         pull_image: bool,
     ) -> Result<Stack> {
         let payload = UpdateStackPayload {
-            stack_file_content: file_content.to_string(),
-            env,
+            stack_file_content: deploy.file_content.to_string(),
+            env: with_stamp(deploy.env, deploy.stamp)?,
             prune,
             pull_image,
         };
@@ -284,7 +460,7 @@ impl PortainerClient {
             .body_mut()
             .read_json()
             .context("Failed to parse update stack response")?;
-        Ok(stack)
+        Ok(stack.take_stamp())
     }
 
     pub fn start_stack(&self, id: u64, endpoint_id: u64) -> Result<Stack> {
@@ -296,7 +472,7 @@ impl PortainerClient {
             .body_mut()
             .read_json()
             .context("Failed to parse start stack response")?;
-        Ok(stack)
+        Ok(stack.take_stamp())
     }
 
     pub fn stop_stack(&self, id: u64, endpoint_id: u64) -> Result<Stack> {
@@ -308,6 +484,166 @@ impl PortainerClient {
             .body_mut()
             .read_json()
             .context("Failed to parse stop stack response")?;
-        Ok(stack)
+        Ok(stack.take_stamp())
     }
+
+    pub fn list_tags(&self) -> Result<Vec<Tag>> {
+        let tags: Vec<Tag> = self
+            .get("/tags")
+            .call()
+            .map_err(|e| api_error("GET", "/tags", e))?
+            .body_mut()
+            .read_json()
+            .context("Failed to parse tags response")?;
+        Ok(tags)
+    }
+
+    /// Create a lock tag. Returns `None` when the API key isn't allowed to
+    /// create tags.
+    fn create_lock_tag(&self, name: &str) -> Result<Option<Tag>> {
+        let payload = CreateTagPayload {
+            name: name.to_string(),
+        };
+        let mut response = match self.post("/tags").send_json(&payload) {
+            Ok(response) => response,
+            Err(ureq::Error::StatusCode(403)) => return Ok(None),
+            Err(e) => return Err(api_error("POST", "/tags", e)),
+        };
+        let tag: Tag = response
+            .body_mut()
+            .read_json()
+            .context("Failed to parse create tag response")?;
+        Ok(Some(tag))
+    }
+
+    pub fn delete_tag(&self, id: u64) -> Result<()> {
+        let path = format!("/tags/{}", id);
+        self.delete(&path)
+            .call()
+            .map_err(|e| api_error("DELETE", &path, e))?;
+        Ok(())
+    }
+
+    fn lock_tags(&self, stack: &str) -> Result<Vec<Tag>> {
+        let prefix = lock_tag_prefix(stack);
+        let mut tags: Vec<Tag> = self
+            .list_tags()?
+            .into_iter()
+            .filter(|t| t.name.starts_with(&prefix))
+            .collect();
+        tags.sort_by_key(|t| t.id);
+        Ok(tags)
+    }
+
+    /// Portainer has no atomic key-value store, so every contender creates its
+    /// own lock tag and the one with the lowest ID wins. Losers remove their
+    /// tag again. Only administrators can create tags, so other API keys
+    /// sync without a lock.
+    pub fn try_lock(&self, stack: &str, info: &LockInfo) -> Result<LockAttempt> {
+        for _ in 0..LOCK_ATTEMPTS {
+            let Some(ours) = self.create_lock_tag(&lock_tag_name(stack, info))? else {
+                return Ok(LockAttempt::Unavailable(LOCK_NEEDS_ADMIN));
+            };
+            let tags = self.lock_tags(stack)?;
+            match tags.first() {
+                Some(first) if first.id == ours.id => return Ok(LockAttempt::Acquired),
+                Some(winner) => {
+                    if tags.iter().any(|t| t.id == ours.id) {
+                        self.delete_tag(ours.id)?;
+                    }
+                    return Ok(LockAttempt::Held(LockHolder {
+                        info: parse_lock_tag(&winner.name),
+                        age: lock_tag_age(&winner.name),
+                        record: LockRecord::Tag(winner.id),
+                    }));
+                }
+                // Our tag was removed right away (a concurrent unlock or
+                // stale break), so there's no lock record: try again
+                None => {}
+            }
+        }
+        anyhow::bail!(
+            "Failed to take lock for stack '{}': the lock tag kept disappearing",
+            stack
+        )
+    }
+
+    /// Remove the lock tag `info` created, if it's still there.
+    pub fn release_lock(&self, stack: &str, info: &LockInfo) -> Result<()> {
+        let name = lock_tag_name(stack, info);
+        for tag in self.lock_tags(stack)?.iter().filter(|t| t.name == name) {
+            self.remove_lock_tag(tag.id)?;
+        }
+        Ok(())
+    }
+
+    /// Delete a lock tag. One that's already gone counts as removed, since
+    /// another contender may have broken the same stale lock.
+    pub fn remove_lock_tag(&self, id: u64) -> Result<()> {
+        let path = format!("/tags/{}", id);
+        match self.delete(&path).call() {
+            Ok(_) | Err(ureq::Error::StatusCode(404)) => Ok(()),
+            Err(e) => Err(api_error("DELETE", &path, e)),
+        }
+    }
+
+    /// Remove every lock tag for `stack`. Returns whether any were present.
+    pub fn force_unlock(&self, stack: &str) -> Result<bool> {
+        let tags = self.lock_tags(stack)?;
+        for tag in &tags {
+            self.delete_tag(tag.id)?;
+        }
+        Ok(!tags.is_empty())
+    }
+}
+
+/// The env vars to deploy, with the stamp added when there is one.
+fn with_stamp(env: &[EnvVar], stamp: Option<&DeployStamp>) -> Result<Vec<EnvVar>> {
+    let mut env = env.to_vec();
+    if let Some(stamp) = stamp {
+        env.push(EnvVar {
+            name: STAMP_ENV_VAR.to_string(),
+            value: serde_json::to_string(stamp)?,
+        });
+    }
+    Ok(env)
+}
+
+fn lock_tag_prefix(stack: &str) -> String {
+    format!("{}:{}:", LOCK_TAG_PREFIX, stack)
+}
+
+/// Encode lock ownership into a tag name, e.g.
+/// `stack-sync-lock:my-stack:alice@laptop:4242:1700000000`.
+fn lock_tag_name(stack: &str, info: &LockInfo) -> String {
+    format!(
+        "{}{}@{}:{}:{}",
+        lock_tag_prefix(stack),
+        info.owner,
+        info.machine,
+        info.pid,
+        info.acquired
+    )
+}
+
+/// Age of a lock tag from its trailing timestamp, which is readable even
+/// when the rest of the name isn't.
+fn lock_tag_age(name: &str) -> Option<u64> {
+    let (_, acquired) = name.rsplit_once(':')?;
+    Some(crate::lock::now().saturating_sub(acquired.parse().ok()?))
+}
+
+fn parse_lock_tag(name: &str) -> Option<LockInfo> {
+    let rest = name.strip_prefix(LOCK_TAG_PREFIX)?.strip_prefix(':')?;
+    let (_stack, holder) = rest.split_once(':')?;
+    let mut parts = holder.rsplitn(3, ':');
+    let acquired = parts.next()?.parse().ok()?;
+    let pid = parts.next()?.parse().ok()?;
+    let (owner, machine) = parts.next()?.split_once('@')?;
+    Some(LockInfo {
+        owner: owner.to_string(),
+        machine: machine.to_string(),
+        pid,
+        acquired,
+    })
 }
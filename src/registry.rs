@@ -0,0 +1,798 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::compose::ImageRef;
+use crate::stamp::sha256_hex;
+
+/// Where Docker Hub images are served from.
+const DOCKER_HUB: &str = "registry-1.docker.io";
+
+/// The server name `docker login` stores Docker Hub credentials under.
+const DOCKER_HUB_LOGIN: &str = "https://index.docker.io/v1/";
+
+/// Manifest types to accept, multi-arch indexes first so the digest matches
+/// what `docker pull` records in `RepoDigests`.
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+     application/vnd.docker.distribution.manifest.list.v2+json, \
+     application/vnd.docker.distribution.manifest.v2+json, \
+     application/vnd.oci.image.manifest.v1+json";
+
+/// Upper bound on paginated tag list requests per repository. A longer
+/// list is an error rather than silently cut short, since the newest tags
+/// could be on the pages left out.
+const MAX_TAG_PAGES: usize = 20;
+
+/// An image's registry host and repository path within it, e.g.
+/// `registry-1.docker.io` and `library/nginx` for `nginx:1.25`.
+#[derive(Debug, PartialEq)]
+pub struct RegistryImage {
+    pub registry: String,
+    pub repository: String,
+}
+
+impl RegistryImage {
+    /// Resolve a repository the way Docker does: the first path component
+    /// is a registry host when it has a dot or a port or is `localhost`;
+    /// otherwise the image is on Docker Hub, under `library/` when it has no
+    /// namespace.
+    pub fn from_ref(image: &ImageRef) -> Self {
+        let (registry, path) = match image.repository.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (normalize_registry(host), path.to_string())
+            }
+            _ => (DOCKER_HUB.to_string(), image.repository.clone()),
+        };
+        let repository = if registry == DOCKER_HUB && !path.contains('/') {
+            format!("library/{}", path)
+        } else {
+            path
+        };
+        Self {
+            registry,
+            repository,
+        }
+    }
+
+    /// Local registries are reached over plain HTTP, as Docker does by
+    /// default.
+    fn base_url(&self) -> String {
+        let host = self.registry.split(':').next().unwrap_or_default();
+        let scheme = match host {
+            "localhost" | "127.0.0.1" => "http",
+            _ => "https",
+        };
+        format!("{}://{}", scheme, self.registry)
+    }
+}
+
+impl fmt::Display for RegistryImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)
+    }
+}
+
+/// Map the names Docker uses for a registry (including the keys `docker
+/// login` writes to `config.json`) to the host its API is served from.
+fn normalize_registry(name: &str) -> String {
+    let host = name
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "docker.io" | "index.docker.io" => DOCKER_HUB.to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+}
+
+/// What `docker-credential-<helper> get` prints.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Credentials stored by `docker login`, by registry host.
+#[derive(Debug, Default)]
+struct DockerCredentials {
+    /// The base64 `user:password` pairs Docker stores in `config.json`,
+    /// ready for Basic auth.
+    auths: HashMap<String, String>,
+    /// Credential helper for registries without one of their own, from
+    /// `credsStore`.
+    store: Option<String>,
+    /// Credential helper per registry, from `credHelpers`.
+    helpers: HashMap<String, String>,
+}
+
+impl DockerCredentials {
+    /// The credential helper holding a registry's login, if any.
+    fn helper(&self, registry: &str) -> Option<&str> {
+        self.helpers
+            .get(registry)
+            .or(self.store.as_ref())
+            .map(String::as_str)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagList {
+    tags: Option<Vec<String>>,
+}
+
+/// Credentials stored by `docker login` in the Docker config.
+fn load_docker_credentials() -> DockerCredentials {
+    let dir = std::env::var("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".docker")));
+    let Ok(dir) = dir else {
+        return DockerCredentials::default();
+    };
+    std::fs::read_to_string(dir.join("config.json"))
+        .ok()
+        .map(|content| parse_docker_credentials(&content))
+        .unwrap_or_default()
+}
+
+fn parse_docker_credentials(content: &str) -> DockerCredentials {
+    let config: DockerConfig = serde_json::from_str(content).unwrap_or_default();
+    DockerCredentials {
+        auths: config
+            .auths
+            .into_iter()
+            .filter_map(|(name, auth)| Some((normalize_registry(&name), auth.auth?)))
+            .collect(),
+        store: config.creds_store.filter(|store| !store.is_empty()),
+        helpers: config
+            .cred_helpers
+            .into_iter()
+            .map(|(name, helper)| (normalize_registry(&name), helper))
+            .collect(),
+    }
+}
+
+/// Ask a credential helper program (`docker-credential-<helper>`) for a
+/// registry's login, as Basic auth credentials. Returns `None` when the
+/// helper has none stored.
+fn helper_credentials(program: &str, registry: &str) -> Result<Option<String>> {
+    use base64::Engine;
+    use std::io::Write;
+
+    let server = if registry == DOCKER_HUB {
+        DOCKER_HUB_LOGIN
+    } else {
+        registry
+    };
+    let mut child = Command::new(program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context(format!(
+            "Failed to run {}, the credential helper in the Docker config",
+            program
+        ))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(server.as_bytes())
+            .context(format!("Failed to write to {}", program))?;
+    }
+    let output = child
+        .wait_with_output()
+        .context(format!("Failed to wait for {}", program))?;
+    // Helpers exit with an error when they hold nothing for the server
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .context(format!("Failed to parse the output of {}", program))?;
+    if credentials.username == "<token>" {
+        bail!(
+            "{} holds an identity token for {}, which isn't supported. Log in with a \
+             password or access token instead.",
+            program,
+            registry
+        );
+    }
+    Ok(Some(base64::engine::general_purpose::STANDARD.encode(
+        format!("{}:{}", credentials.username, credentials.secret),
+    )))
+}
+
+/// A client for the Docker Registry HTTP API v2.
+pub struct RegistryClient {
+    agent: ureq::Agent,
+    credentials: DockerCredentials,
+    /// Credentials fetched from helpers, by registry, so each helper runs
+    /// once per registry.
+    helper_credentials: RefCell<HashMap<String, Option<String>>>,
+    /// Authorization headers obtained per repository, reused across requests.
+    tokens: RefCell<HashMap<String, String>>,
+}
+
+impl RegistryClient {
+    pub fn new() -> Self {
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build();
+        Self {
+            agent: ureq::Agent::new_with_config(config),
+            credentials: load_docker_credentials(),
+            helper_credentials: RefCell::new(HashMap::new()),
+            tokens: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// All tags of a repository.
+    pub fn tags(&self, image: &RegistryImage) -> Result<Vec<String>> {
+        let mut tags = Vec::new();
+        let mut next = Some(format!("/v2/{}/tags/list?n=1000", image.repository));
+        let mut pages = 0;
+        while let Some(path) = next.take() {
+            let mut response = self.call(image, false, &path)?;
+            next = header(&response, "link").and_then(|link| parse_next_link(&link));
+            let page: TagList = response
+                .body_mut()
+                .read_json()
+                .context(format!("Failed to parse tag list for {}", image))?;
+            tags.extend(page.tags.unwrap_or_default());
+            pages += 1;
+            if pages >= MAX_TAG_PAGES && next.is_some() {
+                bail!(
+                    "{} has more than {} pages of tags; not checking for newer tags",
+                    image,
+                    MAX_TAG_PAGES
+                );
+            }
+        }
+        Ok(tags)
+    }
+
+    /// The content digest a tag currently points to, e.g. `sha256:...`.
+    pub fn digest(&self, image: &RegistryImage, reference: &str) -> Result<String> {
+        let path = format!("/v2/{}/manifests/{}", image.repository, reference);
+        let response = self.call(image, true, &path)?;
+        if let Some(digest) = header(&response, "docker-content-digest") {
+            return Ok(digest);
+        }
+        // Some registries only send the digest header on GET; failing that,
+        // the digest is the hash of the manifest itself
+        let mut response = self.call(image, false, &path)?;
+        if let Some(digest) = header(&response, "docker-content-digest") {
+            return Ok(digest);
+        }
+        let manifest = response.body_mut().read_to_string().context(format!(
+            "Failed to read manifest for {}:{}",
+            image, reference
+        ))?;
+        Ok(format!("sha256:{}", sha256_hex(&manifest)))
+    }
+
+    /// Send a request, authenticating on a 401 challenge and retrying once.
+    fn call(
+        &self,
+        image: &RegistryImage,
+        head: bool,
+        path: &str,
+    ) -> Result<ureq::http::Response<ureq::Body>> {
+        let url = format!("{}{}", image.base_url(), path);
+        let method = if head { "HEAD" } else { "GET" };
+        let send = |auth: Option<&str>| {
+            let mut request = if head {
+                self.agent.head(&url)
+            } else {
+                self.agent.get(&url)
+            };
+            request = request.header("Accept", MANIFEST_ACCEPT);
+            if let Some(auth) = auth {
+                request = request.header("Authorization", auth);
+            }
+            request.call().context(format!("{} {} failed", method, url))
+        };
+
+        let key = image.to_string();
+        let cached = self.tokens.borrow().get(&key).cloned();
+        let mut response = send(cached.as_deref())?;
+        if response.status() == 401 {
+            let challenge = header(&response, "www-authenticate").context(format!(
+                "{} {} failed (HTTP 401) without an auth challenge",
+                method, url
+            ))?;
+            let auth = self.authorize(image, &challenge)?;
+            self.tokens.borrow_mut().insert(key, auth.clone());
+            response = send(Some(&auth))?;
+        }
+
+        let status = response.status().as_u16();
+        if !(200..300).contains(&status) {
+            bail!("{} {} failed (HTTP {})", method, url, status);
+        }
+        Ok(response)
+    }
+
+    /// Basic auth credentials for a registry: from its credential helper
+    /// when the Docker config names one, or else from `config.json`.
+    fn basic_credentials(&self, registry: &str) -> Result<Option<String>> {
+        if let Some(helper) = self.credentials.helper(registry) {
+            if let Some(cached) = self.helper_credentials.borrow().get(registry) {
+                return Ok(cached.clone());
+            }
+            let program = format!("docker-credential-{}", helper);
+            let credentials = helper_credentials(&program, registry)?;
+            self.helper_credentials
+                .borrow_mut()
+                .insert(registry.to_string(), credentials.clone());
+            if credentials.is_some() {
+                return Ok(credentials);
+            }
+        }
+        Ok(self.credentials.auths.get(registry).cloned())
+    }
+
+    /// Answer a `WWW-Authenticate` challenge with an Authorization header,
+    /// using `docker login` credentials when there are any.
+    fn authorize(&self, image: &RegistryImage, challenge: &str) -> Result<String> {
+        let (scheme, params) = parse_challenge(challenge);
+        let basic = self.basic_credentials(&image.registry)?;
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => basic.map(|c| format!("Basic {}", c)).context(format!(
+                "Registry {} requires credentials. Run 'docker login {}'.",
+                image.registry, image.registry
+            )),
+            "bearer" => {
+                let realm = params
+                    .get("realm")
+                    .context("Registry auth challenge has no realm")?;
+                let scope = params
+                    .get("scope")
+                    .cloned()
+                    .unwrap_or_else(|| format!("repository:{}:pull", image.repository));
+                let mut request = self.agent.get(realm).query("scope", &scope);
+                if let Some(service) = params.get("service") {
+                    request = request.query("service", service);
+                }
+                if let Some(credentials) = basic {
+                    request = request.header("Authorization", &format!("Basic {}", credentials));
+                }
+                let mut response = request
+                    .call()
+                    .context(format!("Failed to request a token from {}", realm))?;
+                if response.status() != 200 {
+                    bail!(
+                        "Token request to {} failed (HTTP {}). Check 'docker login {}'.",
+                        realm,
+                        response.status().as_u16(),
+                        image.registry
+                    );
+                }
+                let token: TokenResponse = response
+                    .body_mut()
+                    .read_json()
+                    .context("Failed to parse registry token response")?;
+                let token = token
+                    .token
+                    .or(token.access_token)
+                    .context("Registry token response has no token")?;
+                Ok(format!("Bearer {}", token))
+            }
+            other => bail!("Unsupported registry auth scheme: {}", other),
+        }
+    }
+}
+
+fn header(response: &ureq::http::Response<ureq::Body>, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// Split `Bearer realm="https://auth",service="registry",scope="a,b"` into
+/// the scheme and its parameters. Quoted values may contain commas.
+fn parse_challenge(challenge: &str) -> (String, HashMap<String, String>) {
+    let (scheme, rest) = challenge
+        .trim()
+        .split_once(' ')
+        .unwrap_or((challenge.trim(), ""));
+    let mut params = HashMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+        let value: String = if chars.peek() == Some(&'"') {
+            chars.next();
+            chars.by_ref().take_while(|c| *c != '"').collect()
+        } else {
+            chars.by_ref().take_while(|c| *c != ',').collect()
+        };
+        params.insert(key.trim().to_ascii_lowercase(), value);
+    }
+    (scheme.to_string(), params)
+}
+
+/// The path of the `rel="next"` page from a `Link` header.
+fn parse_next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (target, params) = part.split_once(';')?;
+        if !params.contains("rel=\"next\"") {
+            return None;
+        }
+        let target = target.trim().trim_start_matches('<').trim_end_matches('>');
+        // Registries send either a path or an absolute URL
+        Some(target[target.find("/v2/")?..].to_string())
+    })
+}
+
+/// A tag that reads as a version: `1.25`, `v2.1.0`, `1.25-alpine`.
+#[derive(Debug, PartialEq)]
+struct TagVersion<'a> {
+    prefix: &'a str,
+    numbers: Vec<u64>,
+    suffix: &'a str,
+}
+
+fn parse_tag_version(tag: &str) -> Option<TagVersion<'_>> {
+    let (prefix, rest) = match tag.strip_prefix('v') {
+        Some(rest) => ("v", rest),
+        None => ("", tag),
+    };
+    let (core, suffix) = match rest.find('-') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let numbers = core
+        .split('.')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    (1..=4).contains(&numbers.len()).then_some(TagVersion {
+        prefix,
+        numbers,
+        suffix,
+    })
+}
+
+/// Whether a tag reads as a version that newer tags can be looked up for.
+pub fn is_version_tag(tag: &str) -> bool {
+    parse_tag_version(tag).is_some()
+}
+
+/// The highest tag newer than `current` with the same shape: same `v`
+/// prefix, number of components and suffix. This keeps `1.25-alpine` on
+/// `-alpine` tags and doesn't suggest `2` for `1.25`.
+pub fn newer_tag(current: &str, tags: &[String]) -> Option<String> {
+    let current = parse_tag_version(current)?;
+    tags.iter()
+        .filter_map(|tag| parse_tag_version(tag).map(|version| (version, tag)))
+        .filter(|(v, _)| {
+            v.prefix == current.prefix
+                && v.suffix == current.suffix
+                && v.numbers.len() == current.numbers.len()
+                && v.numbers > current.numbers
+        })
+        .max_by(|(a, _), (b, _)| a.numbers.cmp(&b.numbers))
+        .map(|(_, tag)| tag.clone())
+}
+
+/// What `outdated` found for one service's image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageStatus {
+    pub service: String,
+    pub image: String,
+    /// A newer version tag, e.g. `1.27` for `1.25`.
+    pub newer_tag: Option<String>,
+    /// The registry's current digest for the tag, when it differs from the
+    /// one deployed.
+    pub new_digest: Option<String>,
+    /// Why the image couldn't be checked.
+    pub error: Option<String>,
+}
+
+impl ImageStatus {
+    pub fn is_outdated(&self) -> bool {
+        self.newer_tag.is_some() || self.new_digest.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// A registry on 127.0.0.1 that requires a bearer token, splits the tag
+    /// list of `app` over two pages and serves digests on HEAD. Every
+    /// request is recorded as `METHOD path auth`.
+    fn stub_registry() -> (RegistryImage, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        let realm = format!("http://{}/token", host);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut auth = "-".to_string();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("authorization")
+                    {
+                        auth = value.trim().to_string();
+                    }
+                }
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock()
+                    .unwrap()
+                    .push(format!("{} {} {}", method, path, auth));
+
+                let authorized = auth == "Bearer t0k";
+                let (status, headers, body) = match path {
+                    p if p.starts_with("/token?") => {
+                        ("200 OK", String::new(), r#"{"token":"t0k"}"#)
+                    }
+                    _ if !authorized => (
+                        "401 Unauthorized",
+                        format!(
+                            "WWW-Authenticate: Bearer realm=\"{}\",service=\"stub\",scope=\"repository:app:pull\"\r\n",
+                            realm
+                        ),
+                        "",
+                    ),
+                    "/v2/app/tags/list?n=1000" => (
+                        "200 OK",
+                        "Link: </v2/app/tags/list?n=1000&last=1.1>; rel=\"next\"\r\n".to_string(),
+                        r#"{"tags":["1.0","1.1"]}"#,
+                    ),
+                    "/v2/app/tags/list?n=1000&last=1.1" => {
+                        ("200 OK", String::new(), r#"{"tags":["1.2"]}"#)
+                    }
+                    p if p.starts_with("/v2/many/tags/list") => (
+                        "200 OK",
+                        "Link: </v2/many/tags/list?n=1000&last=x>; rel=\"next\"\r\n".to_string(),
+                        r#"{"tags":["x"]}"#,
+                    ),
+                    "/v2/app/manifests/1.2" => (
+                        "200 OK",
+                        "Docker-Content-Digest: sha256:abc\r\n".to_string(),
+                        "",
+                    ),
+                    _ => ("404 Not Found", String::new(), ""),
+                };
+                let body = if method == "HEAD" { "" } else { body };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        let image = RegistryImage {
+            registry: host,
+            repository: "app".to_string(),
+        };
+        (image, requests)
+    }
+
+    fn stub_client() -> RegistryClient {
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build();
+        RegistryClient {
+            agent: ureq::Agent::new_with_config(config),
+            credentials: DockerCredentials::default(),
+            helper_credentials: RefCell::new(HashMap::new()),
+            tokens: RefCell::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn test_registry_client_against_stub() {
+        let (image, requests) = stub_registry();
+        let client = stub_client();
+
+        assert_eq!(client.tags(&image).unwrap(), tags(&["1.0", "1.1", "1.2"]));
+        assert_eq!(client.digest(&image, "1.2").unwrap(), "sha256:abc");
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "GET /v2/app/tags/list?n=1000 -".to_string(),
+                "GET /token?scope=repository%3Aapp%3Apull&service=stub -".to_string(),
+                "GET /v2/app/tags/list?n=1000 Bearer t0k".to_string(),
+                "GET /v2/app/tags/list?n=1000&last=1.1 Bearer t0k".to_string(),
+                "HEAD /v2/app/manifests/1.2 Bearer t0k".to_string(),
+            ]
+        );
+
+        let many = RegistryImage {
+            registry: image.registry.clone(),
+            repository: "many".to_string(),
+        };
+        let err = client.tags(&many).unwrap_err();
+        assert!(err.to_string().contains("more than 20 pages of tags"));
+    }
+
+    fn registry_image(reference: &str) -> RegistryImage {
+        RegistryImage::from_ref(&ImageRef::parse(reference))
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_registry_image_docker_hub() {
+        let image = registry_image("nginx:1.25");
+        assert_eq!(image.registry, DOCKER_HUB);
+        assert_eq!(image.repository, "library/nginx");
+        assert_eq!(image.base_url(), "https://registry-1.docker.io");
+
+        let image = registry_image("grafana/grafana:11.0.0");
+        assert_eq!(image.repository, "grafana/grafana");
+
+        let image = registry_image("docker.io/nginx");
+        assert_eq!(image.registry, DOCKER_HUB);
+        assert_eq!(image.repository, "library/nginx");
+    }
+
+    #[test]
+    fn test_registry_image_custom_registry() {
+        let image = registry_image("ghcr.io/org/app:1.0");
+        assert_eq!(image.registry, "ghcr.io");
+        assert_eq!(image.repository, "org/app");
+
+        let image = registry_image("localhost:5000/app:1.0");
+        assert_eq!(image.registry, "localhost:5000");
+        assert_eq!(image.repository, "app");
+        assert_eq!(image.base_url(), "http://localhost:5000");
+    }
+
+    #[test]
+    fn test_parse_docker_credentials() {
+        let content = r#"{"auths": {
+            "https://index.docker.io/v1/": {"auth": "aHViOnB3"},
+            "ghcr.io": {"auth": "Z2g6cHc="},
+            "empty.example.com": {}
+        }}"#;
+        let credentials = parse_docker_credentials(content);
+        assert_eq!(credentials.auths[DOCKER_HUB], "aHViOnB3");
+        assert_eq!(credentials.auths["ghcr.io"], "Z2g6cHc=");
+        assert!(!credentials.auths.contains_key("empty.example.com"));
+        assert_eq!(credentials.helper("ghcr.io"), None);
+    }
+
+    #[test]
+    fn test_parse_docker_credential_helpers() {
+        let content = r#"{
+            "auths": {"https://index.docker.io/v1/": {}},
+            "credsStore": "osxkeychain",
+            "credHelpers": {"https://gcr.io": "gcloud"}
+        }"#;
+        let credentials = parse_docker_credentials(content);
+        assert_eq!(credentials.helper(DOCKER_HUB), Some("osxkeychain"));
+        assert_eq!(credentials.helper("gcr.io"), Some("gcloud"));
+    }
+
+    #[test]
+    fn test_helper_credentials() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A helper holding a Docker Hub login only
+        let dir = std::env::temp_dir().join(format!("stack-sync-helper-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let helper = dir.join("docker-credential-stub");
+        std::fs::write(
+            &helper,
+            "#!/bin/sh\nread -r server\n[ \"$server\" = https://index.docker.io/v1/ ] || exit 1\n\
+             echo '{\"ServerURL\":\"hub\",\"Username\":\"hub\",\"Secret\":\"pw\"}'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let helper = helper.to_str().unwrap();
+
+        assert_eq!(
+            helper_credentials(helper, DOCKER_HUB).unwrap().as_deref(),
+            Some("aHViOnB3")
+        );
+        assert_eq!(helper_credentials(helper, "ghcr.io").unwrap(), None);
+        let err = helper_credentials("docker-credential-missing", "ghcr.io").unwrap_err();
+        assert!(err.to_string().contains("docker-credential-missing"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull,push""#,
+        );
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/nginx:pull,push");
+
+        let (scheme, params) = parse_challenge(r#"Basic realm="Registry""#);
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params["realm"], "Registry");
+    }
+
+    #[test]
+    fn test_parse_next_link() {
+        assert_eq!(
+            parse_next_link(r#"</v2/app/tags/list?last=1.9&n=1000>; rel="next""#),
+            Some("/v2/app/tags/list?last=1.9&n=1000".to_string())
+        );
+        assert_eq!(
+            parse_next_link(r#"<https://ghcr.io/v2/org/app/tags/list?last=b>; rel="next""#),
+            Some("/v2/org/app/tags/list?last=b".to_string())
+        );
+        assert_eq!(parse_next_link(r#"</v2/app/tags/list>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn test_newer_tag() {
+        let available = tags(&["1.24", "1.25", "1.26", "1.27", "1.27-alpine", "2", "latest"]);
+        assert_eq!(newer_tag("1.25", &available), Some("1.27".to_string()));
+        assert_eq!(newer_tag("1.27", &available), None);
+        assert_eq!(
+            newer_tag("1.25-alpine", &available),
+            Some("1.27-alpine".to_string())
+        );
+        assert_eq!(newer_tag("latest", &available), None);
+    }
+
+    #[test]
+    fn test_newer_tag_compares_numerically() {
+        let available = tags(&["v1.9.0", "v1.10.0", "1.11.0"]);
+        assert_eq!(newer_tag("v1.8.0", &available), Some("v1.10.0".to_string()));
+    }
+}
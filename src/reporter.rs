@@ -1,6 +1,13 @@
+use console::{Alignment, pad_str};
 use owo_colors::{OwoColorize, Style};
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::sync::Mutex;
 
+use crate::compose::{ComposeChange, ImageChange, VariableCheck};
+use crate::registry::ImageStatus;
+use crate::secrets;
 use crate::styles::{AnsiPadding, AppStyles};
 
 #[derive(Debug, PartialEq)]
@@ -10,6 +17,509 @@ pub enum EnvChange {
     Changed(String),
 }
 
+/// How command results are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable, coloured text
+    #[default]
+    Text,
+    /// A JSON array with one object per stack, printed when the command finishes
+    Json,
+    /// One JSON event per line, printed as it happens
+    Ndjson,
+    /// A Markdown report for pull request comments, printed when the command finishes
+    Markdown,
+}
+
+/// Output formats for commands whose results don't make a Markdown report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum DataFormat {
+    /// Human-readable, coloured text
+    #[default]
+    Text,
+    /// A JSON array with one object per stack, printed when the command finishes
+    Json,
+    /// One JSON event per line, printed as it happens
+    Ndjson,
+}
+
+impl From<DataFormat> for OutputFormat {
+    fn from(format: DataFormat) -> Self {
+        match format {
+            DataFormat::Text => OutputFormat::Text,
+            DataFormat::Json => OutputFormat::Json,
+            DataFormat::Ndjson => OutputFormat::Ndjson,
+        }
+    }
+}
+
+/// Collects results for the structured output formats. Each stack gets one
+/// object; actions overwrite its `action` field and details are merged in.
+struct StructuredOutput {
+    format: OutputFormat,
+    stacks: Vec<Map<String, Value>>,
+    /// Index of the stack that detail calls without a name belong to.
+    current: Option<usize>,
+}
+
+impl StructuredOutput {
+    const fn new() -> Self {
+        Self {
+            format: OutputFormat::Text,
+            stacks: Vec::new(),
+            current: None,
+        }
+    }
+
+    fn stack_index(&mut self, name: &str) -> usize {
+        let index = match self.stacks.iter().position(|s| s["stack"] == name) {
+            Some(index) => index,
+            None => {
+                let mut stack = Map::new();
+                stack.insert("stack".to_string(), name.into());
+                self.stacks.push(stack);
+                self.stacks.len() - 1
+            }
+        };
+        self.current = Some(index);
+        index
+    }
+
+    /// Record an event. `action` is set on the stack object when present.
+    /// Returns the NDJSON line to print, if the format streams events.
+    fn record(
+        &mut self,
+        event: &str,
+        stack: Option<&str>,
+        action: bool,
+        fields: Value,
+    ) -> Option<String> {
+        let fields = match fields {
+            Value::Object(map) => map,
+            _ => Map::new(),
+        };
+        let index = match stack {
+            Some(name) => Some(self.stack_index(name)),
+            None => self.current,
+        };
+
+        if self.format == OutputFormat::Ndjson {
+            let mut line = Map::new();
+            line.insert("event".to_string(), event.into());
+            if let Some(index) = index {
+                line.insert("stack".to_string(), self.stacks[index]["stack"].clone());
+            }
+            line.extend(fields);
+            return Some(Value::Object(line).to_string());
+        }
+
+        if let Some(index) = index {
+            let stack = &mut self.stacks[index];
+            if action {
+                stack.insert("action".to_string(), event.into());
+            }
+            stack.extend(fields);
+        }
+        None
+    }
+
+    /// The document to print once the command has finished, for the formats
+    /// that buffer output.
+    fn finish(&mut self) -> Option<String> {
+        let stacks = std::mem::take(&mut self.stacks);
+        match self.format {
+            OutputFormat::Json => serde_json::to_string_pretty(&stacks).ok(),
+            OutputFormat::Markdown => Some(render_markdown(&stacks)),
+            OutputFormat::Text | OutputFormat::Ndjson => None,
+        }
+    }
+}
+
+/// Render buffered stack objects as Markdown: a heading and status per stack,
+/// the compose diff in a fenced block, and env changes by name only.
+fn render_markdown(stacks: &[Map<String, Value>]) -> String {
+    let mut out = Vec::new();
+    for stack in stacks {
+        let name = stack["stack"].as_str().unwrap_or_default();
+        let status = stack
+            .get("status")
+            .or_else(|| stack.get("action"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+        out.push(format!("### `{}`", name));
+        out.push(String::new());
+        out.push(format!("**{}**", humanize(status)));
+
+        let image_changes: Vec<String> = stack
+            .get("image_changes")
+            .and_then(|c| c.as_array())
+            .into_iter()
+            .flatten()
+            .map(|c| {
+                let service = c["service"].as_str().unwrap_or_default();
+                let old = c["old"].as_str();
+                let new = c["new"].as_str();
+                match (c["change"].as_str().unwrap_or_default(), old, new) {
+                    ("bumped", Some(old), Some(new)) => format!(
+                        "- `{}`: `{}` {} → {}",
+                        service,
+                        c["repository"].as_str().unwrap_or_default(),
+                        old,
+                        new
+                    ),
+                    ("added", _, new) => {
+                        format!("- `{}` added: `{}`", service, new.unwrap_or("no image"))
+                    }
+                    ("removed", old, _) => {
+                        format!("- `{}` removed: `{}`", service, old.unwrap_or("no image"))
+                    }
+                    (_, old, new) => format!(
+                        "- `{}`: `{}` → `{}`",
+                        service,
+                        old.unwrap_or_default(),
+                        new.unwrap_or_default()
+                    ),
+                }
+            })
+            .collect();
+        if !image_changes.is_empty() {
+            out.push(String::new());
+            out.push("Image changes:".to_string());
+            out.push(String::new());
+            out.extend(image_changes);
+        }
+
+        let images: Vec<String> = stack
+            .get("images")
+            .and_then(|i| i.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|i| {
+                let service = i["service"].as_str().unwrap_or_default();
+                let image = i["image"].as_str().unwrap_or_default();
+                if let Some(error) = i["error"].as_str() {
+                    return Some(format!(
+                        "- `{}`: `{}` could not be checked: {}",
+                        service, image, error
+                    ));
+                }
+                let mut updates = Vec::new();
+                if let Some(tag) = i["newer_tag"].as_str() {
+                    updates.push(format!("`{}` available", tag));
+                }
+                if let Some(digest) = i["new_digest"].as_str() {
+                    updates.push(format!("new digest `{}`", digest));
+                }
+                (!updates.is_empty())
+                    .then(|| format!("- `{}`: `{}`, {}", service, image, updates.join(", ")))
+            })
+            .collect();
+        if !images.is_empty() {
+            out.push(String::new());
+            out.push("Images:".to_string());
+            out.push(String::new());
+            out.extend(images);
+        }
+
+        let lines: Vec<&str> = stack
+            .get("hunks")
+            .and_then(|h| h.as_array())
+            .into_iter()
+            .flatten()
+            .flat_map(|hunk| {
+                std::iter::once(&hunk["header"])
+                    .chain(hunk["lines"].as_array().into_iter().flatten())
+                    .filter_map(|l| l.as_str())
+                    .filter(|l| !l.is_empty())
+            })
+            .collect();
+        if !lines.is_empty() {
+            let fence = code_fence(&lines);
+            out.push(String::new());
+            out.push(format!("{}diff", fence));
+            out.extend(lines.iter().map(|l| l.to_string()));
+            out.push(fence);
+        }
+
+        let compose_changes: Vec<String> = stack
+            .get("compose_changes")
+            .and_then(|c| c.as_array())
+            .into_iter()
+            .flatten()
+            .map(|c| {
+                let path = c["path"].as_str().unwrap_or_default();
+                match (c["old"].as_str(), c["new"].as_str()) {
+                    (Some(old), Some(new)) => format!("- `{}`: `{}` → `{}`", path, old, new),
+                    (None, Some(new)) => format!("- `{}` added: `{}`", path, new),
+                    (Some(old), None) => format!("- `{}` removed: `{}`", path, old),
+                    (None, None) => format!("- `{}`", path),
+                }
+            })
+            .collect();
+        if !compose_changes.is_empty() {
+            out.push(String::new());
+            out.push("Compose changes:".to_string());
+            out.push(String::new());
+            out.extend(compose_changes);
+        }
+
+        if let Some(changes) = stack.get("env_changes") {
+            let mut items = Vec::new();
+            for (kind, prefix) in [("added", '+'), ("removed", '-'), ("changed", '~')] {
+                for name in changes[kind].as_array().into_iter().flatten() {
+                    items.push(format!(
+                        "- `{} {}` {}",
+                        prefix,
+                        name.as_str().unwrap_or_default(),
+                        kind
+                    ));
+                }
+            }
+            if !items.is_empty() {
+                out.push(String::new());
+                out.push("Env changes (values hidden):".to_string());
+                out.push(String::new());
+                out.extend(items);
+            }
+        }
+        if let Some(changes) = stack.get("file_changes") {
+            let mut items = Vec::new();
+            for kind in ["added", "removed", "changed"] {
+                for path in changes[kind].as_array().into_iter().flatten() {
+                    items.push(format!(
+                        "- `{}` {}",
+                        path.as_str().unwrap_or_default(),
+                        kind
+                    ));
+                }
+            }
+            if !items.is_empty() {
+                out.push(String::new());
+                out.push("File changes:".to_string());
+                out.push(String::new());
+                out.extend(items);
+            }
+        }
+        if let Some(variables) = stack.get("variables") {
+            let mut items = Vec::new();
+            for (kind, text) in [
+                ("missing", "not set, no default"),
+                ("defaulted", "not set, using its default"),
+                ("unused", "set but never used"),
+            ] {
+                for name in variables[kind].as_array().into_iter().flatten() {
+                    items.push(format!(
+                        "- `{}` {}",
+                        name.as_str().unwrap_or_default(),
+                        text
+                    ));
+                }
+            }
+            for message in variables["invalid"].as_array().into_iter().flatten() {
+                items.push(format!("- {}", message.as_str().unwrap_or_default()));
+            }
+            if !items.is_empty() {
+                out.push(String::new());
+                out.push("Variables:".to_string());
+                out.push(String::new());
+                out.extend(items);
+            }
+        }
+        if let Some(errors) = stack.get("compose_errors").and_then(|e| e.as_array()) {
+            out.push(String::new());
+            out.push("Compose errors:".to_string());
+            out.push(String::new());
+            for error in errors {
+                out.push(format!("- `{}`", error.as_str().unwrap_or_default()));
+            }
+        }
+        out.push(String::new());
+    }
+    out.join("\n")
+}
+
+fn variable_check_value(check: &VariableCheck) -> Value {
+    json!({
+        "missing": check.missing,
+        "defaulted": check.defaulted,
+        "unused": check.unused,
+        "invalid": check.invalid,
+    })
+}
+
+/// `would_update` -> `Would Update`
+fn humanize(key: &str) -> String {
+    key.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// A backtick fence longer than any backtick run in the content, so compose
+/// files containing fences can't break out of the code block.
+fn code_fence(lines: &[&str]) -> String {
+    let longest = lines
+        .iter()
+        .flat_map(|l| l.split(|c| c != '`'))
+        .map(|run| run.len())
+        .max()
+        .unwrap_or_default();
+    "`".repeat(longest.max(2) + 1)
+}
+
+static OUTPUT: Mutex<StructuredOutput> = Mutex::new(StructuredOutput::new());
+
+/// Portainer ids are numeric and SSH mode uses the host name; keep numbers as
+/// numbers in structured output.
+fn id_value(id: &dyn Display) -> Value {
+    let id = id.to_string();
+    id.parse::<u64>()
+        .map(Value::from)
+        .unwrap_or(Value::String(id))
+}
+
+/// Group unified-diff lines into hunks, each starting at an `@@` header.
+fn diff_hunks(lines: &[String]) -> Value {
+    let mut hunks: Vec<Value> = Vec::new();
+    for line in lines {
+        if line.starts_with('@') {
+            hunks.push(json!({ "header": line, "lines": [] }));
+            continue;
+        }
+        if hunks.is_empty() {
+            hunks.push(json!({ "header": "", "lines": [] }));
+        }
+        if let Some(Value::Array(hunk_lines)) = hunks.last_mut().map(|h| &mut h["lines"]) {
+            hunk_lines.push(line.clone().into());
+        }
+    }
+    Value::Array(hunks)
+}
+
+/// Structural compose changes as `{path, old, new}` objects; `old` is absent
+/// for additions and `new` for removals.
+fn compose_changes_value(changes: &[ComposeChange]) -> Value {
+    changes
+        .iter()
+        .map(|change| match change {
+            ComposeChange::Added { path, value } => json!({ "path": path, "new": value }),
+            ComposeChange::Removed { path, value } => json!({ "path": path, "old": value }),
+            ComposeChange::Changed { path, old, new } => {
+                json!({ "path": path, "old": old, "new": new })
+            }
+        })
+        .collect()
+}
+
+/// Image changes as `{service, change, old, new}` objects, plus `repository`
+/// for tag bumps where `old` and `new` are versions.
+fn image_changes_value(changes: &[ImageChange]) -> Value {
+    changes
+        .iter()
+        .map(|change| match change {
+            ImageChange::Bumped {
+                service,
+                repository,
+                old,
+                new,
+            } => json!({
+                "service": service,
+                "change": "bumped",
+                "repository": repository,
+                "old": old,
+                "new": new,
+            }),
+            ImageChange::Replaced { service, old, new } => {
+                json!({ "service": service, "change": "replaced", "old": old, "new": new })
+            }
+            ImageChange::ServiceAdded { service, image } => {
+                json!({ "service": service, "change": "added", "new": image })
+            }
+            ImageChange::ServiceRemoved { service, image } => {
+                json!({ "service": service, "change": "removed", "old": image })
+            }
+        })
+        .collect()
+}
+
+/// Image check results, omitting fields that don't apply.
+fn image_statuses_value(statuses: &[ImageStatus]) -> Value {
+    statuses
+        .iter()
+        .map(|status| {
+            let mut value = json!({ "service": status.service, "image": status.image });
+            for (key, field) in [
+                ("newer_tag", &status.newer_tag),
+                ("new_digest", &status.new_digest),
+                ("error", &status.error),
+            ] {
+                if let Some(field) = field {
+                    value[key] = field.clone().into();
+                }
+            }
+            value
+        })
+        .collect()
+}
+
+/// Env changes grouped by kind, names only.
+fn env_changes_value(changes: &[EnvChange]) -> Value {
+    let names = |pick: fn(&EnvChange) -> Option<&String>| -> Vec<&String> {
+        changes.iter().filter_map(pick).collect()
+    };
+    json!({
+        "added": names(|c| match c { EnvChange::Added(n) => Some(n), _ => None }),
+        "removed": names(|c| match c { EnvChange::Removed(n) => Some(n), _ => None }),
+        "changed": names(|c| match c { EnvChange::Changed(n) => Some(n), _ => None }),
+    })
+}
+
+/// Where a stack stands relative to the local config, as shown by `status`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StackStatus {
+    InSync,
+    /// Local files differ from what was last deployed.
+    LocalChanged,
+    /// The remote was changed outside of stack-sync since the last deploy.
+    RemoteDrifted,
+    Missing,
+    Stopped,
+    Disabled,
+}
+
+impl StackStatus {
+    pub fn is_drift(&self) -> bool {
+        !matches!(self, StackStatus::InSync | StackStatus::Disabled)
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            StackStatus::InSync => "in sync",
+            StackStatus::LocalChanged => "local changed",
+            StackStatus::RemoteDrifted => "remote drifted",
+            StackStatus::Missing => "missing",
+            StackStatus::Stopped => "stopped",
+            StackStatus::Disabled => "disabled",
+        }
+    }
+}
+
+/// One resolved config value, as shown by `config show`. `value` is
+/// already formatted as TOML.
+#[derive(Debug, PartialEq)]
+pub struct ConfigSetting {
+    pub key: String,
+    pub value: String,
+    /// The file or env var the value came from, when asked for.
+    pub origin: Option<String>,
+}
+
 type ByteRange = std::ops::Range<usize>;
 
 /// A classified unified-diff line. Paired variants carry the byte range of
@@ -99,9 +609,53 @@ impl Reporter {
 
     const ACTION_LABEL_WIDTH: usize = 15;
 
+    // --- structured output ---
+
+    pub fn set_output_format(format: OutputFormat) {
+        OUTPUT.lock().unwrap().format = format;
+    }
+
+    /// Print anything buffered for the JSON format. Call once when a command
+    /// finishes, whether or not it succeeded.
+    pub fn finish() {
+        if let Some(doc) = OUTPUT.lock().unwrap().finish() {
+            println!("{}", doc);
+        }
+    }
+
+    pub fn is_structured() -> bool {
+        OUTPUT.lock().unwrap().format != OutputFormat::Text
+    }
+
+    /// Record a stack action for structured output. Returns false in text
+    /// mode, where the caller prints as usual.
+    fn structured_action(name: &str, action: &str, fields: Value) -> bool {
+        Self::structured(action, Some(name), true, fields)
+    }
+
+    /// Record extra detail for a stack (or the most recent stack when `name`
+    /// is `None`). Returns false in text mode.
+    fn structured_detail(event: &str, name: Option<&str>, fields: Value) -> bool {
+        Self::structured(event, name, false, fields)
+    }
+
+    fn structured(event: &str, name: Option<&str>, action: bool, fields: Value) -> bool {
+        let mut output = OUTPUT.lock().unwrap();
+        if output.format == OutputFormat::Text {
+            return false;
+        }
+        if let Some(line) = output.record(event, name, action, fields) {
+            println!("{}", line);
+        }
+        true
+    }
+
     // --- action labels ---
 
     pub fn would_update(name: &str, id: impl Display) {
+        if Self::structured_action(name, "would_update", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Would Update"
@@ -113,6 +667,9 @@ impl Reporter {
     }
 
     pub fn would_create(name: &str) {
+        if Self::structured_action(name, "would_create", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Would Create"
@@ -123,6 +680,9 @@ impl Reporter {
     }
 
     pub fn updating(name: &str) {
+        if Self::structured_action(name, "updating", json!({})) {
+            return;
+        }
         println!(
             " {} {}...",
             "Updating".waiting().align_right(Self::ACTION_LABEL_WIDTH),
@@ -131,6 +691,9 @@ impl Reporter {
     }
 
     pub fn updated(name: &str, id: impl Display) {
+        if Self::structured_action(name, "updated", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Updated".updated().align_right(Self::ACTION_LABEL_WIDTH),
@@ -140,6 +703,9 @@ impl Reporter {
     }
 
     pub fn creating(name: &str) {
+        if Self::structured_action(name, "creating", json!({})) {
+            return;
+        }
         println!(
             " {} {}...",
             "Creating".waiting().align_right(Self::ACTION_LABEL_WIDTH),
@@ -148,6 +714,9 @@ impl Reporter {
     }
 
     pub fn created(name: &str, id: impl Display) {
+        if Self::structured_action(name, "created", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Created".updated().align_right(Self::ACTION_LABEL_WIDTH),
@@ -157,6 +726,9 @@ impl Reporter {
     }
 
     pub fn up_to_date(name: &str) {
+        if Self::structured_action(name, "up_to_date", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Up-to-Date"
@@ -167,6 +739,9 @@ impl Reporter {
     }
 
     pub fn would_redeploy(name: &str) {
+        if Self::structured_action(name, "would_redeploy", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Would Redeploy"
@@ -177,6 +752,9 @@ impl Reporter {
     }
 
     pub fn redeploying(name: &str) {
+        if Self::structured_action(name, "redeploying", json!({})) {
+            return;
+        }
         println!(
             " {} {}...",
             "Redeploying"
@@ -187,6 +765,9 @@ impl Reporter {
     }
 
     pub fn redeployed(name: &str, id: impl Display) {
+        if Self::structured_action(name, "redeployed", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Redeployed".updated().align_right(Self::ACTION_LABEL_WIDTH),
@@ -196,6 +777,9 @@ impl Reporter {
     }
 
     pub fn changed(name: &str) {
+        if Self::structured_action(name, "changed", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Changed"
@@ -206,6 +790,9 @@ impl Reporter {
     }
 
     pub fn would_stop(name: &str, id: impl Display) {
+        if Self::structured_action(name, "would_stop", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Would Stop"
@@ -217,6 +804,9 @@ impl Reporter {
     }
 
     pub fn stopping(name: &str) {
+        if Self::structured_action(name, "stopping", json!({})) {
+            return;
+        }
         println!(
             " {} {}...",
             "Stopping".waiting().align_right(Self::ACTION_LABEL_WIDTH),
@@ -225,6 +815,9 @@ impl Reporter {
     }
 
     pub fn stopped(name: &str, id: impl Display) {
+        if Self::structured_action(name, "stopped", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Stopped".updated().align_right(Self::ACTION_LABEL_WIDTH),
@@ -234,6 +827,9 @@ impl Reporter {
     }
 
     pub fn already_stopped(name: &str) {
+        if Self::structured_action(name, "already_stopped", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Already Stopped"
@@ -244,6 +840,9 @@ impl Reporter {
     }
 
     pub fn starting(name: &str) {
+        if Self::structured_action(name, "starting", json!({})) {
+            return;
+        }
         println!(
             " {} {}...",
             "Starting".waiting().align_right(Self::ACTION_LABEL_WIDTH),
@@ -252,6 +851,9 @@ impl Reporter {
     }
 
     pub fn started(name: &str, id: impl Display) {
+        if Self::structured_action(name, "started", json!({ "id": id_value(&id) })) {
+            return;
+        }
         println!(
             " {} {} {}",
             "Started".updated().align_right(Self::ACTION_LABEL_WIDTH),
@@ -261,6 +863,9 @@ impl Reporter {
     }
 
     pub fn disabled(name: &str) {
+        if Self::structured_action(name, "disabled", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Disabled"
@@ -270,7 +875,160 @@ impl Reporter {
         );
     }
 
+    /// Print the result of `check` for a stack: missing variables and env
+    /// schema violations fail it, defaulted and unused ones are warnings.
+    pub fn variable_check(name: &str, check: &VariableCheck) {
+        let action = if check.passed() {
+            "checked"
+        } else {
+            "check_failed"
+        };
+        if Self::structured_action(
+            name,
+            action,
+            json!({ "variables": variable_check_value(check) }),
+        ) {
+            return;
+        }
+        let label = if check.passed() {
+            "Checked".up_to_date()
+        } else {
+            "Failed".style_if_supported(Style::new().red().bold())
+        };
+        println!(
+            " {} {}",
+            label.align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name)
+        );
+        for name in &check.missing {
+            println!(
+                "    {}",
+                format!("! {} is not set and has no default", name)
+                    .style_if_supported(Style::new().red())
+            );
+        }
+        Self::invalid_vars(&check.invalid);
+        for name in &check.defaulted {
+            println!(
+                "    {}",
+                format!("~ {} is not set, using its default", name)
+                    .style_if_supported(Style::new().yellow())
+            );
+        }
+        for name in &check.unused {
+            println!(
+                "    {}",
+                format!("? {} is set but never used", name).dimmed()
+            );
+        }
+    }
+
+    /// Print the result of `config check` for the local config file.
+    pub fn config_check(path: &str, errors: &[String], warnings: &[String]) {
+        let action = if errors.is_empty() {
+            "checked"
+        } else {
+            "check_failed"
+        };
+        if Self::structured_action(
+            path,
+            action,
+            json!({ "errors": errors, "warnings": warnings }),
+        ) {
+            return;
+        }
+        let label = if errors.is_empty() {
+            "Checked".up_to_date()
+        } else {
+            "Failed".style_if_supported(Style::new().red().bold())
+        };
+        println!(
+            " {} {}",
+            label.align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(path)
+        );
+        for error in errors {
+            println!(
+                "    {}",
+                format!("! {}", error).style_if_supported(Style::new().red())
+            );
+        }
+        for warning in warnings {
+            println!(
+                "    {}",
+                format!("~ {}", warning).style_if_supported(Style::new().yellow())
+            );
+        }
+    }
+
+    /// Print the effective config as TOML, one table per section (the
+    /// unnamed first section holds the globals), with each value's origin
+    /// as a trailing comment when known.
+    pub fn effective_config(path: &str, sections: &[(String, Vec<ConfigSetting>)]) {
+        let setting_json =
+            |s: &ConfigSetting| json!({ "key": s.key, "value": s.value, "origin": s.origin });
+        let fields: Map<String, Value> = sections
+            .iter()
+            .map(|(name, settings)| {
+                let name = if name.is_empty() { "global" } else { name };
+                (
+                    name.to_string(),
+                    settings.iter().map(setting_json).collect::<Vec<_>>().into(),
+                )
+            })
+            .collect();
+        if Self::structured_action(path, "config", json!({ "settings": fields })) {
+            return;
+        }
+        println!("{}", format!("# Effective config for {}", path).dimmed());
+        for (name, settings) in sections {
+            if !name.is_empty() {
+                println!();
+                println!("{}", Self::bold(&format!("[{}]", name)));
+            }
+            let width = settings
+                .iter()
+                .map(|s| s.key.len() + s.value.len() + 3)
+                .max()
+                .unwrap_or(0);
+            for setting in settings {
+                let line = format!("{} = {}", setting.key, setting.value);
+                match &setting.origin {
+                    Some(origin) => println!(
+                        "{}  {}",
+                        pad_str(&line, width, Alignment::Left, None),
+                        format!("# {}", origin).dimmed()
+                    ),
+                    None => println!("{}", line),
+                }
+            }
+        }
+    }
+
+    /// Print the problems that make a stack's compose files invalid.
+    pub fn invalid_compose(name: &str, errors: &[String]) {
+        if Self::structured_action(name, "invalid_compose", json!({ "compose_errors": errors })) {
+            return;
+        }
+        println!(
+            " {} {}",
+            "Invalid"
+                .style_if_supported(Style::new().red().bold())
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name)
+        );
+        for error in errors {
+            println!(
+                "    {}",
+                format!("! {}", error).style_if_supported(Style::new().red())
+            );
+        }
+    }
+
     pub fn not_found(name: &str) {
+        if Self::structured_action(name, "not_found", json!({})) {
+            return;
+        }
         println!(
             " {} {}",
             "Not Found"
@@ -280,7 +1038,255 @@ impl Reporter {
         );
     }
 
+    pub fn outdated(name: &str) {
+        if Self::structured_action(name, "outdated", json!({})) {
+            return;
+        }
+        println!(
+            " {} {}",
+            "Outdated"
+                .would_update()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name)
+        );
+    }
+
+    pub fn locked(name: &str, digests: &BTreeMap<String, String>, skipped: &[String]) {
+        if Self::structured_action(
+            name,
+            "locked",
+            json!({ "digests": digests, "skipped": skipped }),
+        ) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "Locked".updated().align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            format!(
+                "({} {})",
+                digests.len(),
+                if digests.len() == 1 {
+                    "image"
+                } else {
+                    "images"
+                }
+            )
+            .dimmed()
+        );
+        for (image, digest) in digests {
+            println!("    {} {}", image, format!("→ {}", digest).dimmed());
+        }
+        for image in skipped {
+            println!(
+                "    {}",
+                format!("! {} can't be locked: it uses variables", image)
+                    .style_if_supported(Style::new().yellow())
+            );
+        }
+    }
+
+    pub fn wrote_lock_file(path: impl Display) {
+        if Self::is_structured() {
+            return;
+        }
+        println!("Wrote {}", path);
+    }
+
+    pub fn encrypted_env_file(path: impl Display, output: impl Display, encryption: &str) {
+        if Self::is_structured() {
+            return;
+        }
+        println!("Encrypted {} with {} to {}", path, encryption, output);
+    }
+
+    pub fn decrypted_env_file(path: impl Display, output: impl Display) {
+        if Self::is_structured() {
+            return;
+        }
+        println!("Decrypted {} to {}", path, output);
+    }
+
+    pub fn edited_env_file(path: impl Display, changed: bool) {
+        if Self::is_structured() {
+            return;
+        }
+        if changed {
+            println!("Re-encrypted {}", path);
+        } else {
+            println!("No changes to {}", path);
+        }
+    }
+
+    pub fn waiting_for_lock(name: &str, holder: impl Display) {
+        if Self::structured_action(
+            name,
+            "waiting_for_lock",
+            json!({ "holder": holder.to_string() }),
+        ) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "Waiting".waiting().align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            format!("(locked by {})", holder).dimmed()
+        );
+    }
+
+    /// Note that `--semantic` fell back to comparing text because a compose
+    /// file isn't valid YAML.
+    pub fn semantic_fallback(name: &str, reason: impl Display) {
+        if Self::structured_detail(
+            "semantic_fallback",
+            Some(name),
+            json!({ "semantic_fallback": reason.to_string() }),
+        ) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "Line Diff"
+                .would_update()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            format!("(not valid YAML, compared as text: {:#})", reason).dimmed()
+        );
+    }
+
+    /// Note env vars whose secret references were left unresolved, and
+    /// what was done with them instead.
+    pub fn unresolved_secrets(name: &str, vars: &[&str], mode: secrets::Mode) {
+        if Self::structured_detail(
+            "unresolved_secrets",
+            Some(name),
+            json!({ "unresolved_secrets": vars }),
+        ) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "Unresolved"
+                .would_update()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            match mode {
+                secrets::Mode::CheckPresence => format!(
+                    "({} only checked for presence; pass --resolve-secrets to check their values)",
+                    vars.join(", ")
+                ),
+                _ => format!(
+                    "({} kept as deployed; pass --resolve-secrets to compare them)",
+                    vars.join(", ")
+                ),
+            }
+            .dimmed()
+        );
+    }
+
+    /// Warn about paths the compose file references that exist locally but
+    /// aren't uploaded: directories, and paths outside the stack directory.
+    pub fn files_not_uploaded(name: &str, paths: &[String]) {
+        if Self::structured_detail(
+            "files_not_uploaded",
+            Some(name),
+            json!({ "files_not_uploaded": paths }),
+        ) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "Not Uploaded"
+                .would_update()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            format!(
+                "({}: only files inside the stack directory are uploaded)",
+                paths.join(", ")
+            )
+            .dimmed()
+        );
+    }
+
+    pub fn stale_lock(name: &str, holder: impl Display) {
+        if Self::structured_action(name, "stale_lock", json!({ "holder": holder.to_string() })) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "Stale Lock"
+                .would_update()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            format!("(breaking lock held by {})", holder).dimmed()
+        );
+    }
+
+    /// Warn that a stack is synced without a deployment lock.
+    pub fn lock_unavailable(name: &str, reason: &str) {
+        if Self::structured_action(name, "lock_unavailable", json!({ "reason": reason })) {
+            return;
+        }
+        println!(
+            " {} {} {}",
+            "No Lock"
+                .would_update()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name),
+            format!("(syncing without a lock: {})", reason).dimmed()
+        );
+    }
+
+    pub fn unlocked(name: &str) {
+        if Self::structured_action(name, "unlocked", json!({})) {
+            return;
+        }
+        println!(
+            " {} {}",
+            "Unlocked".updated().align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name)
+        );
+    }
+
+    pub fn not_locked(name: &str) {
+        if Self::structured_action(name, "not_locked", json!({})) {
+            return;
+        }
+        println!(
+            " {} {}",
+            "Not Locked"
+                .up_to_date()
+                .align_right(Self::ACTION_LABEL_WIDTH),
+            Self::bold(name)
+        );
+    }
+
+    pub fn wrote_file(name: &str, kind: &str, path: impl Display) {
+        if Self::structured_detail(
+            "wrote_file",
+            Some(name),
+            json!({ format!("{}_file", kind): path.to_string() }),
+        ) {
+            return;
+        }
+        println!("Wrote {} file to {}", kind, path);
+    }
+
+    pub fn imported(name: &str) {
+        if Self::structured_action(name, "imported", json!({})) {
+            return;
+        }
+        println!("Added stack '{}' to config", name);
+    }
+
     pub fn view(name: &str, id: impl Display, status: &str) {
+        if Self::structured_action(
+            name,
+            "view",
+            json!({ "id": id_value(&id), "status": status }),
+        ) {
+            return;
+        }
         println!(
             " {} {} {} {}",
             "View".up_to_date().align_right(Self::ACTION_LABEL_WIDTH),
@@ -290,6 +1296,50 @@ impl Reporter {
         );
     }
 
+    // --- status table ---
+
+    const STATUS_WIDTH: usize = 14;
+
+    pub fn status_header(name_width: usize) {
+        if Self::is_structured() {
+            return;
+        }
+        println!(
+            " {}  {}  {}",
+            pad_str(&"STACK".field_label(), name_width, Alignment::Left, None),
+            pad_str(
+                &"STATUS".field_label(),
+                Self::STATUS_WIDTH,
+                Alignment::Left,
+                None
+            ),
+            "UPDATED".field_label()
+        );
+    }
+
+    pub fn status_row(name: &str, name_width: usize, status: StackStatus, updated: &str) {
+        if Self::structured_action(
+            name,
+            "status",
+            json!({ "status": status.label().replace(' ', "_"), "updated": updated }),
+        ) {
+            return;
+        }
+        let label = status.label();
+        let styled = match status {
+            StackStatus::InSync => label.up_to_date(),
+            StackStatus::Disabled => label.dimmed().to_string(),
+            StackStatus::RemoteDrifted => label.style_if_supported(Style::new().red().bold()),
+            _ => label.would_update(),
+        };
+        println!(
+            " {}  {}  {}",
+            pad_str(&Self::bold(name), name_width, Alignment::Left, None),
+            pad_str(&styled, Self::STATUS_WIDTH, Alignment::Left, None),
+            updated.dimmed()
+        );
+    }
+
     // --- detail block ---
 
     // +2 accounts for the leading space in action labels and a small indent
@@ -302,6 +1352,20 @@ impl Reporter {
         env: Option<(String, usize)>,
         endpoint_id: impl Display,
     ) {
+        if Self::structured_detail(
+            "details",
+            None,
+            json!({ "details": {
+                "host": host,
+                "compose_file": compose_path.to_string(),
+                "compose_bytes": compose_bytes,
+                "env_file": env.as_ref().map(|(path, _)| path),
+                "env_vars": env.as_ref().map(|(_, vars)| vars),
+                "endpoint_id": id_value(&endpoint_id),
+            }}),
+        ) {
+            return;
+        }
         let w = Self::FIELD_LABEL_WIDTH;
         println!("{:w$}{}:         {}", "", "Host".field_label(), host);
         println!(
@@ -345,6 +1409,21 @@ impl Reporter {
         updated: impl Display,
         env_count: usize,
     ) {
+        if Self::structured_detail(
+            "details",
+            None,
+            json!({ "details": {
+                "type": stack_type,
+                "endpoint_id": endpoint_id,
+                "created_by": created_by,
+                "created": created.to_string(),
+                "updated_by": updated_by,
+                "updated": updated.to_string(),
+                "env_vars": env_count,
+            }}),
+        ) {
+            return;
+        }
         let w = Self::FIELD_LABEL_WIDTH;
         println!("{:w$}{}:       {}", "", "Type".field_label(), stack_type);
         println!("{:w$}{}:   {}", "", "Endpoint".field_label(), endpoint_id);
@@ -357,6 +1436,56 @@ impl Reporter {
         }
     }
 
+    /// Print a deployment stamp, aligned with the view detail block.
+    pub fn stamp_details(
+        deployer: &str,
+        machine: &str,
+        deployed: impl Display,
+        git_commit: Option<&str>,
+        compose_sha256: &str,
+        env_hmac: Option<&str>,
+    ) {
+        if Self::structured_detail(
+            "stamp",
+            None,
+            json!({ "stamp": {
+                "deployed_by": deployer,
+                "machine": machine,
+                "deployed_at": deployed.to_string(),
+                "git_commit": git_commit,
+                "compose_sha256": compose_sha256,
+                "env_hmac": env_hmac,
+            }}),
+        ) {
+            return;
+        }
+        let w = Self::FIELD_LABEL_WIDTH;
+        let short = |sha: &str| sha.chars().take(12).collect::<String>();
+        println!(
+            "{:w$}{}:   {}@{}",
+            "",
+            "Deployer".field_label(),
+            deployer,
+            machine
+        );
+        println!("{:w$}{}:   {}", "", "Deployed".field_label(), deployed);
+        println!(
+            "{:w$}{}:     {}",
+            "",
+            "Commit".field_label(),
+            git_commit.unwrap_or("n/a")
+        );
+        println!(
+            "{:w$}{}:    {}",
+            "",
+            "Compose".field_label(),
+            short(compose_sha256)
+        );
+        if let Some(env) = env_hmac {
+            println!("{:w$}{}:        {}", "", "Env".field_label(), short(env));
+        }
+    }
+
     pub fn ssh_stack_details(
         host: &str,
         compose_path: impl Display,
@@ -364,6 +1493,20 @@ impl Reporter {
         env: Option<(String, usize)>,
         host_dir: &str,
     ) {
+        if Self::structured_detail(
+            "details",
+            None,
+            json!({ "details": {
+                "host": host,
+                "compose_file": compose_path.to_string(),
+                "compose_bytes": compose_bytes,
+                "env_file": env.as_ref().map(|(path, _)| path),
+                "env_vars": env.as_ref().map(|(_, vars)| vars),
+                "host_dir": host_dir,
+            }}),
+        ) {
+            return;
+        }
         let w = Self::FIELD_LABEL_WIDTH;
         println!("{:w$}{}:         {}", "", "Host".field_label(), host);
         println!(
@@ -398,7 +1541,40 @@ impl Reporter {
         }
     }
 
+    /// Print env schema violations found while diffing a stack.
+    pub fn schema_violations(messages: &[String]) {
+        if messages.is_empty()
+            || Self::structured_detail(
+                "schema",
+                None,
+                json!({ "variables": { "invalid": messages } }),
+            )
+        {
+            return;
+        }
+        Self::invalid_vars(messages);
+    }
+
+    fn invalid_vars(messages: &[String]) {
+        for message in messages {
+            println!(
+                "    {}",
+                format!("! {}", message).style_if_supported(Style::new().red())
+            );
+        }
+    }
+
     pub fn diff_details(compose_diff: &[String], env_changes: &[EnvChange]) {
+        if Self::structured_detail(
+            "diff",
+            None,
+            json!({
+                "hunks": diff_hunks(compose_diff),
+                "env_changes": env_changes_value(env_changes),
+            }),
+        ) {
+            return;
+        }
         for line in &pair_diff_lines(compose_diff) {
             let styled = match line {
                 DiffLine::Insert(text) => {
@@ -419,9 +1595,27 @@ impl Reporter {
             println!("    {}", styled);
         }
 
-        if !env_changes.is_empty() {
-            println!("    {}", "env changes (values hidden):".field_label());
-            for change in env_changes {
+        Self::change_list("env changes (values hidden):", env_changes);
+    }
+
+    /// Print changes to the extra files uploaded with an SSH stack.
+    pub fn file_changes(changes: &[EnvChange]) {
+        if changes.is_empty()
+            || Self::structured_detail(
+                "files",
+                None,
+                json!({ "file_changes": env_changes_value(changes) }),
+            )
+        {
+            return;
+        }
+        Self::change_list("file changes:", changes);
+    }
+
+    fn change_list(label: &str, changes: &[EnvChange]) {
+        if !changes.is_empty() {
+            println!("    {}", label.field_label());
+            for change in changes {
                 let styled = match change {
                     EnvChange::Added(n) => {
                         format!("+ {}", n).style_if_supported(Style::new().green())
@@ -438,6 +1632,111 @@ impl Reporter {
         }
     }
 
+    /// Print outdated images and images that couldn't be checked.
+    /// Structured output lists every image.
+    pub fn image_statuses(statuses: &[ImageStatus]) {
+        if Self::structured_detail(
+            "images",
+            None,
+            json!({ "images": image_statuses_value(statuses) }),
+        ) {
+            return;
+        }
+        for status in statuses {
+            if let Some(error) = &status.error {
+                println!(
+                    "    {} {}",
+                    format!(
+                        "! {}: {} could not be checked:",
+                        status.service, status.image
+                    )
+                    .style_if_supported(Style::new().red()),
+                    error.dimmed()
+                );
+                continue;
+            }
+            if let Some(tag) = &status.newer_tag {
+                println!(
+                    "    {}",
+                    format!("~ {}: {} → {} available", status.service, status.image, tag)
+                        .style_if_supported(Style::new().yellow())
+                );
+            }
+            if let Some(digest) = &status.new_digest {
+                println!(
+                    "    {} {}",
+                    format!("~ {}: {} has a new digest", status.service, status.image)
+                        .style_if_supported(Style::new().yellow()),
+                    format!("({})", digest).dimmed()
+                );
+            }
+        }
+    }
+
+    pub fn image_changes(changes: &[ImageChange]) {
+        if Self::structured_detail(
+            "image_changes",
+            None,
+            json!({ "image_changes": image_changes_value(changes) }),
+        ) {
+            return;
+        }
+        println!("    {}", "image changes:".field_label());
+        for change in changes {
+            let styled = match change {
+                ImageChange::Bumped {
+                    service,
+                    repository,
+                    old,
+                    new,
+                } => format!("~ {}: {} {} → {}", service, repository, old, new)
+                    .style_if_supported(Style::new().yellow()),
+                ImageChange::Replaced { service, old, new } => {
+                    format!("~ {}: {} → {}", service, old, new)
+                        .style_if_supported(Style::new().yellow())
+                }
+                ImageChange::ServiceAdded { service, image } => format!(
+                    "+ {}: {} (new service)",
+                    service,
+                    image.as_deref().unwrap_or("no image")
+                )
+                .style_if_supported(Style::new().green()),
+                ImageChange::ServiceRemoved { service, image } => format!(
+                    "- {}: {} (removed service)",
+                    service,
+                    image.as_deref().unwrap_or("no image")
+                )
+                .style_if_supported(Style::new().red()),
+            };
+            println!("    {}", styled);
+        }
+    }
+
+    pub fn compose_changes(changes: &[ComposeChange]) {
+        if Self::structured_detail(
+            "compose_changes",
+            None,
+            json!({ "compose_changes": compose_changes_value(changes) }),
+        ) {
+            return;
+        }
+        for change in changes {
+            let styled = match change {
+                ComposeChange::Added { path, value } => {
+                    format!("+ {}: {}", path, value).style_if_supported(Style::new().green())
+                }
+                ComposeChange::Removed { path, value } => {
+                    format!("- {}: {}", path, value).style_if_supported(Style::new().red())
+                }
+                ComposeChange::Changed { path, old, new } => {
+                    format!("~ {}: {} → {}", path, old, new)
+                        .style_if_supported(Style::new().yellow())
+                }
+            };
+            println!("    {}", styled);
+        }
+    }
+
     /// Render one diff line, optionally emphasizing the changed segment
     /// (byte range into `content`) with reverse video.
     fn styled_diff_line(
@@ -458,6 +1757,18 @@ impl Reporter {
     }
 
     pub fn ssh_view_details(host: &str, host_dir: &str, ps_output: Option<&str>) {
+        if Self::structured_detail(
+            "details",
+            None,
+            json!({ "details": {
+                "mode": "ssh",
+                "host": host,
+                "host_dir": host_dir,
+                "containers": ps_output,
+            }}),
+        ) {
+            return;
+        }
         let w = Self::FIELD_LABEL_WIDTH;
         println!("{:w$}{}:       SSH", "", "Mode".field_label());
         println!("{:w$}{}:         {}", "", "Host".field_label(), host);
@@ -473,7 +1784,307 @@ impl Reporter {
 
 #[cfg(test)]
 mod tests {
-    use super::{DiffLine, EnvChange, Reporter, changed_segments, pair_diff_lines};
+    use super::{
+        ComposeChange, ConfigSetting, DiffLine, EnvChange, ImageChange, ImageStatus, OutputFormat,
+        Reporter, StackStatus, StructuredOutput, VariableCheck, changed_segments, code_fence,
+        compose_changes_value, diff_hunks, image_changes_value, image_statuses_value,
+        pair_diff_lines, secrets, variable_check_value,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn test_structured_json_groups_by_stack() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Json;
+        assert_eq!(
+            output.record("updating", Some("web"), true, json!({})),
+            None
+        );
+        output.record("updated", Some("web"), true, json!({ "id": 42 }));
+        output.record("diff", None, false, json!({ "hunks": [] }));
+        output.record("up_to_date", Some("db"), true, json!({}));
+        let doc: serde_json::Value = serde_json::from_str(&output.finish().unwrap()).unwrap();
+        assert_eq!(
+            doc,
+            json!([
+                { "stack": "web", "action": "updated", "id": 42, "hunks": [] },
+                { "stack": "db", "action": "up_to_date" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_structured_ndjson_streams_events() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Ndjson;
+        let line = output.record("updated", Some("web"), true, json!({ "id": 42 }));
+        assert_eq!(
+            line.as_deref(),
+            Some(r#"{"event":"updated","stack":"web","id":42}"#)
+        );
+        assert_eq!(output.finish(), None);
+    }
+
+    #[test]
+    fn test_render_markdown_image_changes() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Markdown;
+        output.record("would_update", Some("web"), true, json!({}));
+        output.record(
+            "image_changes",
+            None,
+            false,
+            json!({ "image_changes": image_changes_value(&[
+                ImageChange::Bumped {
+                    service: "web".to_string(),
+                    repository: "nginx".to_string(),
+                    old: "1.25".to_string(),
+                    new: "1.27".to_string(),
+                },
+                ImageChange::ServiceAdded {
+                    service: "proxy".to_string(),
+                    image: Some("traefik:v3".to_string()),
+                },
+                ImageChange::ServiceRemoved {
+                    service: "worker".to_string(),
+                    image: None,
+                },
+            ]) }),
+        );
+        assert_eq!(
+            output.finish().unwrap(),
+            [
+                "### `web`",
+                "",
+                "**Would Update**",
+                "",
+                "Image changes:",
+                "",
+                "- `web`: `nginx` 1.25 → 1.27",
+                "- `proxy` added: `traefik:v3`",
+                "- `worker` removed: `no image`",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_markdown_images() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Markdown;
+        output.record("outdated", Some("web"), true, json!({}));
+        output.record(
+            "images",
+            None,
+            false,
+            json!({ "images": image_statuses_value(&[
+                ImageStatus {
+                    service: "web".to_string(),
+                    image: "nginx:1.25".to_string(),
+                    newer_tag: Some("1.27".to_string()),
+                    new_digest: None,
+                    error: None,
+                },
+                ImageStatus {
+                    service: "db".to_string(),
+                    image: "postgres:15".to_string(),
+                    newer_tag: None,
+                    new_digest: None,
+                    error: None,
+                },
+                ImageStatus {
+                    service: "app".to_string(),
+                    image: "app:latest".to_string(),
+                    newer_tag: None,
+                    new_digest: None,
+                    error: Some("HTTP 404".to_string()),
+                },
+            ]) }),
+        );
+        assert_eq!(
+            output.finish().unwrap(),
+            [
+                "### `web`",
+                "",
+                "**Outdated**",
+                "",
+                "Images:",
+                "",
+                "- `web`: `nginx:1.25`, `1.27` available",
+                "- `app`: `app:latest` could not be checked: HTTP 404",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_markdown_compose_changes() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Markdown;
+        output.record("changed", Some("web"), true, json!({}));
+        output.record(
+            "compose_changes",
+            None,
+            false,
+            json!({ "compose_changes": compose_changes_value(&[
+                ComposeChange::Changed {
+                    path: "services.web.image".to_string(),
+                    old: "nginx:1.25".to_string(),
+                    new: "nginx:1.27".to_string(),
+                },
+                ComposeChange::Removed {
+                    path: "services.cache".to_string(),
+                    value: "{}".to_string(),
+                },
+            ]) }),
+        );
+        assert_eq!(
+            output.finish().unwrap(),
+            [
+                "### `web`",
+                "",
+                "**Changed**",
+                "",
+                "Compose changes:",
+                "",
+                "- `services.web.image`: `nginx:1.25` → `nginx:1.27`",
+                "- `services.cache` removed: `{}`",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_markdown_variables() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Markdown;
+        output.record(
+            "check_failed",
+            Some("web"),
+            true,
+            json!({ "variables": variable_check_value(&VariableCheck {
+                missing: vec!["DATABASE_URL".to_string()],
+                defaulted: vec![],
+                unused: vec!["OLD_KEY".to_string()],
+                invalid: vec!["PORT must match /[0-9]+/".to_string()],
+            }) }),
+        );
+        assert_eq!(
+            output.finish().unwrap(),
+            [
+                "### `web`",
+                "",
+                "**Check Failed**",
+                "",
+                "Variables:",
+                "",
+                "- `DATABASE_URL` not set, no default",
+                "- `OLD_KEY` set but never used",
+                "- PORT must match /[0-9]+/",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_markdown_compose_errors() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Markdown;
+        output.record(
+            "invalid_compose",
+            Some("web"),
+            true,
+            json!({ "compose_errors": ["compose.yaml:3: services.web.ports[0]: invalid port 'x'"] }),
+        );
+        assert_eq!(
+            output.finish().unwrap(),
+            [
+                "### `web`",
+                "",
+                "**Invalid Compose**",
+                "",
+                "Compose errors:",
+                "",
+                "- `compose.yaml:3: services.web.ports[0]: invalid port 'x'`",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let mut output = StructuredOutput::new();
+        output.format = OutputFormat::Markdown;
+        output.record("changed", Some("web"), true, json!({}));
+        output.record(
+            "diff",
+            None,
+            false,
+            json!({
+                "hunks": [{ "header": "@@ -1,1 +1,1 @@", "lines": ["-a", "+b"] }],
+                "env_changes": { "added": ["NEW"], "removed": [], "changed": ["SECRET"] },
+            }),
+        );
+        output.record(
+            "files",
+            None,
+            false,
+            json!({ "file_changes": { "added": [], "removed": ["old.conf"], "changed": [] } }),
+        );
+        output.record("up_to_date", Some("db"), true, json!({}));
+        assert_eq!(
+            output.finish().unwrap(),
+            [
+                "### `web`",
+                "",
+                "**Changed**",
+                "",
+                "```diff",
+                "@@ -1,1 +1,1 @@",
+                "-a",
+                "+b",
+                "```",
+                "",
+                "Env changes (values hidden):",
+                "",
+                "- `+ NEW` added",
+                "- `~ SECRET` changed",
+                "",
+                "File changes:",
+                "",
+                "- `old.conf` removed",
+                "",
+                "### `db`",
+                "",
+                "**Up To Date**",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_code_fence_outgrows_content() {
+        assert_eq!(code_fence(&["plain"]), "```");
+        assert_eq!(code_fence(&["+ ````yaml"]), "`````");
+    }
+
+    #[test]
+    fn test_diff_hunks() {
+        let lines: Vec<String> = ["@@ -1,2 +1,2 @@", " a", "-b", "+c"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(
+            diff_hunks(&lines),
+            json!([{ "header": "@@ -1,2 +1,2 @@", "lines": [" a", "-b", "+c"] }])
+        );
+    }
 
     #[test]
     fn test_changed_segments_uncomment() {
@@ -541,6 +2152,15 @@ mod tests {
     fn style_gallery() {
         Reporter::would_update("my-stack", 42);
         Reporter::would_create("my-stack");
+        Reporter::variable_check(
+            "my-stack",
+            &VariableCheck {
+                missing: vec!["DATABASE_URL".to_string()],
+                defaulted: vec!["TAG".to_string()],
+                unused: vec!["OLD_KEY".to_string()],
+                invalid: vec!["LOG_LEVEL must be one of debug, info".to_string()],
+            },
+        );
         Reporter::updating("my-stack");
         Reporter::updated("my-stack", 42);
         Reporter::creating("my-stack");
@@ -558,6 +2178,23 @@ mod tests {
         Reporter::disabled("my-stack");
         Reporter::not_found("my-stack");
         Reporter::changed("my-stack");
+        Reporter::status_header(12);
+        Reporter::status_row("my-stack", 12, StackStatus::InSync, "2026-07-08 12:00 UTC");
+        Reporter::status_row("other-stack", 12, StackStatus::LocalChanged, "n/a");
+        Reporter::status_row("drifted", 12, StackStatus::RemoteDrifted, "n/a");
+        Reporter::status_row("off", 12, StackStatus::Disabled, "n/a");
+        Reporter::waiting_for_lock("my-stack", "alice@laptop (pid 4242, 12s ago)");
+        Reporter::stale_lock("my-stack", "alice@laptop (pid 4242, 4000s ago)");
+        Reporter::lock_unavailable(
+            "my-stack",
+            "creating Portainer tags needs an administrator API key",
+        );
+        Reporter::semantic_fallback("my-stack", "Failed to parse compose file");
+        Reporter::unresolved_secrets("my-stack", &["DB_PASSWORD"], secrets::Mode::KeepDeployed);
+        Reporter::unresolved_secrets("my-stack", &["DB_PASSWORD"], secrets::Mode::CheckPresence);
+        Reporter::files_not_uploaded("my-stack", &["./data".to_string()]);
+        Reporter::unlocked("my-stack");
+        Reporter::not_locked("my-stack");
         Reporter::diff_details(
             &[
                 "@@ -1,8 +1,8 @@".to_string(),
@@ -581,6 +2218,127 @@ mod tests {
                 EnvChange::Changed("API_KEY".to_string()),
             ],
         );
+        Reporter::config_check(
+            ".stack-sync.toml",
+            &[
+                ".stack-sync.toml: unknown key 'stacks.web.env_flie' (did you mean 'env_file'?)"
+                    .to_string(),
+            ],
+            &[
+                ".stack-sync.toml: 'endpoint_id' of stack 'web' is only used in Portainer mode"
+                    .to_string(),
+            ],
+        );
+        Reporter::effective_config(
+            ".stack-sync.toml",
+            &[
+                (
+                    String::new(),
+                    vec![
+                        ConfigSetting {
+                            key: "host".to_string(),
+                            value: "\"https://portainer.example.com\"".to_string(),
+                            origin: Some("../.stack-sync.toml".to_string()),
+                        },
+                        ConfigSetting {
+                            key: "portainer_api_key".to_string(),
+                            value: "\"ptr_****\"".to_string(),
+                            origin: Some("PORTAINER_API_KEY".to_string()),
+                        },
+                    ],
+                ),
+                (
+                    "stacks.web".to_string(),
+                    vec![ConfigSetting {
+                        key: "compose_files".to_string(),
+                        value: "[\"/srv/web/compose.yaml\"]".to_string(),
+                        origin: Some(".stack-sync.toml".to_string()),
+                    }],
+                ),
+            ],
+        );
+        Reporter::invalid_compose(
+            "my-stack",
+            &["compose.yaml:6: services.web.ports[1]: invalid port '80:80:80'".to_string()],
+        );
+        Reporter::file_changes(&[
+            EnvChange::Added("nginx/site.conf".to_string()),
+            EnvChange::Removed("old.conf".to_string()),
+        ]);
+        Reporter::locked(
+            "my-stack",
+            &std::collections::BTreeMap::from([(
+                "nginx:1.25".to_string(),
+                "sha256:4c0fdaa8b6341bfdeca5f18f7837462c80cff90527ee35ef185571e1c327beac"
+                    .to_string(),
+            )]),
+            &["app:${TAG}".to_string()],
+        );
+        Reporter::wrote_lock_file("stack-sync.lock");
+        Reporter::outdated("my-stack");
+        Reporter::image_statuses(&[
+            ImageStatus {
+                service: "web".to_string(),
+                image: "nginx:1.25".to_string(),
+                newer_tag: Some("1.27".to_string()),
+                new_digest: Some(
+                    "sha256:4c0fdaa8b6341bfdeca5f18f7837462c80cff90527ee35ef185571e1c327beac"
+                        .to_string(),
+                ),
+                error: None,
+            },
+            ImageStatus {
+                service: "db".to_string(),
+                image: "ghcr.io/org/private:1".to_string(),
+                newer_tag: None,
+                new_digest: None,
+                error: Some("Registry ghcr.io requires credentials".to_string()),
+            },
+        ]);
+        Reporter::image_changes(&[
+            ImageChange::Bumped {
+                service: "web".to_string(),
+                repository: "nginx".to_string(),
+                old: "1.25".to_string(),
+                new: "1.27".to_string(),
+            },
+            ImageChange::Replaced {
+                service: "db".to_string(),
+                old: "postgres:15".to_string(),
+                new: "ghcr.io/org/postgres:15".to_string(),
+            },
+            ImageChange::ServiceAdded {
+                service: "proxy".to_string(),
+                image: Some("traefik:v3".to_string()),
+            },
+            ImageChange::ServiceRemoved {
+                service: "worker".to_string(),
+                image: None,
+            },
+        ]);
+        Reporter::compose_changes(&[
+            ComposeChange::Changed {
+                path: "services.web.image".to_string(),
+                old: "nginx:1.25".to_string(),
+                new: "nginx:1.27".to_string(),
+            },
+            ComposeChange::Added {
+                path: "services.web.ports[1]".to_string(),
+                value: "443:443".to_string(),
+            },
+            ComposeChange::Removed {
+                path: "services.cache".to_string(),
+                value: "{\"image\":\"redis\"}".to_string(),
+            },
+        ]);
+        Reporter::stamp_details(
+            "alice",
+            "laptop",
+            "2026-07-08 12:00 UTC",
+            Some("4d9b1b1c0ffee"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            None,
+        );
         Reporter::stack_details(
             "https://portainer.example.com",
             "docker-compose.yml",
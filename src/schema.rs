@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::config::EnvVar;
+use crate::dotenv;
+use crate::secrets;
+
+/// Env vars a stack expects, from its `env_schema` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvSchema {
+    #[serde(default)]
+    pub keys: BTreeMap<String, KeySchema>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySchema {
+    /// Must be set to a non-empty value. Keys are required unless they say
+    /// otherwise.
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// Regex the whole value must match.
+    pub pattern: Option<String>,
+    /// The only values accepted.
+    pub allowed: Option<Vec<String>>,
+    /// Masked by `config show`, even with `--show-values`.
+    #[serde(default)]
+    pub secret: bool,
+    /// Shown when the key is missing, to say what it is for.
+    pub description: Option<String>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+/// One way an env file breaks its schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaViolation {
+    pub name: String,
+    /// Whether the key is missing or empty, as opposed to having a bad value.
+    pub missing: bool,
+    pub message: String,
+}
+
+impl EnvSchema {
+    /// Load a schema file. A `.toml` file declares keys under `[keys.NAME]`;
+    /// any other file is read as an env file such as `.env.example`, whose
+    /// keys are all required.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .context(format!("Failed to read env schema: {}", path.display()))?;
+        let context = format!("Failed to parse env schema: {}", path.display());
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&content).context(context)
+        } else {
+            let vars = dotenv::parse(&content).context(context)?;
+            Ok(Self::from_example(&vars))
+        }
+    }
+
+    fn from_example(vars: &[EnvVar]) -> Self {
+        Self {
+            keys: vars
+                .iter()
+                .map(|v| (v.name.clone(), KeySchema::default_required()))
+                .collect(),
+        }
+    }
+
+    /// Check env vars against the schema. Values that are unresolved secret
+    /// references only need to be set. Messages never include a value, since
+    /// it may have come from a secret reference or an encrypted env file.
+    pub fn validate(&self, env: &[EnvVar]) -> Result<Vec<SchemaViolation>> {
+        let mut violations = Vec::new();
+        for (name, key) in &self.keys {
+            let value = env
+                .iter()
+                .find(|v| &v.name == name)
+                .map(|v| v.value.as_str());
+            let Some(value) = value.filter(|v| !v.is_empty()) else {
+                if key.required {
+                    let state = if value.is_some() { "empty" } else { "missing" };
+                    let message = match &key.description {
+                        Some(description) => {
+                            format!("{} is required but {} ({})", name, state, description)
+                        }
+                        None => format!("{} is required but {}", name, state),
+                    };
+                    violations.push(SchemaViolation {
+                        name: name.clone(),
+                        missing: true,
+                        message,
+                    });
+                }
+                continue;
+            };
+            // Only the presence of a secret left unresolved is known
+            if secrets::is_unresolved(value) {
+                continue;
+            }
+            if let Some(pattern) = &key.pattern {
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .context(format!("Invalid pattern for {} in env schema", name))?;
+                if !regex.is_match(value) {
+                    violations.push(SchemaViolation {
+                        name: name.clone(),
+                        missing: false,
+                        message: format!("{} must match /{}/", name, pattern),
+                    });
+                }
+            }
+            if let Some(allowed) = &key.allowed
+                && !allowed.iter().any(|a| a == value)
+            {
+                violations.push(SchemaViolation {
+                    name: name.clone(),
+                    missing: false,
+                    message: format!("{} must be one of {}", name, allowed.join(", ")),
+                });
+            }
+        }
+        Ok(violations)
+    }
+}
+
+impl KeySchema {
+    fn default_required() -> Self {
+        Self {
+            required: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str, value: &str) -> EnvVar {
+        EnvVar {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn messages(schema: &EnvSchema, env: &[EnvVar]) -> Vec<String> {
+        schema
+            .validate(env)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_toml_schema() {
+        let schema: EnvSchema = toml::from_str(
+            r#"
+[keys.DATABASE_URL]
+pattern = "postgres://.+"
+secret = true
+description = "Postgres connection string"
+
+[keys.LOG_LEVEL]
+allowed = ["debug", "info", "warn"]
+required = false
+
+[keys.PORT]
+pattern = "[0-9]+"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            messages(&schema, &[var("LOG_LEVEL", "loud"), var("PORT", "80a")]),
+            vec![
+                "DATABASE_URL is required but missing (Postgres connection string)",
+                "LOG_LEVEL must be one of debug, info, warn",
+                "PORT must match /[0-9]+/",
+            ]
+        );
+        assert_eq!(
+            messages(
+                &schema,
+                &[var("DATABASE_URL", "mysql://secret"), var("PORT", "")]
+            ),
+            vec![
+                "DATABASE_URL must match /postgres://.+/",
+                "PORT is required but empty",
+            ]
+        );
+        assert!(
+            messages(
+                &schema,
+                &[var("DATABASE_URL", "postgres://db"), var("PORT", "80")]
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_validate_skips_value_rules_for_unresolved_secrets() {
+        let schema: EnvSchema = toml::from_str(
+            "[keys.DATABASE_URL]\npattern = \"postgres://.+\"\n\n\
+             [keys.MODE]\nallowed = [\"a\", \"b\"]\n\n[keys.TOKEN]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            messages(
+                &schema,
+                &[
+                    var("DATABASE_URL", secrets::UNRESOLVED),
+                    var("MODE", &format!("x{}", secrets::UNRESOLVED)),
+                ]
+            ),
+            vec!["TOKEN is required but missing"]
+        );
+    }
+
+    #[test]
+    fn test_validate_never_prints_resolved_secrets() {
+        let schema: EnvSchema = toml::from_str(
+            "[keys.DB_PASSWORD]\npattern = \"[a-z]{20,}\"\n\n\
+             [keys.MODE]\nallowed = [\"a\"]\n",
+        )
+        .unwrap();
+        let password =
+            secrets::resolve("cmd://echo hunter2".to_string(), &Default::default()).unwrap();
+        let mode = secrets::resolve("cmd://echo b".to_string(), &Default::default()).unwrap();
+        let messages = messages(
+            &schema,
+            &[var("DB_PASSWORD", &password), var("MODE", &mode)],
+        );
+        assert_eq!(messages.len(), 2);
+        assert!(
+            messages
+                .iter()
+                .all(|m| !m.contains("hunter2") && !m.contains("'b'"))
+        );
+    }
+
+    #[test]
+    fn test_schema_from_example() {
+        let schema =
+            EnvSchema::from_example(&dotenv::parse("# Example\nA=changeme\nB=\n").unwrap());
+        let violations = schema.validate(&[var("A", "1")]).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].name, "B");
+        assert!(violations[0].missing);
+    }
+
+    #[test]
+    fn test_schema_rejects_unknown_fields() {
+        assert!(toml::from_str::<EnvSchema>("[keys.A]\nrequird = true\n").is_err());
+    }
+}
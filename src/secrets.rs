@@ -0,0 +1,180 @@
+use anyhow::{Context, Result, bail};
+use std::collections::{BTreeMap, HashMap};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::ssh::shell_quote;
+
+/// `secret://<provider>/<path>`: fetched with a command from `[secret_providers]`.
+const SECRET_SCHEME: &str = "secret://";
+/// `cmd://<command>`: the output of a shell command.
+const CMD_SCHEME: &str = "cmd://";
+/// Stands in for a reference that was not resolved. Never deployed: only
+/// read-only commands leave references unresolved.
+pub const UNRESOLVED: &str = "<unresolved secret>";
+
+/// What a command does with `secret://` and `cmd://` references.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Run the commands, as deploys need.
+    Resolve,
+    /// Leave references unresolved and keep their deployed values, for
+    /// commands that compare with the remote.
+    KeepDeployed,
+    /// Leave references unresolved and only check that they are set, for
+    /// commands that don't look at the remote.
+    CheckPresence,
+}
+
+struct Resolution {
+    mode: Mode,
+    /// Output of each command run so far, so a reference shared by several
+    /// stacks or env layers runs its command once per invocation.
+    cache: BTreeMap<String, String>,
+}
+
+static RESOLUTION: Mutex<Resolution> = Mutex::new(Resolution {
+    mode: Mode::Resolve,
+    cache: BTreeMap::new(),
+});
+
+/// Whether to run secret commands. Read-only commands leave references
+/// unresolved unless asked to resolve secrets, so previewing a change never
+/// runs `cmd://` commands or unlocks a vault.
+pub fn set_mode(mode: Mode) {
+    RESOLUTION.lock().unwrap().mode = mode;
+}
+
+pub fn mode() -> Mode {
+    RESOLUTION.lock().unwrap().mode
+}
+
+/// Whether an env value is, or was interpolated from, an unresolved
+/// reference.
+pub fn is_unresolved(value: &str) -> bool {
+    value.contains(UNRESOLVED)
+}
+
+/// Resolve an env value that references a secret, returning other values
+/// unchanged. Resolved values are only ever held in memory: callers send
+/// them to Portainer or the remote `.env`, and errors never include them.
+/// With resolution turned off, references become [`UNRESOLVED`].
+pub fn resolve(value: String, providers: &HashMap<String, String>) -> Result<String> {
+    if let Some(command) = value.strip_prefix(CMD_SCHEME) {
+        return cached_run(command);
+    }
+    let Some(reference) = value.strip_prefix(SECRET_SCHEME) else {
+        return Ok(value);
+    };
+    let (provider, path) = reference.split_once('/').unwrap_or((reference, ""));
+    if path.is_empty() {
+        bail!(
+            "Invalid secret reference '{}'. Expected {}<provider>/<path>.",
+            value,
+            SECRET_SCHEME
+        );
+    }
+    let template = providers.get(provider).context(format!(
+        "Unknown secret provider '{}'. Add a command for it under [secret_providers].",
+        provider
+    ))?;
+    cached_run(&provider_command(template, path))
+}
+
+fn cached_run(command: &str) -> Result<String> {
+    let mut resolution = RESOLUTION.lock().unwrap();
+    if resolution.mode != Mode::Resolve {
+        return Ok(UNRESOLVED.to_string());
+    }
+    if let Some(value) = resolution.cache.get(command) {
+        return Ok(value.clone());
+    }
+    let value = run(command)?;
+    resolution.cache.insert(command.to_string(), value.clone());
+    Ok(value)
+}
+
+/// The command for a provider, with the shell-quoted path substituted for
+/// `{path}`, or appended when the template has no placeholder.
+fn provider_command(template: &str, path: &str) -> String {
+    if template.contains("{path}") {
+        template.replace("{path}", &shell_quote(path))
+    } else {
+        format!("{} {}", template, shell_quote(path))
+    }
+}
+
+/// Run a secret command and return its output without the trailing newline.
+/// Stdin and stderr stay attached to the terminal so providers can prompt
+/// to unlock a vault.
+fn run(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .context(format!("Failed to run secret command: {}", command))?;
+    if !output.status.success() {
+        bail!(
+            "Secret command failed (exit {}): {}",
+            output.status.code().unwrap_or(-1),
+            command
+        );
+    }
+    let value = String::from_utf8(output.stdout).context(format!(
+        "Secret command returned invalid UTF-8: {}",
+        command
+    ))?;
+    Ok(value.trim_end_matches(['\n', '\r']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn providers() -> HashMap<String, String> {
+        HashMap::from([
+            ("echo".to_string(), "printf '%s\\n'".to_string()),
+            ("file".to_string(), "printf 'from %s' {path}".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_resolve_plain_value_unchanged() {
+        assert_eq!(resolve("plain".to_string(), &providers()).unwrap(), "plain");
+    }
+
+    #[test]
+    fn test_resolve_cmd() {
+        let value = resolve("cmd://printf 'hunter2\\n\\n'".to_string(), &providers()).unwrap();
+        assert_eq!(value, "hunter2");
+    }
+
+    #[test]
+    fn test_resolve_secret_provider() {
+        let value = resolve("secret://echo/homelab/it's".to_string(), &providers()).unwrap();
+        assert_eq!(value, "homelab/it's");
+        let value = resolve("secret://file/db".to_string(), &providers()).unwrap();
+        assert_eq!(value, "from db");
+    }
+
+    #[test]
+    fn test_resolve_runs_each_command_once() {
+        let runs =
+            std::env::temp_dir().join(format!("stack-sync-secret-runs-{}", std::process::id()));
+        let command = format!("cmd://echo x >> {0}; wc -l < {0}", runs.display());
+        assert_eq!(resolve(command.clone(), &providers()).unwrap().trim(), "1");
+        assert_eq!(resolve(command, &providers()).unwrap().trim(), "1");
+        std::fs::remove_file(runs).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let err = resolve("secret://vault/db".to_string(), &providers()).unwrap_err();
+        assert!(err.to_string().contains("Unknown secret provider 'vault'"));
+        assert!(resolve("secret://echo".to_string(), &providers()).is_err());
+        let err = resolve("cmd://exit 3".to_string(), &providers()).unwrap_err();
+        assert!(err.to_string().contains("exit 3"));
+    }
+}
@@ -1,13 +1,108 @@
 use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
 
+use crate::compose;
 use crate::config::SshGlobalConfig;
+use crate::lock::{LockAttempt, LockHolder, LockInfo, LockRecord};
+use crate::stamp::{DeployStamp, sha256_hex};
+
+/// Directory for the shared master connection sockets, created with mode
+/// 0700 under `$XDG_RUNTIME_DIR`, or `~/.ssh` when that isn't set.
+const CONTROL_DIR: &str = "stack-sync";
+
+/// Socket name inside [`CONTROL_DIR`]. `%C` hashes the connection
+/// parameters and keeps the path short enough for the socket length limit.
+const CONTROL_SOCKET: &str = "%C";
+
+/// How long an idle master connection is kept open after the last command.
+const CONTROL_PERSIST: &str = "60";
+
+/// Deployment metadata written alongside `compose.yaml`.
+const STAMP_FILE: &str = ".stack-sync.json";
+
+/// Paths of the extra files stack-sync uploaded for the stack, one per line,
+/// so later deploys know which ones to diff and prune.
+const FILES_MANIFEST: &str = ".stack-sync-files";
+
+/// `docker compose` with every compose file of the stack, in order:
+/// `compose.yaml`, then the overrides `compose.2.yaml`, `compose.3.yaml`, ...
+const DOCKER_COMPOSE: &str = "docker compose -f compose.yaml \
+     $(i=2; while [ -f compose.$i.yaml ]; do printf ' -f compose.%s.yaml' $i; i=$((i+1)); done)";
+
+/// Printed by the remote lock command when the lock file was created.
+const LOCK_ACQUIRED: &str = "__STACK_SYNC_LOCKED__";
+
+/// Printed by the remote lock command ahead of the holder's record when the
+/// lock file already exists.
+const LOCK_HELD: &str = "__STACK_SYNC_HELD__";
+
+/// Printed by the remote unlock command when a lock file was removed.
+const LOCK_REMOVED: &str = "__STACK_SYNC_UNLOCKED__";
+
+/// Snapshot of a stack on the remote host, gathered in a single round-trip.
+#[derive(Debug, Default, PartialEq)]
+pub struct RemoteStack {
+    /// The compose file, merged with any overrides the way they are
+    /// deployed.
+    pub compose: String,
+    pub env: Option<String>,
+    /// SHA-256 of each file uploaded next to the compose file, by relative
+    /// path. Empty for a file listed in the manifest but missing.
+    pub files: BTreeMap<String, String>,
+    /// A stack only counts as running when every service defined in the
+    /// compose file has a running container. Checking for "any container"
+    /// lets a stack that lost a service (crash + prune, manual rm) report
+    /// healthy forever, since sync skips `up -d` for unchanged files.
+    pub running: bool,
+    pub stamp: Option<DeployStamp>,
+}
+
+impl RemoteStack {
+    /// Whether the last deploy wrote the `.env` file, per its stamp.
+    pub fn wrote_env(&self) -> bool {
+        self.stamp.as_ref().is_some_and(|s| s.env_hmac.is_some())
+    }
+
+    /// The `.env` file to compare with the local env. When the stack has no
+    /// env, a `.env` that stack-sync didn't write is left alone, so it
+    /// doesn't count as a change.
+    pub fn env_for(&self, has_env: bool) -> Option<&str> {
+        if !has_env && !self.wrote_env() {
+            return None;
+        }
+        self.env.as_deref()
+    }
+}
+
+/// Everything `deploy_stack` uploads for a stack.
+#[derive(Debug, Clone, Copy)]
+pub struct DeployRequest<'a> {
+    /// The stack's compose files, in merge order.
+    pub compose_files: &'a [String],
+    pub env_content: Option<&'a str>,
+    /// Files uploaded next to the compose file, by relative path.
+    pub stack_files: &'a BTreeMap<String, Vec<u8>>,
+    /// Files uploaded by an earlier deploy that are no longer part of the
+    /// stack.
+    pub stale: &'a [String],
+    /// Remove the `.env` an earlier deploy wrote, now that the stack has no
+    /// env.
+    pub remove_env: bool,
+    pub stamp: &'a DeployStamp,
+    /// Pull images before bringing the stack up.
+    pub pull: bool,
+}
 
 pub struct SshClient {
     host: String,
     user: Option<String>,
     key: Option<String>,
     host_dir: String,
+    /// Multiplexing options, worked out on first use.
+    control: OnceLock<Vec<String>>,
 }
 
 impl SshClient {
@@ -17,6 +112,7 @@ impl SshClient {
             user: config.ssh_user.clone(),
             key: config.ssh_key.as_ref().map(|k| shellexpand_tilde(k)),
             host_dir: config.host_dir.clone(),
+            control: OnceLock::new(),
         }
     }
 
@@ -36,25 +132,62 @@ impl SshClient {
         args
     }
 
+    /// Options that let every ssh invocation share one multiplexed
+    /// connection, so only the first command pays for the handshake. Left
+    /// out when the user's ssh config already sets a `ControlPath`, or when
+    /// there's no private directory for the socket.
+    fn control_args(&self) -> &[String] {
+        self.control.get_or_init(|| {
+            if self.user_control_path() {
+                return Vec::new();
+            }
+            control_dir().map_or_else(Vec::new, |dir| multiplex_args(&dir))
+        })
+    }
+
+    /// Whether `ssh -G` reports a `ControlPath` for the destination.
+    fn user_control_path(&self) -> bool {
+        Command::new("ssh")
+            .arg("-G")
+            .args(self.ssh_args())
+            .arg(self.destination())
+            .output()
+            .is_ok_and(|output| sets_control_path(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn ssh_command(&self, remote_cmd: &str) -> Command {
+        let mut command = Command::new("ssh");
+        command
+            .args(self.control_args())
+            .args(self.ssh_args())
+            .arg(self.destination())
+            .arg(remote_cmd);
+        command
+    }
+
     pub fn stack_dir(&self, name: &str) -> String {
         format!("{}/{}", self.host_dir, name)
     }
 
-    fn compose_file_path(&self, name: &str) -> String {
-        format!("{}/compose.yaml", self.stack_dir(name))
+    /// Remote path of the stack's compose file at `index` in its list.
+    fn compose_file_path(&self, name: &str, index: usize) -> String {
+        match index {
+            0 => format!("{}/compose.yaml", self.stack_dir(name)),
+            i => format!("{}/compose.{}.yaml", self.stack_dir(name), i + 1),
+        }
     }
 
     fn env_file_path(&self, name: &str) -> String {
         format!("{}/.env", self.stack_dir(name))
     }
 
-    pub fn run_ssh(&self, cmd: &str) -> Result<String> {
-        let mut args = self.ssh_args();
-        args.push(self.destination());
-        args.push(cmd.to_string());
+    fn stamp_file_path(&self, name: &str) -> String {
+        format!("{}/{}", self.stack_dir(name), STAMP_FILE)
+    }
 
-        let output = Command::new("ssh")
-            .args(&args)
+    pub fn run_ssh(&self, cmd: &str) -> Result<String> {
+        let output = self
+            .ssh_command(cmd)
             .output()
             .context("Failed to execute ssh command")?;
 
@@ -70,95 +203,116 @@ impl SshClient {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
 
-    fn read_remote_file(&self, path: &str) -> Result<String> {
-        self.run_ssh(&format!("cat {}", path))
+    /// Fetch the compose file, env file and running state of a stack with a
+    /// single ssh invocation. Returns `None` when the stack has no compose
+    /// file on the host.
+    pub fn get_stack(&self, name: &str) -> Result<Option<RemoteStack>> {
+        let marker = section_marker();
+        let output = self.run_ssh(&stack_state_script(&self.stack_dir(name), &marker))?;
+        parse_stack_state(&output, &marker)
     }
 
-    pub fn stack_exists(&self, name: &str) -> Result<bool> {
-        let mut args = self.ssh_args();
-        args.push(self.destination());
-        args.push(format!("test -f {}", self.compose_file_path(name)));
-
-        let output = Command::new("ssh")
-            .args(&args)
-            .output()
-            .context("Failed to execute ssh command")?;
-
-        Ok(output.status.success())
-    }
-
-    /// A stack only counts as running when every service defined in the
-    /// compose file has a running container. Checking for "any container"
-    /// lets a stack that lost a service (crash + prune, manual rm) report
-    /// healthy forever, since sync skips `up -d` for unchanged files.
-    pub fn stack_is_running(&self, name: &str) -> Result<bool> {
+    /// Upload a stack and bring it up. Stale files are removed, as is the
+    /// `.env` file when asked to.
+    pub fn deploy_stack(&self, name: &str, request: &DeployRequest) -> Result<()> {
+        let DeployRequest {
+            compose_files,
+            env_content,
+            stack_files,
+            stale,
+            remove_env,
+            stamp,
+            pull,
+        } = *request;
         let dir = self.stack_dir(name);
-        let mut args = self.ssh_args();
-        args.push(self.destination());
-        args.push(format!(
-            "cd {} && docker compose config --services 2>/dev/null && echo __SEP__ && docker compose ps --services --status running 2>/dev/null",
-            dir
-        ));
-
-        let output = Command::new("ssh")
-            .args(&args)
-            .output()
-            .context("Failed to execute ssh command")?;
 
-        if !output.status.success() {
-            return Ok(false);
+        // Create directories
+        let mut dirs = vec![shell_quote(&dir)];
+        for path in stack_files.keys() {
+            if let Some((parent, _)) = path.rsplit_once('/') {
+                dirs.push(shell_quote(&format!("{}/{}", dir, parent)));
+            }
         }
+        self.run_ssh(&format!("mkdir -p {}", dirs.join(" ")))?;
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let Some((defined, running)) = stdout.split_once("__SEP__") else {
-            return Ok(false);
-        };
-        let defined: Vec<&str> = defined.split_whitespace().collect();
-        let running: Vec<&str> = running.split_whitespace().collect();
-        Ok(!defined.is_empty() && defined.iter().all(|s| running.contains(s)))
-    }
-
-    pub fn deploy_stack(
-        &self,
-        name: &str,
-        compose_content: &str,
-        env_content: Option<&str>,
-        pull: bool,
-    ) -> Result<()> {
-        let dir = self.stack_dir(name);
-
-        // Create directory
-        self.run_ssh(&format!("mkdir -p {}", dir))?;
-
-        // Write compose file via stdin to avoid temp files
-        let compose_path = self.compose_file_path(name);
-        self.write_remote_file(&compose_path, compose_content)?;
-
-        // Write env file if provided
+        // Stage every file next to its target and only swap them in once all
+        // uploads are verified, so a dropped connection during the upload
+        // never leaves a truncated compose or env file behind. The swap is
+        // best-effort: each rename is atomic, but the renames run one after
+        // another, so a failure part-way leaves a mix of old and new files
+        // until the next sync.
+        let mut files: Vec<(String, &[u8])> = compose_files
+            .iter()
+            .enumerate()
+            .map(|(i, content)| (self.compose_file_path(name, i), content.as_bytes()))
+            .collect();
         if let Some(env) = env_content {
-            let env_path = self.env_file_path(name);
-            self.write_remote_file(&env_path, env)?;
+            files.push((self.env_file_path(name), env.as_bytes()));
+        }
+        for (path, content) in stack_files {
+            files.push((format!("{}/{}", dir, path), content));
+        }
+        let manifest: String = stack_files
+            .keys()
+            .map(|path| format!("{}\n", path))
+            .collect();
+        files.push((format!("{}/{}", dir, FILES_MANIFEST), manifest.as_bytes()));
+        let stamp = serde_json::to_string_pretty(stamp)?;
+        files.push((self.stamp_file_path(name), stamp.as_bytes()));
+        let mut staged: Vec<String> = Vec::new();
+        let mut renames = Vec::new();
+        for (path, content) in files {
+            let temp = staging_path(&path);
+            staged.push(temp.clone());
+            if let Err(e) = self.upload_file(&temp, content) {
+                self.discard(&staged);
+                return Err(e);
+            }
+            renames.push(format!(
+                "mv -f {} {}",
+                shell_quote(&temp),
+                shell_quote(&path)
+            ));
+        }
+        // Removals only run once every new file is in place
+        renames.push(stale_overrides_removal(&dir, compose_files.len()));
+        let mut removed: Vec<String> = stale
+            .iter()
+            .map(|path| shell_quote(&format!("{}/{}", dir, path)))
+            .collect();
+        if remove_env {
+            removed.push(shell_quote(&self.env_file_path(name)));
+        }
+        if !removed.is_empty() {
+            renames.push(format!("rm -f {}", removed.join(" ")));
+        }
+        if let Err(e) = self.run_ssh(&renames.join(" && ")) {
+            self.discard(&staged);
+            return Err(e.context("Failed to move uploaded files into place"));
         }
 
         if pull {
             self.run_ssh(&format!(
-                "cd {} && docker compose pull && docker compose up -d",
-                dir
+                "cd {0} && {1} pull && {1} up -d",
+                shell_quote(&dir),
+                DOCKER_COMPOSE
             ))?;
         } else {
-            self.run_ssh(&format!("cd {} && docker compose up -d", dir))?;
+            self.run_ssh(&format!(
+                "cd {} && {} up -d",
+                shell_quote(&dir),
+                DOCKER_COMPOSE
+            ))?;
         }
 
         Ok(())
     }
 
-    fn write_remote_file(&self, remote_path: &str, content: &str) -> Result<()> {
-        let mut args = self.ssh_args();
-        args.push(self.destination());
-        args.push(format!("cat > {}", remote_path));
-
-        let mut child = Command::new("ssh")
-            .args(&args)
+    /// Run a remote command with `input` piped to its stdin. The raw output is
+    /// returned so callers can interpret the exit status themselves.
+    fn run_with_input(&self, cmd: &str, input: &[u8]) -> Result<std::process::Output> {
+        let mut child = self
+            .ssh_command(cmd)
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped())
@@ -168,15 +322,27 @@ impl SshClient {
         use std::io::Write;
         if let Some(ref mut stdin) = child.stdin {
             stdin
-                .write_all(content.as_bytes())
+                .write_all(input)
                 .context("Failed to write to ssh stdin")?;
         }
-        // Drop stdin to close it so the remote cat exits
+        // Drop stdin to close it so the remote command sees EOF
         drop(child.stdin.take());
 
-        let output = child
+        child
             .wait_with_output()
-            .context("Failed to wait for ssh command")?;
+            .context("Failed to wait for ssh command")
+    }
+
+    /// Stream `content` into `remote_path` and verify the SHA-256 of what
+    /// landed on the host matches what was sent.
+    fn upload_file(&self, remote_path: &str, content: &[u8]) -> Result<()> {
+        let output = self.run_with_input(
+            &format!(
+                "cat > {0} && (sha256sum {0} 2>/dev/null || shasum -a 256 {0})",
+                shell_quote(remote_path)
+            ),
+            content,
+        )?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
@@ -188,45 +354,125 @@ impl SshClient {
             );
         }
 
-        Ok(())
-    }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let remote_sum = stdout.split_whitespace().next().unwrap_or_default();
+        if remote_sum != sha256_hex(content) {
+            anyhow::bail!(
+                "Checksum mismatch after writing remote file {}",
+                remote_path
+            );
+        }
 
-    pub fn stop_stack(&self, name: &str) -> Result<()> {
-        let dir = self.stack_dir(name);
-        self.run_ssh(&format!("cd {} && docker compose down", dir))?;
         Ok(())
     }
 
-    pub fn get_compose_content(&self, name: &str) -> Result<String> {
-        self.read_remote_file(&self.compose_file_path(name))
+    /// Best-effort removal of staged uploads after a failed deploy. Errors are
+    /// ignored so the original failure is the one reported.
+    fn discard(&self, paths: &[String]) {
+        let paths: Vec<String> = paths.iter().map(|p| shell_quote(p)).collect();
+        let _ = self.run_ssh(&format!("rm -f {}", paths.join(" ")));
     }
 
-    pub fn get_env_content(&self, name: &str) -> Result<Option<String>> {
-        let env_path = self.env_file_path(name);
-        let mut args = self.ssh_args();
-        args.push(self.destination());
-        args.push(format!("test -f {} && cat {}", env_path, env_path));
+    fn lock_file_path(&self, name: &str) -> String {
+        format!("{}/.stack-sync-locks/{}.lock", self.host_dir, name)
+    }
 
-        let output = Command::new("ssh")
-            .args(&args)
-            .output()
-            .context("Failed to execute ssh command")?;
+    /// Create the lock file with noclobber so creation is atomic; if it
+    /// already exists, the current holder's record is printed instead. The
+    /// lock's age comes from the record, not the file's mtime, which has no
+    /// portable shell command.
+    pub fn try_lock(&self, name: &str, info: &LockInfo) -> Result<LockAttempt> {
+        let path = self.lock_file_path(name);
+        let quoted = shell_quote(&path);
+        let output = self.run_with_input(
+            &format!(
+                "mkdir -p {} && {{ (set -C; cat > {}) 2>/dev/null && echo {} || \
+                 {{ echo {}; cat {} 2>/dev/null; true; }}; }}",
+                shell_quote(&format!("{}/.stack-sync-locks", self.host_dir)),
+                quoted,
+                LOCK_ACQUIRED,
+                LOCK_HELD,
+                quoted
+            ),
+            serde_json::to_string(info)?.as_bytes(),
+        )?;
 
         if !output.status.success() {
-            return Ok(None);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!(
+                "Failed to take lock {} (exit {}): {}",
+                path,
+                output.status.code().unwrap_or(-1),
+                stderr.trim()
+            );
         }
 
-        let content = String::from_utf8_lossy(&output.stdout).to_string();
-        if content.trim().is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(content))
+        Ok(parse_lock_output(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Remove the lock file `info` wrote, if it's still ours.
+    pub fn release_lock(&self, name: &str, info: &LockInfo) -> Result<()> {
+        self.remove_lock_file(name, &serde_json::to_string(info)?)
+    }
+
+    /// Remove the lock file only if it still holds `record`, so a lock
+    /// someone else took in the meantime survives.
+    pub fn remove_lock_file(&self, name: &str, record: &str) -> Result<()> {
+        let output = self.run_with_input(
+            &remove_if_unchanged(&self.lock_file_path(name)),
+            record.as_bytes(),
+        )?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!(
+                "Failed to remove lock for {} (exit {}): {}",
+                name,
+                output.status.code().unwrap_or(-1),
+                stderr.trim()
+            );
         }
+        Ok(())
+    }
+
+    /// Remove the lock file regardless of who holds it. Returns whether a
+    /// lock was present.
+    pub fn force_unlock(&self, name: &str) -> Result<bool> {
+        let output = self.run_ssh(&format!(
+            "rm {} 2>/dev/null && echo {} || true",
+            shell_quote(&self.lock_file_path(name)),
+            LOCK_REMOVED
+        ))?;
+        Ok(output.trim() == LOCK_REMOVED)
+    }
+
+    /// The `RepoDigests` of an image on the host. Returns `None` when the
+    /// image isn't present or was never pulled from a registry.
+    pub fn image_repo_digests(&self, image: &str) -> Result<Option<Vec<String>>> {
+        let output = self.run_ssh(&format!(
+            "docker image inspect --format '{{{{join .RepoDigests \" \"}}}}' {} 2>/dev/null || true",
+            shell_quote(image)
+        ))?;
+        let digests: Vec<String> = output.split_whitespace().map(|d| d.to_string()).collect();
+        Ok((!digests.is_empty()).then_some(digests))
+    }
+
+    pub fn stop_stack(&self, name: &str) -> Result<()> {
+        let dir = self.stack_dir(name);
+        self.run_ssh(&format!(
+            "cd {} && {} down",
+            shell_quote(&dir),
+            DOCKER_COMPOSE
+        ))?;
+        Ok(())
     }
 
     pub fn docker_compose_ps(&self, name: &str) -> Result<String> {
         let dir = self.stack_dir(name);
-        self.run_ssh(&format!("cd {} && docker compose ps", dir))
+        self.run_ssh(&format!(
+            "cd {} && {} ps",
+            shell_quote(&dir),
+            DOCKER_COMPOSE
+        ))
     }
 
     pub fn host(&self) -> &str {
@@ -234,7 +480,84 @@ impl SshClient {
     }
 }
 
-fn shellexpand_tilde(path: &str) -> String {
+/// Quote a value for a POSIX shell command line.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Create (or tighten) the per-user socket directory.
+fn control_dir() -> Option<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let base = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".ssh"),
+    };
+    let dir = base.join(CONTROL_DIR);
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .ok()?;
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).ok()?;
+    Some(dir)
+}
+
+fn multiplex_args(dir: &Path) -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        format!("ControlPath={}", dir.join(CONTROL_SOCKET).display()),
+        "-o".to_string(),
+        format!("ControlPersist={}", CONTROL_PERSIST),
+    ]
+}
+
+/// Whether `ssh -G` output has a `controlpath` other than `none`.
+fn sets_control_path(ssh_config: &str) -> bool {
+    ssh_config.lines().any(|line| {
+        line.strip_prefix("controlpath ")
+            .is_some_and(|path| path.trim() != "none")
+    })
+}
+
+/// Read the output of the remote lock command.
+fn parse_lock_output(stdout: &str) -> LockAttempt {
+    if stdout.trim() == LOCK_ACQUIRED {
+        return LockAttempt::Acquired;
+    }
+    let (_, record) = stdout.split_once('\n').unwrap_or((stdout, ""));
+    let info: Option<LockInfo> = serde_json::from_str(record.trim()).ok();
+    LockAttempt::Held(LockHolder {
+        age: info.as_ref().map(LockInfo::age),
+        info,
+        record: LockRecord::File(record.to_string()),
+    })
+}
+
+/// Remove `path` when its content matches stdin.
+fn remove_if_unchanged(path: &str) -> String {
+    let path = shell_quote(path);
+    format!("cmp -s - {0} && rm -f {0}; true", path)
+}
+
+/// Remove compose overrides beyond the first `count` compose files, left
+/// from a longer list; `docker compose` would still pick them up.
+fn stale_overrides_removal(dir: &str, count: usize) -> String {
+    let kept: Vec<String> = (2..=count).map(|i| format!("compose.{}.yaml", i)).collect();
+    let glob = format!("{}/compose.[0-9]*.yaml", shell_quote(dir));
+    if kept.is_empty() {
+        return format!("rm -f {}", glob);
+    }
+    format!(
+        "for f in {}; do case \"${{f##*/}}\" in {}) ;; *) rm -f \"$f\" ;; esac; done",
+        glob,
+        kept.join("|")
+    )
+}
+
+pub fn shellexpand_tilde(path: &str) -> String {
     if let Some(rest) = path.strip_prefix("~/")
         && let Ok(home) = std::env::var("HOME")
     {
@@ -243,6 +566,106 @@ fn shellexpand_tilde(path: &str) -> String {
     path.to_string()
 }
 
+/// Temporary upload path for `path`, in the same directory so the final
+/// rename stays on one filesystem and is atomic.
+fn staging_path(path: &str) -> String {
+    format!("{}.stack-sync-tmp", path)
+}
+
+/// A per-call marker for delimiting sections of [`stack_state_script`] output.
+/// Unique enough that it won't collide with compose or env file content.
+fn section_marker() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("__STACK_SYNC_{:x}_{:x}__", std::process::id(), nanos)
+}
+
+/// Shell script that prints every piece of remote state as a delimited
+/// section. Each section header is preceded by a newline so file contents
+/// without a trailing newline are preserved exactly. Prints nothing when the
+/// stack has no compose file, and always ends with an `end` section so a
+/// truncated response can be detected.
+fn stack_state_script(dir: &str, marker: &str) -> String {
+    let section = |name: &str| format!("printf '\\n{} {}\\n'", marker, name);
+    [
+        format!(
+            "cd {} 2>/dev/null && [ -f compose.yaml ] || exit 0",
+            shell_quote(dir)
+        ),
+        format!("{}; cat compose.yaml", section("compose")),
+        format!(
+            "i=2; while [ -f compose.$i.yaml ]; do printf '\\n{} compose.%s\\n' $i; \
+             cat compose.$i.yaml; i=$((i+1)); done",
+            marker
+        ),
+        format!("{}; [ -f .env ] && cat .env", section("env")),
+        format!("{0}; [ -f {1} ] && cat {1}", section("stamp"), STAMP_FILE),
+        format!(
+            "[ -f {0} ] && while IFS= read -r f; do printf '\\n{1} file:%s\\n' \"$f\"; \
+             [ -f \"$f\" ] && {{ sha256sum \"$f\" 2>/dev/null || shasum -a 256 \"$f\"; }}; \
+             done < {0}",
+            FILES_MANIFEST, marker
+        ),
+        format!(
+            "{}; {} config --services 2>/dev/null",
+            section("services"),
+            DOCKER_COMPOSE
+        ),
+        format!(
+            "{}; {} ps --services --status running 2>/dev/null",
+            section("running"),
+            DOCKER_COMPOSE
+        ),
+        section("end"),
+    ]
+    .join("\n")
+}
+
+fn parse_stack_state(output: &str, marker: &str) -> Result<Option<RemoteStack>> {
+    let delimiter = format!("\n{} ", marker);
+    let sections: HashMap<&str, &str> = output
+        .split(delimiter.as_str())
+        .skip(1)
+        .filter_map(|part| part.split_once('\n'))
+        .collect();
+
+    if sections.is_empty() {
+        return Ok(None);
+    }
+    if !sections.contains_key("end") {
+        anyhow::bail!("Incomplete response from remote host");
+    }
+
+    let section = |name: &str| sections.get(name).copied().unwrap_or_default();
+    let mut compose_files = vec![section("compose").to_string()];
+    while let Some(file) = sections.get(format!("compose.{}", compose_files.len() + 1).as_str()) {
+        compose_files.push(file.to_string());
+    }
+    // An override that doesn't parse still differs from the local files, so
+    // sync replaces it
+    let compose = compose::merge_files(&compose_files).unwrap_or_else(|_| compose_files.join("\n"));
+    let files = sections
+        .iter()
+        .filter_map(|(name, content)| {
+            let sum = content.split_whitespace().next().unwrap_or_default();
+            Some((name.strip_prefix("file:")?.to_string(), sum.to_string()))
+        })
+        .collect();
+    let env = section("env");
+    let defined: Vec<&str> = section("services").split_whitespace().collect();
+    let running: Vec<&str> = section("running").split_whitespace().collect();
+
+    Ok(Some(RemoteStack {
+        compose,
+        env: (!env.trim().is_empty()).then(|| env.to_string()),
+        files,
+        running: !defined.is_empty() && defined.iter().all(|s| running.contains(s)),
+        stamp: serde_json::from_str(section("stamp")).ok(),
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -292,9 +715,13 @@ mod tests {
     fn test_compose_file_path() {
         let client = SshClient::new(&test_config(None, None));
         assert_eq!(
-            client.compose_file_path("my-app"),
+            client.compose_file_path("my-app", 0),
             "/mnt/docker/my-app/compose.yaml"
         );
+        assert_eq!(
+            client.compose_file_path("my-app", 1),
+            "/mnt/docker/my-app/compose.2.yaml"
+        );
     }
 
     #[test]
@@ -303,6 +730,318 @@ mod tests {
         assert_eq!(client.env_file_path("my-app"), "/mnt/docker/my-app/.env");
     }
 
+    #[test]
+    fn test_lock_file_path() {
+        let client = SshClient::new(&test_config(None, None));
+        assert_eq!(
+            client.lock_file_path("my-app"),
+            "/mnt/docker/.stack-sync-locks/my-app.lock"
+        );
+    }
+
+    #[test]
+    fn test_stamp_file_path() {
+        let client = SshClient::new(&test_config(None, None));
+        assert_eq!(
+            client.stamp_file_path("my-app"),
+            "/mnt/docker/my-app/.stack-sync.json"
+        );
+    }
+
+    #[test]
+    fn test_parse_stack_state_stamp() {
+        let output = state_output(
+            "M",
+            &[
+                ("compose", "services: {}\n"),
+                (
+                    "stamp",
+                    r#"{"deployed_by":"alice","deployed_at":1700000000,"machine":"laptop","git_commit":"abc123","compose_sha256":"00","env_hmac":null,"env_salt":""}"#,
+                ),
+                ("end", ""),
+            ],
+        );
+        let stack = parse_stack_state(&output, "M").unwrap().unwrap();
+        let stamp = stack.stamp.unwrap();
+        assert_eq!(stamp.deployed_by, "alice");
+        assert_eq!(stamp.git_commit.as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_env_for_leaves_unmanaged_env_alone() {
+        let stamp = |env_hmac: Option<&str>| DeployStamp {
+            deployed_by: "alice".to_string(),
+            deployed_at: 1700000000,
+            machine: "laptop".to_string(),
+            git_commit: None,
+            compose_sha256: "00".to_string(),
+            env_hmac: env_hmac.map(String::from),
+            env_salt: String::new(),
+        };
+        // A `.env` that was there before stack-sync, with no stamp or a
+        // stamp that wrote no env, is neither compared nor removed
+        let mut remote = RemoteStack {
+            env: Some("MANUAL=1\n".to_string()),
+            ..Default::default()
+        };
+        for previous in [None, Some(stamp(None))] {
+            remote.stamp = previous;
+            assert!(!remote.wrote_env());
+            assert_eq!(remote.env_for(false), None);
+            assert_eq!(remote.env_for(true), Some("MANUAL=1\n"));
+        }
+
+        remote.stamp = Some(stamp(Some("ff")));
+        assert!(remote.wrote_env());
+        assert_eq!(remote.env_for(false), Some("MANUAL=1\n"));
+    }
+
+    #[test]
+    fn test_staging_path_is_next_to_target() {
+        assert_eq!(
+            staging_path("/mnt/docker/my-app/compose.yaml"),
+            "/mnt/docker/my-app/compose.yaml.stack-sync-tmp"
+        );
+    }
+
+    #[test]
+    fn test_multiplex_args() {
+        let args = multiplex_args(Path::new("/run/user/1000/stack-sync"));
+        assert!(args.contains(&"ControlMaster=auto".to_string()));
+        assert!(args.contains(&"ControlPath=/run/user/1000/stack-sync/%C".to_string()));
+        assert!(args.iter().any(|a| a.starts_with("ControlPersist=")));
+    }
+
+    #[test]
+    fn test_sets_control_path() {
+        assert!(!sets_control_path("user root\ncontrolmaster false\n"));
+        assert!(!sets_control_path("controlpath none\n"));
+        assert!(sets_control_path(
+            "controlmaster auto\ncontrolpath /home/me/.ssh/cm-%C\n"
+        ));
+    }
+
+    fn state_output(marker: &str, sections: &[(&str, &str)]) -> String {
+        sections
+            .iter()
+            .map(|(name, body)| format!("\n{} {}\n{}", marker, name, body))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_stack_state_missing_stack() {
+        assert_eq!(parse_stack_state("", "M").unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_stack_state_running() {
+        let output = state_output(
+            "M",
+            &[
+                ("compose", "services:\n  web:\n    image: nginx"),
+                ("env", "FOO=bar\n"),
+                ("services", "web\ndb\n"),
+                ("running", "db\nweb\n"),
+                ("end", ""),
+            ],
+        );
+        let stack = parse_stack_state(&output, "M").unwrap().unwrap();
+        assert_eq!(stack.compose, "services:\n  web:\n    image: nginx");
+        assert_eq!(stack.env.as_deref(), Some("FOO=bar\n"));
+        assert!(stack.running);
+    }
+
+    #[test]
+    fn test_parse_stack_state_missing_service_not_running() {
+        let output = state_output(
+            "M",
+            &[
+                ("compose", "services: {}\n"),
+                ("env", ""),
+                ("services", "web\ndb\n"),
+                ("running", "db\n"),
+                ("end", ""),
+            ],
+        );
+        let stack = parse_stack_state(&output, "M").unwrap().unwrap();
+        assert_eq!(stack.env, None);
+        assert!(!stack.running);
+    }
+
+    #[test]
+    fn test_parse_stack_state_truncated() {
+        let output = state_output("M", &[("compose", "services: {}\n")]);
+        assert!(parse_stack_state(&output, "M").is_err());
+    }
+
+    #[test]
+    fn test_parse_stack_state_merges_overrides() {
+        let output = state_output(
+            "M",
+            &[
+                ("compose", "services:\n  web:\n    image: nginx:1.25\n"),
+                ("compose.2", "services:\n  web:\n    image: nginx:1.27\n"),
+                ("env", ""),
+                ("end", ""),
+            ],
+        );
+        let stack = parse_stack_state(&output, "M").unwrap().unwrap();
+        assert_eq!(stack.compose, "services:\n  web:\n    image: nginx:1.27\n");
+    }
+
+    #[test]
+    fn test_stack_state_script_reads_overrides() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("compose.yaml"), "base").unwrap();
+        std::fs::write(dir.join("compose.2.yaml"), "second").unwrap();
+        std::fs::write(dir.join("compose.3.yaml"), "third").unwrap();
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(stack_state_script(&dir.display().to_string(), "M"))
+            .output()
+            .unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            output.contains("\nM compose\nbase\nM compose.2\nsecond\nM compose.3\nthird\nM env")
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_stack_state_script_reads_uploaded_files() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-files-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nginx")).unwrap();
+        std::fs::write(dir.join("compose.yaml"), "services: {}\n").unwrap();
+        std::fs::write(dir.join("nginx/site conf"), "server {}\n").unwrap();
+        std::fs::write(dir.join(FILES_MANIFEST), "nginx/site conf\ngone.conf\n").unwrap();
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(stack_state_script(&dir.display().to_string(), "M"))
+            .output()
+            .unwrap();
+        let stack = parse_stack_state(&String::from_utf8(output.stdout).unwrap(), "M")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            stack.files,
+            BTreeMap::from([
+                ("gone.conf".to_string(), String::new()),
+                ("nginx/site conf".to_string(), sha256_hex("server {}\n")),
+            ])
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_stack_state_script_uses_marker_sections() {
+        let script = stack_state_script("/mnt/docker/my-app", "M");
+        assert!(script.starts_with("cd '/mnt/docker/my-app' "));
+        for name in ["compose", "env", "stamp", "services", "running", "end"] {
+            assert!(script.contains(&format!("M {}", name)));
+        }
+    }
+
+    #[test]
+    fn test_stale_overrides_removal() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-overrides-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in [
+            "compose.yaml",
+            "compose.2.yaml",
+            "compose.3.yaml",
+            "compose.4.yaml",
+        ] {
+            std::fs::write(dir.join(file), "services: {}\n").unwrap();
+        }
+        let dir_str = dir.display().to_string();
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(stale_overrides_removal(&dir_str, 2))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(dir.join("compose.2.yaml").exists());
+        assert!(!dir.join("compose.3.yaml").exists());
+        assert!(!dir.join("compose.4.yaml").exists());
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(stale_overrides_removal(&dir_str, 1))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(dir.join("compose.yaml").exists());
+        assert!(!dir.join("compose.2.yaml").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_parse_lock_output() {
+        assert!(matches!(
+            parse_lock_output(&format!("{}\n", LOCK_ACQUIRED)),
+            LockAttempt::Acquired
+        ));
+        let record = r#"{"owner":"alice","machine":"laptop","pid":42,"acquired":1700000000}"#;
+        let LockAttempt::Held(holder) = parse_lock_output(&format!("{}\n{}", LOCK_HELD, record))
+        else {
+            panic!("expected a held lock");
+        };
+        // The age comes from the record's timestamp
+        assert_eq!(holder.age, Some(crate::lock::now() - 1700000000));
+        assert!(holder.is_stale());
+        assert_eq!(holder.info.unwrap().pid, 42);
+        assert!(matches!(holder.record, LockRecord::File(r) if r == record));
+
+        let LockAttempt::Held(holder) = parse_lock_output(&format!("{}\n{{\"own", LOCK_HELD))
+        else {
+            panic!("expected a held lock");
+        };
+        assert!(holder.info.is_none());
+        assert_eq!(holder.age, None);
+        assert!(!holder.is_stale());
+    }
+
+    #[test]
+    fn test_remove_if_unchanged() {
+        let dir = std::env::temp_dir().join(format!("stack-sync-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("web.lock");
+        std::fs::write(&path, "theirs").unwrap();
+        let remove = |record: &str| {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(remove_if_unchanged(&path.display().to_string()))
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            use std::io::Write;
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(record.as_bytes())
+                .unwrap();
+            assert!(child.wait().unwrap().success());
+        };
+
+        remove("ours");
+        assert!(path.exists());
+        remove("theirs");
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("nginx:1.25"), "'nginx:1.25'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
     #[test]
     fn test_shellexpand_tilde() {
         // Test with ~ prefix
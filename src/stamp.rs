@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::{Config, EnvVar};
+use crate::lock;
+
+/// Record of who deployed a stack, from where, and what content went out.
+/// Written next to the compose file in SSH mode and saved with the stack as
+/// an env var in Portainer mode, since Portainer's `updatedBy` only names the
+/// API key owner.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeployStamp {
+    pub deployed_by: String,
+    pub deployed_at: u64,
+    pub machine: String,
+    /// HEAD of the git repo holding the config, suffixed with `-dirty` when
+    /// the working tree has uncommitted changes.
+    pub git_commit: Option<String>,
+    pub compose_sha256: String,
+    /// HMAC-SHA256 of the env content keyed with `env_salt`. The key is
+    /// stored in the stamp, so this only defeats precomputed tables: anyone
+    /// who can read the stamp can still check a guessed env against it.
+    pub env_hmac: Option<String>,
+    /// Random key for `env_hmac`, new for every deploy.
+    #[serde(default)]
+    pub env_salt: String,
+}
+
+impl DeployStamp {
+    pub fn new(config: &Config, compose_content: &str, env_content: Option<&str>) -> Self {
+        let env_salt = hex(&rand::random::<[u8; 16]>());
+        Self {
+            deployed_by: lock::current_owner(),
+            deployed_at: lock::now(),
+            machine: lock::current_machine(),
+            git_commit: git_commit(&config.base_dir),
+            compose_sha256: sha256_hex(compose_content),
+            env_hmac: env_content.map(|env| hmac_hex(&env_salt, env)),
+            env_salt,
+        }
+    }
+
+    /// Stamp for a Portainer deploy, where env vars are sent as a list rather
+    /// than a file. They are hashed in `NAME=value` form.
+    pub fn for_env_vars(config: &Config, compose_content: &str, env_vars: &[EnvVar]) -> Self {
+        Self::new(
+            config,
+            compose_content,
+            env_vars_content(env_vars).as_deref(),
+        )
+    }
+
+    /// Whether the given content is exactly what this stamp recorded.
+    pub fn matches(&self, compose_content: &str, env_content: Option<&str>) -> bool {
+        self.compose_sha256 == sha256_hex(compose_content)
+            && self.env_hmac == env_content.map(|env| hmac_hex(&self.env_salt, env))
+    }
+}
+
+/// Env vars rendered as `NAME=value` lines, the form hashed for stamps.
+pub fn env_vars_content(env_vars: &[EnvVar]) -> Option<String> {
+    if env_vars.is_empty() {
+        return None;
+    }
+    Some(
+        env_vars
+            .iter()
+            .map(|v| format!("{}={}\n", v.name, v.value))
+            .collect(),
+    )
+}
+
+pub fn sha256_hex(content: impl AsRef<[u8]>) -> String {
+    use sha2::{Digest, Sha256};
+    hex(&Sha256::digest(content))
+}
+
+fn hmac_hex(key: &str, content: &str) -> String {
+    use hmac::{Hmac, Mac};
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(content.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn git_commit(dir: &Path) -> Option<String> {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    let commit = git(&["rev-parse", "HEAD"])?;
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+    Some(if dirty {
+        format!("{}-dirty", commit)
+    } else {
+        commit
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            name: "my-stack".to_string(),
+            compose_files: vec!["compose.yaml".to_string()],
+            compose_template: false,
+            env_files: vec![],
+            env: Default::default(),
+            common_env: Default::default(),
+            env_strategy: Default::default(),
+            ignore_env: vec![],
+            files: vec![],
+            env_schema: None,
+            host: "example".to_string(),
+            endpoint_id: 2,
+            enabled: true,
+            base_dir: std::env::temp_dir(),
+            vars: Default::default(),
+            secret_providers: Default::default(),
+            age_identity: None,
+            docker_validate: false,
+            portainer_stamp: true,
+        }
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_stamp_hashes_content() {
+        let stamp = DeployStamp::new(&test_config(), "abc", None);
+        assert_eq!(stamp.compose_sha256, sha256_hex("abc"));
+        assert_eq!(stamp.env_hmac, None);
+    }
+
+    #[test]
+    fn test_stamp_for_env_vars_hashes_as_dotenv() {
+        let vars = vec![EnvVar {
+            name: "FOO".to_string(),
+            value: "bar".to_string(),
+        }];
+        let stamp = DeployStamp::for_env_vars(&test_config(), "abc", &vars);
+        assert_eq!(stamp.env_hmac, Some(hmac_hex(&stamp.env_salt, "FOO=bar\n")));
+    }
+
+    #[test]
+    fn test_env_hash_is_salted() {
+        let a = DeployStamp::new(&test_config(), "abc", Some("PASSWORD=hunter2"));
+        let b = DeployStamp::new(&test_config(), "abc", Some("PASSWORD=hunter2"));
+        assert_ne!(a.env_salt, b.env_salt);
+        assert_ne!(a.env_hmac, b.env_hmac);
+        assert_ne!(a.env_hmac, Some(sha256_hex("PASSWORD=hunter2")));
+    }
+
+    #[test]
+    fn test_hmac_hex() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hmac_hex("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_stamp_matches() {
+        let stamp = DeployStamp::new(&test_config(), "abc", Some("FOO=bar"));
+        assert!(stamp.matches("abc", Some("FOO=bar")));
+        assert!(!stamp.matches("abc", None));
+        assert!(!stamp.matches("abcd", Some("FOO=bar")));
+    }
+
+    #[test]
+    fn test_stamp_json_round_trip() {
+        let stamp = DeployStamp::new(&test_config(), "abc", Some("FOO=bar"));
+        let json = serde_json::to_string(&stamp).unwrap();
+        assert_eq!(serde_json::from_str::<DeployStamp>(&json).unwrap(), stamp);
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::{Result, bail};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+/// Render a compose template: `{{ name }}` is replaced with the value of
+/// `name`. Anything else between braces, like Go templates in labels
+/// (`{{.Name}}`), is left as written, as are docker's own `${VAR}`
+/// references, which are resolved on the host.
+pub fn render(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let placeholder = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_-]*)\s*\}\}").unwrap();
+    let mut rendered = Vec::new();
+    for (i, line) in template.split('\n').enumerate() {
+        let mut unknown = None;
+        let line = placeholder.replace_all(line, |caps: &Captures| {
+            let name = &caps[1];
+            match vars.get(name) {
+                Some(value) => value.clone(),
+                None => {
+                    unknown.get_or_insert_with(|| name.to_string());
+                    String::new()
+                }
+            }
+        });
+        if let Some(name) = unknown {
+            bail!(
+                "Line {}: unknown template variable '{}'. Set it in [vars] or the stack's vars table.",
+                i + 1,
+                name
+            );
+        }
+        rendered.push(line.into_owned());
+    }
+    Ok(rendered.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HashMap<String, String> {
+        HashMap::from([
+            ("domain".to_string(), "example.com".to_string()),
+            ("replicas".to_string(), "3".to_string()),
+            ("stack".to_string(), "web".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_render_substitutes_vars() {
+        let template = "services:\n  {{stack}}:\n    deploy:\n      replicas: {{ replicas }}\n    \
+                        labels:\n      - traefik.http.routers.web.rule=Host(`{{ domain }}`)\n";
+        assert_eq!(
+            render(template, &vars()).unwrap(),
+            "services:\n  web:\n    deploy:\n      replicas: 3\n    \
+             labels:\n      - traefik.http.routers.web.rule=Host(`example.com`)\n"
+        );
+    }
+
+    #[test]
+    fn test_render_leaves_other_braces() {
+        let template = "format: '{{.Names}}'\nimage: app:${TAG:-latest}\n";
+        assert_eq!(render(template, &vars()).unwrap(), template);
+    }
+
+    #[test]
+    fn test_render_unknown_var() {
+        let err = render("a: 1\nb: {{ missing }}\n", &vars()).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Line 2: unknown template variable 'missing'")
+        );
+    }
+}
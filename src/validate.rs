@@ -0,0 +1,625 @@
+use regex::Regex;
+use serde_yaml::Value;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::LazyLock;
+
+use crate::config::EnvVar;
+
+/// Top-level keys of the compose specification. `x-` extensions are
+/// allowed too.
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "version", "name", "include", "services", "networks", "volumes", "configs", "secrets", "models",
+];
+
+const VOLUME_TYPES: &[&str] = &["volume", "bind", "tmpfs", "npipe", "cluster", "image"];
+
+/// Short-syntax port, capturing the host and container port or range.
+static PORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:(?:\[[0-9A-Fa-f:.]+\]|\d{1,3}(?:\.\d{1,3}){3}):)?(?:(\d+(?:-\d+)?)?:)?(\d+(?:-\d+)?)(?:/(?:tcp|udp|sctp))?$",
+    )
+    .unwrap()
+});
+
+const VOLUME_OPTIONS: &[&str] = &[
+    "ro",
+    "rw",
+    "z",
+    "Z",
+    "nocopy",
+    "consistent",
+    "cached",
+    "delegated",
+    "shared",
+    "rshared",
+    "slave",
+    "rslave",
+    "private",
+    "rprivate",
+];
+
+/// A problem found in a compose file, with the line it is on when it could
+/// be located.
+#[derive(Debug, PartialEq)]
+pub struct ComposeIssue {
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ComposeIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// A segment of the path to a value: a mapping key or a list index.
+#[derive(Clone, Copy)]
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Validate a stack's compose files, given as (path, content) pairs in
+/// merge order, against the parts of the compose specification that most
+/// often break a deploy: YAML syntax, a `services` key, port and volume
+/// syntax, and unknown top-level keys. Values built from `${VAR}` are only
+/// known on the host and are skipped.
+pub fn validate(files: &[(String, String)]) -> Vec<ComposeIssue> {
+    let mut issues = Vec::new();
+    let mut has_services = false;
+    let mut all_parsed = true;
+    let mut declared_volumes = BTreeSet::new();
+    let mut named_volumes = Vec::new();
+
+    for (file, content) in files {
+        let mut value: Value = match serde_yaml::from_str(content) {
+            Ok(value) => value,
+            Err(e) => {
+                issues.push(ComposeIssue {
+                    file: file.clone(),
+                    line: e.location().map(|l| l.line()),
+                    message: format!("invalid YAML: {}", yaml_message(&e)),
+                });
+                all_parsed = false;
+                continue;
+            }
+        };
+        if let Err(e) = value.apply_merge() {
+            issues.push(ComposeIssue {
+                file: file.clone(),
+                line: None,
+                message: format!("invalid YAML merge key: {}", e),
+            });
+            all_parsed = false;
+            continue;
+        }
+        let mut file_issues = FileIssues {
+            file,
+            content,
+            issues: &mut issues,
+        };
+        let Some(top) = value.as_mapping() else {
+            file_issues.push(&[], "expected a mapping at the top level".to_string());
+            continue;
+        };
+
+        for key in top.keys() {
+            let key = key.as_str().unwrap_or_default();
+            if !TOP_LEVEL_KEYS.contains(&key) && !key.starts_with("x-") {
+                file_issues.push(
+                    &[Segment::Key(key)],
+                    format!("unknown top-level key '{}'", key),
+                );
+            }
+        }
+        if let Some(volumes) = top.get("volumes").map(untag).and_then(|v| v.as_mapping()) {
+            declared_volumes.extend(volumes.keys().filter_map(|k| k.as_str()).map(String::from));
+        }
+
+        let Some(services) = top.get("services").map(untag) else {
+            continue;
+        };
+        has_services = true;
+        let Some(services) = services.as_mapping() else {
+            file_issues.push(
+                &[Segment::Key("services")],
+                "services must be a mapping of service names to services".to_string(),
+            );
+            continue;
+        };
+        for (name, service) in services {
+            let name = name.as_str().unwrap_or_default();
+            let service = untag(service);
+            let path = [Segment::Key("services"), Segment::Key(name)];
+            if service.is_null() {
+                continue;
+            }
+            let Some(service) = service.as_mapping() else {
+                file_issues.push(&path, "a service must be a mapping".to_string());
+                continue;
+            };
+            file_issues.check_ports(name, service.get("ports").map(untag));
+            for (i, volume) in file_issues.check_volumes(name, service.get("volumes").map(untag)) {
+                named_volumes.push((file.clone(), name.to_string(), i, volume));
+            }
+        }
+    }
+
+    if let Some((file, _)) = files.first()
+        && !has_services
+        && all_parsed
+    {
+        issues.push(ComposeIssue {
+            file: file.clone(),
+            line: None,
+            message: "no 'services' defined".to_string(),
+        });
+    }
+    for (file, service, i, volume) in named_volumes {
+        if declared_volumes.contains(&volume) {
+            continue;
+        }
+        let content = files
+            .iter()
+            .find(|(f, _)| *f == file)
+            .map(|(_, c)| c.as_str())
+            .unwrap_or_default();
+        let path = [
+            Segment::Key("services"),
+            Segment::Key(&service),
+            Segment::Key("volumes"),
+            Segment::Index(i),
+        ];
+        issues.push(ComposeIssue {
+            line: find_line(content, &path),
+            file,
+            message: format!(
+                "{}: volume '{}' is not declared under the top-level 'volumes' key",
+                display_path(&path),
+                volume
+            ),
+        });
+    }
+    issues
+}
+
+/// Issues found in one file, located by the path of the offending value.
+struct FileIssues<'a> {
+    file: &'a str,
+    content: &'a str,
+    issues: &'a mut Vec<ComposeIssue>,
+}
+
+impl FileIssues<'_> {
+    fn push(&mut self, path: &[Segment], message: String) {
+        let message = if path.len() < 2 {
+            message
+        } else {
+            format!("{}: {}", display_path(path), message)
+        };
+        self.issues.push(ComposeIssue {
+            file: self.file.to_string(),
+            line: find_line(self.content, path),
+            message,
+        });
+    }
+
+    fn check_ports(&mut self, service: &str, ports: Option<&Value>) {
+        let Some(ports) = ports.and_then(|p| p.as_sequence()) else {
+            return;
+        };
+        for (i, port) in ports.iter().enumerate() {
+            let path = [
+                Segment::Key("services"),
+                Segment::Key(service),
+                Segment::Key("ports"),
+                Segment::Index(i),
+            ];
+            let error = match port {
+                Value::Number(n) => match n.as_u64() {
+                    Some(1..=65535) => None,
+                    _ => Some(format!("invalid port {}", n)),
+                },
+                Value::String(s) if s.contains('$') => None,
+                Value::String(s) => port_error(s),
+                Value::Mapping(m) => match m.get("target") {
+                    Some(Value::Number(_)) => None,
+                    Some(Value::String(s)) if s.contains('$') || port_error(s).is_none() => None,
+                    Some(_) => Some("invalid port 'target'".to_string()),
+                    None => Some("port is missing 'target'".to_string()),
+                },
+                _ => Some("a port must be a string, a number or a mapping".to_string()),
+            };
+            if let Some(error) = error {
+                self.push(&path, error);
+            }
+        }
+    }
+
+    /// Check a service's volumes, returning the named volumes it uses by
+    /// their index.
+    fn check_volumes(&mut self, service: &str, volumes: Option<&Value>) -> Vec<(usize, String)> {
+        let mut named = Vec::new();
+        let Some(volumes) = volumes.and_then(|v| v.as_sequence()) else {
+            return named;
+        };
+        for (i, volume) in volumes.iter().enumerate() {
+            let path = [
+                Segment::Key("services"),
+                Segment::Key(service),
+                Segment::Key("volumes"),
+                Segment::Index(i),
+            ];
+            let error = match volume {
+                Value::String(s) if s.contains('$') => None,
+                Value::String(s) => {
+                    let error = volume_error(s);
+                    let source = s.split(':').next().unwrap_or_default();
+                    if error.is_none() && s.contains(':') && is_volume_name(source) {
+                        named.push((i, source.to_string()));
+                    }
+                    error
+                }
+                Value::Mapping(m) => {
+                    let kind = m.get("type").and_then(|t| t.as_str());
+                    match (kind, m.get("target")) {
+                        (Some(kind), _) if !VOLUME_TYPES.contains(&kind) => {
+                            Some(format!("unknown volume type '{}'", kind))
+                        }
+                        (_, None) => Some("volume is missing 'target'".to_string()),
+                        _ => {
+                            let source = m.get("source").and_then(|s| s.as_str());
+                            if let Some(source) = source
+                                && kind.unwrap_or("volume") == "volume"
+                                && is_volume_name(source)
+                            {
+                                named.push((i, source.to_string()));
+                            }
+                            None
+                        }
+                    }
+                }
+                _ => Some("a volume must be a string or a mapping".to_string()),
+            };
+            if let Some(error) = error {
+                self.push(&path, error);
+            }
+        }
+        named
+    }
+}
+
+/// Why a short-syntax port (`[ip:][host:]container[/protocol]`) is
+/// invalid, if it is.
+fn port_error(port: &str) -> Option<String> {
+    let invalid = || Some(format!("invalid port '{}'", port));
+    let Some(caps) = PORT.captures(port) else {
+        return invalid();
+    };
+    for range in caps.iter().skip(1).flatten() {
+        let bounds: Vec<u64> = range
+            .as_str()
+            .split('-')
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        let in_range = bounds.iter().all(|n| (1..=65535).contains(n));
+        if !in_range || bounds.windows(2).any(|w| w[0] > w[1]) {
+            return invalid();
+        }
+    }
+    None
+}
+
+/// Why a short-syntax volume (`[source:]target[:options]`) is invalid, if
+/// it is.
+fn volume_error(volume: &str) -> Option<String> {
+    let parts: Vec<&str> = volume.split(':').collect();
+    let target = match parts.len() {
+        1 => parts[0],
+        2 | 3 if parts[0].is_empty() => {
+            return Some(format!("volume '{}' has an empty source", volume));
+        }
+        2 | 3 => parts[1],
+        _ => return Some(format!("invalid volume '{}'", volume)),
+    };
+    if !target.starts_with('/') {
+        return Some(format!(
+            "volume '{}' must mount at an absolute path in the container",
+            volume
+        ));
+    }
+    if let Some(options) = parts.get(2) {
+        for option in options.split(',') {
+            if !VOLUME_OPTIONS.contains(&option) {
+                return Some(format!(
+                    "volume '{}' has an unknown option '{}'",
+                    volume, option
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// Whether a volume source names a volume rather than a host path.
+fn is_volume_name(source: &str) -> bool {
+    !source.is_empty() && !source.starts_with(['.', '/', '~'])
+}
+
+fn untag(value: &Value) -> &Value {
+    match value {
+        Value::Tagged(tagged) => &tagged.value,
+        other => other,
+    }
+}
+
+/// serde_yaml's message without the position, which is reported
+/// separately.
+fn yaml_message(error: &serde_yaml::Error) -> String {
+    let message = error.to_string();
+    match message.find(" at line ") {
+        Some(i) => message[..i].to_string(),
+        None => message,
+    }
+}
+
+fn display_path(path: &[Segment]) -> String {
+    let mut out = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if out.is_empty() => out.push_str(key),
+            Segment::Key(key) => {
+                out.push('.');
+                out.push_str(key);
+            }
+            Segment::Index(i) => out.push_str(&format!("[{}]", i)),
+        }
+    }
+    out
+}
+
+/// The 1-based line of the value at `path` in block-style YAML, found by
+/// indentation. Falls back to the deepest part of the path that could be
+/// found, e.g. the key of a flow-style list.
+fn find_line(content: &str, path: &[Segment]) -> Option<usize> {
+    let lines: Vec<(usize, &str)> = content
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            (line.len() - trimmed.len(), trimmed)
+        })
+        .collect();
+    let is_content = |trimmed: &str| !trimmed.is_empty() && !trimmed.starts_with('#');
+
+    let (mut start, mut end) = (0, lines.len());
+    let mut found = None;
+    for segment in path {
+        let Some(indent) = lines[start..end]
+            .iter()
+            .find(|(_, t)| is_content(t))
+            .map(|(i, _)| *i)
+        else {
+            break;
+        };
+        let matches = |t: &str| match segment {
+            Segment::Key(key) => [
+                key.to_string(),
+                format!("\"{}\"", key),
+                format!("'{}'", key),
+            ]
+            .iter()
+            .any(|k| {
+                t.strip_prefix(k.as_str())
+                    .is_some_and(|r| r.starts_with(':'))
+            }),
+            Segment::Index(_) => t == "-" || t.starts_with("- "),
+        };
+        let mut candidates = (start..end).filter(|&i| lines[i].0 == indent && matches(lines[i].1));
+        let position = match segment {
+            Segment::Key(_) => candidates.next(),
+            Segment::Index(n) => candidates.nth(*n),
+        };
+        let Some(position) = position else {
+            break;
+        };
+        found = Some(position + 1);
+        // The value runs until the next line at the same or a lower indent,
+        // except list items written at their key's indent
+        let is_key = matches!(segment, Segment::Key(_));
+        start = position + 1;
+        end = (start..end)
+            .find(|&i| {
+                let (line_indent, trimmed) = lines[i];
+                is_content(trimmed)
+                    && (line_indent < indent
+                        || (line_indent == indent && !(is_key && trimmed.starts_with('-'))))
+            })
+            .unwrap_or(end);
+    }
+    found
+}
+
+/// Run `docker compose config -q` on the merged compose file, from the
+/// stack's directory so relative paths resolve and with `env` set for
+/// interpolation. Returns `None` when docker or its compose plugin isn't
+/// installed, and docker's error lines otherwise.
+pub fn docker_compose_config(
+    content: &str,
+    project_dir: &Path,
+    env: &[EnvVar],
+) -> Option<Vec<String>> {
+    let available = Command::new("docker")
+        .args(["compose", "version"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !available {
+        return None;
+    }
+    let mut child = Command::new("docker")
+        .args(["compose", "--project-directory"])
+        .arg(project_dir)
+        .args(["-f", "-", "config", "-q"])
+        .envs(env.iter().map(|v| (&v.name, &v.value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(content.as_bytes());
+    }
+    let output = child.wait_with_output().ok()?;
+    if output.status.success() {
+        return Some(vec![]);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Some(
+        stderr
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issues(content: &str) -> Vec<String> {
+        validate(&[("compose.yaml".to_string(), content.to_string())])
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_file() {
+        let content = "\
+name: app
+x-common: &common
+  restart: always
+services:
+  web:
+    <<: *common
+    image: nginx
+    ports:
+      - 80
+      - \"8080:80\"
+      - 127.0.0.1:443:443/tcp
+      - \"[::1]:53:53/udp\"
+      - 9000-9001:9000-9001
+      - ${PORT}:80
+      - target: 80
+        published: 8081
+    volumes:
+      - ./html:/usr/share/nginx/html:ro,z
+      - data:/data
+      - /cache
+      - type: tmpfs
+        target: /tmp
+volumes:
+  data:
+";
+        assert!(issues(content).is_empty(), "{:?}", issues(content));
+    }
+
+    #[test]
+    fn test_validate_reports_yaml_syntax_with_line() {
+        let issues = issues("services:\n  web:\n    image: nginx\n   ports: [\n");
+        assert_eq!(issues.len(), 1);
+        assert!(
+            issues[0].starts_with("compose.yaml:4: invalid YAML"),
+            "{}",
+            issues[0]
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_unknown_keys_and_missing_services() {
+        assert_eq!(
+            issues("service:\n  web:\n    image: nginx\n"),
+            [
+                "compose.yaml:1: unknown top-level key 'service'",
+                "compose.yaml: no 'services' defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_ports_and_volumes_by_line() {
+        let content = "\
+services:
+  web:
+    image: nginx
+    ports:
+    - \"80:80\"
+    - \"80:80:80\"
+    - 70000:80
+    volumes:
+      - ./html:usr/share/html
+      - ./conf:/etc/nginx:rx
+      - missing:/data
+";
+        assert_eq!(
+            issues(content),
+            [
+                "compose.yaml:6: services.web.ports[1]: invalid port '80:80:80'",
+                "compose.yaml:7: services.web.ports[2]: invalid port '70000:80'",
+                "compose.yaml:9: services.web.volumes[0]: volume './html:usr/share/html' must mount \
+                 at an absolute path in the container",
+                "compose.yaml:10: services.web.volumes[1]: volume './conf:/etc/nginx:rx' has an \
+                 unknown option 'rx'",
+                "compose.yaml:11: services.web.volumes[2]: volume 'missing' is not declared under \
+                 the top-level 'volumes' key",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_across_override_files() {
+        let files = [
+            (
+                "compose.yaml".to_string(),
+                "services:\n  db:\n    image: postgres\n    volumes:\n      - pg:/var/lib/postgresql\n"
+                    .to_string(),
+            ),
+            ("compose.prod.yaml".to_string(), "volumes:\n  pg: {}\n".to_string()),
+        ];
+        assert!(validate(&files).is_empty());
+    }
+
+    #[test]
+    fn test_find_line() {
+        let content =
+            "services:\n  web:\n    ports: [\"80\"]\n  db:\n    ports:\n      - 1\n      - 2\n";
+        let ports = |service| {
+            [
+                Segment::Key("services"),
+                Segment::Key(service),
+                Segment::Key("ports"),
+            ]
+        };
+        let web = ports("web");
+        assert_eq!(
+            find_line(content, &[web[0], web[1], web[2], Segment::Index(0)]),
+            Some(3)
+        );
+        let db = ports("db");
+        assert_eq!(
+            find_line(content, &[db[0], db[1], db[2], Segment::Index(1)]),
+            Some(7)
+        );
+    }
+}